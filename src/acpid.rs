@@ -0,0 +1,73 @@
+use std::io::{BufRead, BufReader};
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::{info, warn};
+use bl_control::log;
+use crate::{ActivitySource, Event};
+
+// How long to wait before trying to reconnect to acpid
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+
+// Maps a line received from acpid on to an event for the main loop, if it's
+// one that we care about. Lines look like "button/lid LID close" or
+// "video/brightnessup BRTUP 00000086 00000000"
+fn parse_event(line: &str) -> Option<Event> {
+    let mut parts = line.split_whitespace();
+    match parts.next()? {
+        // The lid is a policy source: closing it dims straight away
+        "button/lid" => match parts.nth(1)? {
+            "close" => Some(Event::DimNow),
+            "open" => Some(Event::Activity(ActivitySource::Acpid)),
+            _ => None
+        },
+
+        // Anything the user had to physically press counts as activity
+        "button/power" | "video/brightnessup" | "video/brightnessdown" => Some(Event::Activity(ActivitySource::Acpid)),
+
+        _ => None
+    }
+}
+
+
+// Creates a thread that reads events from the acpid socket and posts those we
+// care about to the given channel, reconnecting if acpid goes away
+pub fn spawn_reader(socket_path: String, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("acpid-reader".to_string());
+    thread_builder.spawn(move || {
+        loop {
+            let stream = match UnixStream::connect(&socket_path) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!(target: log::INPUT, "Failed to connect to acpid at {}: {}", socket_path, e);
+                    thread::sleep(RECONNECT_DELAY);
+                    continue;
+                }
+            };
+            info!(target: log::INPUT, "Connected to acpid at {}", socket_path);
+
+            // Events are delivered one per line
+            for line in BufReader::new(stream).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        warn!(target: log::INPUT, "Error reading from acpid: {}", e);
+                        break;
+                    }
+                };
+
+                if let Some(event) = parse_event(&line) {
+                    if let Err(e) = s.send(event) {
+                        warn!(target: log::INPUT, "{}", e);
+                    }
+                }
+            }
+
+            warn!(target: log::INPUT, "Lost connection to acpid, reconnecting");
+            thread::sleep(RECONNECT_DELAY);
+        }
+    })
+}
@@ -0,0 +1,172 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::{debug, warn};
+use bl_control::error::BlError;
+
+use crate::{power, Event};
+
+// Where the kernel lists industrial I/O devices, which include light sensors
+const IIO_PATH: &str = "/sys/bus/iio/devices";
+
+// How much of each new reading goes into the smoothed light level for
+// --als-auto, with the rest coming from the readings before it
+const SMOOTHING: f64 = 0.3;
+
+
+// Finds the first IIO device that reports illuminance
+pub fn find_sensor() -> Result<PathBuf, BlError> {
+    let entries = fs::read_dir(IIO_PATH).map_err(|e| BlError::io(format!("could not list {}", IIO_PATH), e))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.join("in_illuminance_input").exists() || path.join("in_illuminance_raw").exists() {
+            return Ok(path);
+        }
+    }
+
+    Err(BlError::DeviceNotFound(String::from("no ambient light sensor found")))
+}
+
+
+fn read_value(path: &Path) -> Option<f64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+
+// Reads the ambient light level in lux. Some sensors give it directly, and
+// others give a raw value to be adjusted by an offset and scale
+pub fn read_lux(sensor: &Path) -> Result<f64, String> {
+    if let Some(lux) = read_value(&sensor.join("in_illuminance_input")) {
+        return Ok(lux);
+    }
+
+    match read_value(&sensor.join("in_illuminance_raw")) {
+        Some(raw) => {
+            let offset = read_value(&sensor.join("in_illuminance_offset")).unwrap_or(0.0);
+            let scale = read_value(&sensor.join("in_illuminance_scale")).unwrap_or(1.0);
+            Ok((raw + offset) * scale)
+        },
+        None => Err(format!("could not read illuminance from {}", sensor.display()))
+    }
+}
+
+
+// Creates a thread that checks the ambient light every so often and posts to
+// the main loop whenever the room becomes brighter or darker than the threshold
+pub fn spawn_gate(sensor: PathBuf, threshold: f64, interval: Duration, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("als-gate".to_string());
+    thread_builder.spawn(move || {
+        let mut was_bright = None;
+        loop {
+            match read_lux(&sensor) {
+                Ok(lux) => {
+                    let bright = lux >= threshold;
+                    if Some(bright) != was_bright {
+                        debug!("Room is {} ({:.0} lux)", if bright { "bright, not dimming" } else { "dark, dimming when idle" }, lux);
+                        if let Err(e) = s.send(Event::AmbientBright(bright)) {
+                            warn!("{}", e);
+                        }
+                        was_bright = Some(bright);
+                    }
+                },
+                Err(e) => warn!("Failed to read ambient light: {}", e)
+            }
+
+            power::poll_sleep(interval);
+        }
+    })
+}
+
+
+// Works out the backlight level for an ambient light level from the curve,
+// interpolating between its points and holding the ends beyond them. The
+// curve is sorted by lux
+fn curve_level(curve: &[(f64, u8)], lux: f64) -> f64 {
+    let (first, last) = match (curve.first(), curve.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return 0.0
+    };
+    if lux <= first.0 {
+        return first.1 as f64;
+    }
+
+    for pair in curve.windows(2) {
+        let ((lux_a, level_a), (lux_b, level_b)) = (pair[0], pair[1]);
+        if lux <= lux_b {
+            let t = (lux - lux_a) / (lux_b - lux_a);
+            return level_a as f64 + t * (level_b as f64 - level_a as f64);
+        }
+    }
+    last.1 as f64
+}
+
+
+// Creates a thread that follows the ambient light, posting the level that the
+// curve gives for it to the main loop whenever that changes. Readings are
+// smoothed so that a passing shadow doesn't change the level, and the level
+// only changes once the curve has moved a whole level past it, so that it
+// doesn't flicker between two levels when the light is in between
+pub fn spawn_auto(sensor: PathBuf, mut curve: Vec<(f64, u8)>, interval: Duration, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let thread_builder = thread::Builder::new().name("als-auto".to_string());
+    thread_builder.spawn(move || {
+        let mut smoothed: Option<f64> = None;
+        let mut level: Option<u8> = None;
+        loop {
+            match read_lux(&sensor) {
+                Ok(lux) => {
+                    let lux = match smoothed {
+                        Some(smoothed) => smoothed + SMOOTHING * (lux - smoothed),
+                        None => lux
+                    };
+                    smoothed = Some(lux);
+
+                    let target = curve_level(&curve, lux);
+                    if level.is_none_or(|level| (target - level as f64).abs() >= 1.0) {
+                        let new_level = target.round() as u8;
+                        debug!("Ambient light is {:.0} lux, so the level is {}", lux, new_level);
+                        if let Err(e) = s.send(Event::AmbientLevel(new_level)) {
+                            warn!("{}", e);
+                        }
+                        level = Some(new_level);
+                    }
+                },
+                Err(e) => warn!("Failed to read ambient light: {}", e)
+            }
+
+            power::poll_sleep(interval);
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVE: [(f64, u8); 3] = [(0.0, 40), (50.0, 20), (300.0, 0)];
+
+    #[test]
+    fn holds_the_ends_of_the_curve() {
+        assert_eq!(curve_level(&CURVE, -5.0), 40.0);
+        assert_eq!(curve_level(&CURVE, 0.0), 40.0);
+        assert_eq!(curve_level(&CURVE, 300.0), 0.0);
+        assert_eq!(curve_level(&CURVE, 1000.0), 0.0);
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        assert_eq!(curve_level(&CURVE, 25.0), 30.0);
+        assert_eq!(curve_level(&CURVE, 50.0), 20.0);
+        assert_eq!(curve_level(&CURVE, 175.0), 10.0);
+    }
+
+    #[test]
+    fn has_no_level_without_a_curve() {
+        assert_eq!(curve_level(&[], 100.0), 0.0);
+        assert_eq!(curve_level(&[(10.0, 7)], 100.0), 7.0);
+    }
+}
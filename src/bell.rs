@@ -0,0 +1,52 @@
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+use x11rb::connection::Connection;
+use x11rb::protocol::xkb::{self, ConnectionExt as _};
+use x11rb::protocol::Event as XEvent;
+
+use tracing::{info, warn};
+use crate::Event;
+
+
+// Subscribes to bell notifications on the X server's core keyboard
+fn watch_bell(s: &UnboundedSender<Event>) -> Result<(), String> {
+    let (conn, _) = x11rb::connect(None).map_err(|e| e.to_string())?;
+
+    // XKB has to be enabled on the connection before it can be used
+    let reply = conn.xkb_use_extension(1, 0).map_err(|e| e.to_string())?
+        .reply().map_err(|e| e.to_string())?;
+    if !reply.supported {
+        return Err(String::from("X server does not support XKB"));
+    }
+
+    conn.xkb_select_events(
+        xkb::ID::USE_CORE_KBD.into(),
+        xkb::EventType::from(0u16),
+        xkb::EventType::BELL_NOTIFY,
+        xkb::MapPart::from(0u16),
+        xkb::MapPart::from(0u16),
+        &xkb::SelectEventsAux::new()
+    ).map_err(|e| e.to_string())?;
+    conn.flush().map_err(|e| e.to_string())?;
+    info!("Watching for the X11 bell");
+
+    loop {
+        let event = conn.wait_for_event().map_err(|e| e.to_string())?;
+        if let XEvent::XkbBellNotify(_) = event {
+            if let Err(e) = s.send(Event::Bell) {
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+
+// Creates a thread that posts to the main loop whenever the X11 bell rings
+pub fn spawn_x11(s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("x11-bell".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = watch_bell(&s) {
+            warn!("Stopped watching for the X11 bell: {}", e);
+        }
+    })
+}
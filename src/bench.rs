@@ -0,0 +1,76 @@
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+use bl_control::BacklightBackend;
+use bl_control::error::BlError;
+
+
+// The timings of one kind of transfer
+struct Timings {
+    durations: Vec<Duration>,
+    errors: u32
+}
+
+impl Timings {
+    fn new() -> Timings {
+        Timings { durations: Vec::new(), errors: 0 }
+    }
+
+    // Times a single transfer, counting it as an error if it failed
+    fn time<T>(&mut self, transfer: impl FnOnce() -> Result<T, BlError>) -> Option<T> {
+        let start = Instant::now();
+        match transfer() {
+            Ok(value) => {
+                self.durations.push(start.elapsed());
+                Some(value)
+            },
+            Err(e) => {
+                warn!("Transfer failed: {}", e);
+                self.errors += 1;
+                None
+            }
+        }
+    }
+
+    // Reports the min/median/max latency and the number of errors
+    fn report(&mut self, name: &str) {
+        self.durations.sort();
+        match (self.durations.first(), self.durations.last()) {
+            (Some(min), Some(max)) => {
+                let median = self.durations[self.durations.len() / 2];
+                info!("{}: min {:.2}ms, median {:.2}ms, max {:.2}ms, {} errors", name,
+                    ms(*min), ms(median), ms(*max), self.errors);
+            },
+            _ => info!("{}: no successful transfers, {} errors", name, self.errors)
+        }
+    }
+}
+
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+
+// Times a series of gets and sets against the backlight. The level is set to
+// whatever it already is, so the backlight doesn't visibly change
+pub fn run(backlight: &mut dyn BacklightBackend, count: u32) {
+    info!("Benchmarking the backlight with {} transfers of each kind", count);
+
+    let mut gets = Timings::new();
+    let mut sets = Timings::new();
+    let mut level = None;
+    for _ in 0..count {
+        if let Some(l) = gets.time(|| backlight.get()) {
+            level = Some(l);
+        }
+
+        // We can't write back a level we've never managed to read
+        if let Some(l) = level {
+            sets.time(|| backlight.set(l));
+        }
+    }
+
+    gets.report("get");
+    sets.report("set");
+}
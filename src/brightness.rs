@@ -0,0 +1,569 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot::{self, error::TryRecvError};
+
+use tracing::{info, warn, error};
+use bl_control::log;
+use bl_control::BacklightBackend;
+use bl_control::error::BlError;
+
+use crate::power;
+
+// How often the brightness is stepped during a fade, in milliseconds
+const FADE_STEP_MS: u64 = 100;
+
+// How long each half of a flash lasts, in milliseconds
+const FLASH_MS: u64 = 150;
+
+// How often the screensaver animation moves on, in milliseconds
+const SCREENSAVER_STEP_MS: u64 = 1000;
+
+// The levels the screensaver pulses through on keyboards without color
+const SCREENSAVER_PULSE: [u8; 4] = [0, 1, 2, 1];
+
+// How often a software breath moves on a level, in milliseconds
+const BREATHE_STEP_MS: u64 = 200;
+
+// How long a panic elsewhere waits for the backlight to be put back before
+// the process exits anyway, in milliseconds
+const PANIC_RESTORE_MS: u64 = 2000;
+
+
+// Slow animations that run until the next level change
+#[derive(Clone, Copy)]
+enum Animation {
+    // Very dim colors or pulses while the backlight is off
+    Screensaver { color: bool },
+    // Breathing up to a level and back down again
+    Breathe { level: u8 }
+}
+
+// Requests that can be made of the brightness thread
+pub enum Command {
+    // Move to a new level, either by fading or immediately. Any fade that is
+    // in progress is cancelled, so the latest request always wins. The reason
+    // is logged with the change
+    Set { level: u8, fade: bool, reason: &'static str },
+    // Change the backlight color
+    Color { r: u8, g: u8, b: u8 },
+    // Flash the backlight a number of times as a visual alert
+    Flash { count: u8 },
+    // Limit the level to at most this, or remove the limit
+    Cap(Option<u8>),
+    // Start a very dim animation while the backlight is off, drifting through
+    // colors if the keyboard has them and pulsing otherwise. It's stopped by
+    // the next level change
+    Screensaver { color: bool },
+    // Breathe slowly up to the given level and back instead of being steady,
+    // using the controller's own effect if it has one. Like the screensaver,
+    // it's stopped by the next level change
+    Breathe { level: u8, hardware: bool },
+    // Read the level the user has set. If a fade is in progress the device is
+    // somewhere between levels, so the level being faded towards is given
+    Read(oneshot::Sender<Result<u8, BlError>>),
+    // Switch to another backlight, e.g. after the configuration changed,
+    // putting the level and color on it
+    Replace(Box<dyn BacklightBackend>),
+    // Give the device back before the system sleeps, answering once it has.
+    // Any fade is finished straight away, so that the level it was heading
+    // for is the one put back on waking
+    Suspend(oneshot::Sender<()>),
+    // Open the device again after the system wakes, putting the level and
+    // color back on it
+    Resume,
+    // Put the backlight straight back to a level and let go of the device,
+    // answering once it's been given back. Nothing is handled after this
+    Shutdown { level: u8, done: oneshot::Sender<()> }
+}
+
+
+// Converts a hue in degrees to a fully saturated color
+fn hue_to_rgb(hue: u32) -> (u8, u8, u8) {
+    let hue = hue % 360;
+    let x = ((60 - (hue % 120).abs_diff(60)) * 255 / 60) as u8;
+    match hue / 60 {
+        0 => (255, x, 0),
+        1 => (x, 255, 0),
+        2 => (0, 255, x),
+        3 => (0, x, 255),
+        4 => (x, 0, 255),
+        _ => (255, 0, x)
+    }
+}
+
+
+// How long to wait between fade steps, which is longer while saving power
+fn fade_step_interval() -> Duration {
+    match power::low_power() {
+        true => Duration::from_millis(FADE_STEP_MS) * power::LOW_POWER_FACTOR,
+        false => Duration::from_millis(FADE_STEP_MS)
+    }
+}
+
+
+// Works out the next level when fading from one level towards another. The
+// brightness is non-linear so the step size depends on the current level
+fn fade_step(level: u8, target: u8) -> u8 {
+    let step = if level >= 10 { 2 } else { 1 };
+    if level > target {
+        level.saturating_sub(step).max(target)
+    } else {
+        level.saturating_add(step).min(target)
+    }
+}
+
+
+// The single owner of the USB device. All changes to the brightness go through
+// here so that requests from different sources can't interleave their writes
+struct Brightness {
+    backlight: Box<dyn BacklightBackend>,
+    max_level: u8,
+    // A temporary limit on the level, e.g. while running hot
+    cap: Option<u8>,
+    // The level last asked for, which may be above the cap
+    requested: u8,
+    // The level last written to the device
+    level: u8,
+    // The level we're currently fading towards, if any
+    fade_target: Option<u8>,
+    // The color last asked for, to put back after the screensaver
+    color: Option<(u8, u8, u8)>,
+    // The running animation and its frame, if any
+    animation: Option<(Animation, u32)>
+}
+
+impl Brightness {
+    // Writes a level to the backlight, logging any failure
+    fn write_level(&mut self, level: u8) {
+        if let Err(e) = self.backlight.set(level) {
+            warn!("Failed to set backlight level: {}", e);
+        }
+    }
+
+    // Writes a color to the backlight, logging any failure
+    fn write_color(&mut self, r: u8, g: u8, b: u8) {
+        if let Err(e) = self.backlight.set_color(r, g, b) {
+            warn!("Failed to set backlight color: {}", e);
+        }
+    }
+
+    // Handles a single request
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Set { level, fade, reason } => {
+                // Any level change means the animation is over
+                if let Some((Animation::Screensaver { .. }, _)) = self.animation.take() {
+                    if let Some((r, g, b)) = self.color {
+                        self.write_color(r, g, b);
+                    }
+                }
+
+                let old = self.fade_target.unwrap_or(self.level);
+                self.requested = level.min(self.max_level);
+                let level = self.requested.min(self.cap.unwrap_or(u8::MAX));
+                if level != old {
+                    info!(target: log::STATEMACHINE, event = "brightness_changed", old_brightness = old, brightness = level, reason,
+                        "Backlight level changed");
+                }
+                if fade && level != self.level {
+                    self.fade_target = Some(level);
+                } else {
+                    self.fade_target = None;
+                    self.level = level;
+                    self.write_level(level);
+                }
+            },
+            Command::Color { r, g, b } => {
+                self.color = Some((r, g, b));
+                if self.animation.is_none() {
+                    self.write_color(r, g, b);
+                }
+            },
+            Command::Screensaver { color } => {
+                self.fade_target = None;
+                self.animation = Some((Animation::Screensaver { color }, 0));
+                self.animate();
+            },
+            Command::Breathe { level, hardware } => {
+                let level = level.min(self.max_level).min(self.cap.unwrap_or(u8::MAX));
+                self.fade_target = None;
+                if hardware {
+                    self.animation = None;
+                    self.level = level;
+                    if let Err(e) = self.backlight.breathe(level) {
+                        warn!("Failed to start breathing: {}", e);
+                    }
+                } else {
+                    self.animation = Some((Animation::Breathe { level }, 0));
+                    self.animate();
+                }
+            },
+            Command::Flash { count } => self.flash(count),
+            Command::Cap(cap) => {
+                // Bring the level down straight away if it's over the cap.
+                // Lifting the cap is left to whoever sets the level next
+                self.cap = cap;
+                if let Some(cap) = cap {
+                    if self.level > cap {
+                        self.handle_command(Command::Set { level: self.requested, fade: true, reason: "cap" });
+                    }
+                    self.fade_target = self.fade_target.map(|t| t.min(cap));
+                }
+            },
+            Command::Read(reply) => {
+                let result = match (self.fade_target, self.cap) {
+                    // While capped, the level asked for is the one the user
+                    // set, unless they've changed it on the keyboard since
+                    (Some(_), Some(_)) => Ok(self.requested),
+                    (Some(target), None) => Ok(target),
+                    (None, Some(_)) => match self.backlight.get() {
+                        Ok(l) if l == self.level => Ok(self.requested),
+                        other => other
+                    },
+                    (None, None) => self.backlight.get()
+                };
+
+                // The requester may have given up waiting, which is fine
+                let _ = reply.send(result);
+            },
+            Command::Replace(backlight) => {
+                // The old device is given back as it's dropped
+                let caps = backlight.capabilities();
+                self.backlight = backlight;
+                self.max_level = caps.max_level;
+                self.animation = None;
+                self.fade_target = None;
+                self.requested = self.requested.min(self.max_level);
+                self.level = self.level.min(self.max_level);
+                self.reapply();
+            },
+            Command::Suspend(done) => {
+                self.animation = None;
+                if let Some(target) = self.fade_target.take() {
+                    self.level = target;
+                }
+                self.backlight.suspend();
+                let _ = done.send(());
+            },
+            Command::Resume => {
+                match self.backlight.resume() {
+                    Ok(()) => info!(target: log::USB, "Reopened the backlight after waking"),
+                    Err(e) => warn!(target: log::USB, "Failed to reopen the backlight after waking: {}", e)
+                }
+                self.reapply();
+            },
+            // Handled by run, as it stops the thread
+            Command::Shutdown { .. } => ()
+        }
+    }
+
+    // Writes the color and level we think the backlight has, for a device
+    // that may have lost them
+    fn reapply(&mut self) {
+        if let (Some((r, g, b)), true) = (self.color, self.backlight.capabilities().color) {
+            self.write_color(r, g, b);
+        }
+        self.write_level(self.level);
+    }
+
+    // Abandons any fade or animation and writes the level straight away,
+    // putting back the color if the screensaver changed it
+    fn restore(&mut self, level: u8) {
+        if let (Some((Animation::Screensaver { .. }, _)), Some((r, g, b))) = (self.animation.take(), self.color) {
+            self.write_color(r, g, b);
+        }
+        self.fade_target = None;
+        self.level = level.min(self.max_level).min(self.cap.unwrap_or(u8::MAX));
+        self.write_level(self.level);
+    }
+
+    // Flashes the backlight, going to whichever of off or full brightness
+    // stands out most from the current level, and then returns to it. Any
+    // fade carries on from where it was afterwards
+    fn flash(&mut self, count: u8) {
+        let flash_level = match self.level > self.max_level / 2 {
+            true => 0,
+            false => self.max_level
+        };
+
+        for _ in 0..count {
+            self.write_level(flash_level);
+            thread::sleep(Duration::from_millis(FLASH_MS));
+            self.write_level(self.level);
+            thread::sleep(Duration::from_millis(FLASH_MS));
+        }
+    }
+
+    // Takes the next step of the current fade
+    fn step(&mut self) {
+        if let Some(target) = self.fade_target {
+            self.level = fade_step(self.level, target);
+            self.write_level(self.level);
+
+            // If we've reached the target, we can stop fading
+            if self.level == target {
+                self.fade_target = None;
+            }
+        }
+    }
+
+    // Shows the next frame of the running animation
+    fn animate(&mut self) {
+        let (animation, frame) = match self.animation {
+            Some(a) => a,
+            None => return
+        };
+
+        match animation {
+            // Drift slowly through the colors at the lowest level
+            Animation::Screensaver { color: true } => {
+                let (r, g, b) = hue_to_rgb(frame * 2);
+                self.write_color(r, g, b);
+                if frame == 0 {
+                    self.level = 1;
+                    self.write_level(self.level);
+                }
+            },
+            Animation::Screensaver { color: false } => {
+                self.level = SCREENSAVER_PULSE[frame as usize % SCREENSAVER_PULSE.len()];
+                self.write_level(self.level);
+            },
+
+            // Count up to the level and back down to zero
+            Animation::Breathe { level } => {
+                let period = level as u32 * 2;
+                let position = frame % period.max(1);
+                self.level = match position > level as u32 {
+                    true => (period - position) as u8,
+                    false => position as u8
+                };
+                self.write_level(self.level);
+            }
+        }
+        self.animation = Some((animation, frame.wrapping_add(1)));
+    }
+
+    // Processes requests until every sender has gone away or we're asked to
+    // shut down, in which case the channel to answer on is returned
+    fn run(&mut self, r: Receiver<Command>) -> Option<oneshot::Sender<()>> {
+        loop {
+            // Only wake up for fade steps if we're fading, or for the next
+            // frame if an animation is running
+            let wait = match (self.fade_target, self.animation) {
+                (Some(_), _) => Some(fade_step_interval()),
+                (None, Some((Animation::Screensaver { .. }, _))) => Some(Duration::from_millis(SCREENSAVER_STEP_MS)),
+                (None, Some((Animation::Breathe { .. }, _))) => Some(Duration::from_millis(BREATHE_STEP_MS)),
+                (None, None) => None
+            };
+            let command = match wait {
+                Some(wait) => match r.recv_timeout(wait) {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return None
+                },
+                None => match r.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => return None
+                }
+            };
+
+            match (command, self.fade_target) {
+                (Some(Command::Shutdown { level, done }), _) => {
+                    self.restore(level);
+                    return Some(done);
+                },
+                (Some(command), _) => self.handle_command(command),
+                (None, Some(_)) => self.step(),
+                (None, None) => self.animate()
+            }
+        }
+    }
+}
+
+
+// Creates the thread that owns the device, starting from the given level and
+// color, and returns the channel used to send it requests. If the thread
+// panics, it puts back the level in restore_level and gives the device back
+// before exiting the process
+pub fn spawn(backlight: Box<dyn BacklightBackend>, max_level: u8, level: u8, color: Option<(u8, u8, u8)>, restore_level: Arc<AtomicU8>) -> std::io::Result<Sender<Command>> {
+    let (s, r) = mpsc::channel();
+    let thread_builder = thread::Builder::new().name("brightness".to_string());
+    thread_builder.spawn(move || {
+        let mut brightness = Brightness {
+            backlight,
+            max_level,
+            cap: None,
+            requested: level,
+            level,
+            fade_target: None,
+            color,
+            animation: None
+        };
+        let done = match panic::catch_unwind(AssertUnwindSafe(|| brightness.run(r))) {
+            Ok(done) => done,
+            Err(_) => {
+                let level = restore_level.load(Ordering::Relaxed);
+                error!(brightness = level, "Brightness thread panicked, restoring backlight level {} before exiting", level);
+                brightness.restore(level);
+                drop(brightness);
+                process::exit(101);
+            }
+        };
+
+        // Dropping the backlight gives the device back (e.g. reattaching the
+        // kernel driver), which has to have happened before we answer
+        drop(brightness);
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+    })?;
+
+    Ok(s)
+}
+
+
+// Makes a panic in the main loop put the backlight back to the level in
+// restore_level and give the device back before the process exits, rather
+// than leaving the keyboard dark or detached from its driver. The brightness
+// thread puts things back itself once it has unwound, and the helper threads
+// are left to unwind on their own as the daemon carries on without them
+pub fn install_panic_guard(s: Sender<Command>, restore_level: Arc<AtomicU8>) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if thread::current().name() != Some("main") {
+            return;
+        }
+
+        let level = restore_level.load(Ordering::Relaxed);
+        error!(brightness = level, "Panicked, restoring backlight level {} before exiting", level);
+        let (done_s, mut done_r) = oneshot::channel();
+        if s.send(Command::Shutdown { level, done: done_s }).is_ok() {
+            // This may be a thread inside the runtime, so it can't block on
+            // the answer
+            let deadline = Instant::now() + Duration::from_millis(PANIC_RESTORE_MS);
+            while matches!(done_r.try_recv(), Err(TryRecvError::Empty)) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        process::exit(101);
+    }));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bl_control::{MockBacklight, MAX_LEVEL};
+
+    fn brightness(level: u8) -> Brightness {
+        Brightness {
+            backlight: Box::new(MockBacklight::new(level)),
+            max_level: MAX_LEVEL,
+            cap: None,
+            requested: level,
+            level,
+            fade_target: None,
+            color: None,
+            animation: None
+        }
+    }
+
+    // Steps the fade to the end, giving the level written at each step
+    fn fade(brightness: &mut Brightness) -> Vec<u8> {
+        let mut levels = Vec::new();
+        while brightness.fade_target.is_some() {
+            brightness.step();
+            levels.push(brightness.backlight.get().unwrap());
+        }
+        levels
+    }
+
+    #[test]
+    fn steps_faster_at_high_levels() {
+        assert_eq!(fade_step(20, 0), 18);
+        assert_eq!(fade_step(10, 0), 8);
+        assert_eq!(fade_step(9, 0), 8);
+        assert_eq!(fade_step(9, 20), 10);
+        assert_eq!(fade_step(10, 20), 12);
+    }
+
+    #[test]
+    fn steps_stop_at_the_target() {
+        assert_eq!(fade_step(11, 10), 10);
+        assert_eq!(fade_step(19, 20), 20);
+        assert_eq!(fade_step(5, 5), 5);
+    }
+
+    #[test]
+    fn sets_without_fading() {
+        let mut brightness = brightness(MAX_LEVEL);
+        brightness.handle_command(Command::Set { level: 20, fade: false, reason: "test" });
+        assert_eq!(brightness.backlight.get().unwrap(), 20);
+        assert_eq!(brightness.fade_target, None);
+    }
+
+    #[test]
+    fn fades_down_to_a_level() {
+        let mut brightness = brightness(14);
+        brightness.handle_command(Command::Set { level: 0, fade: true, reason: "test" });
+        assert_eq!(brightness.backlight.get().unwrap(), 14);
+        assert_eq!(fade(&mut brightness), vec![12, 10, 8, 7, 6, 5, 4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn fades_up_to_a_level() {
+        let mut brightness = brightness(7);
+        brightness.handle_command(Command::Set { level: 15, fade: true, reason: "test" });
+        assert_eq!(fade(&mut brightness), vec![8, 9, 10, 12, 14, 15]);
+    }
+
+    #[test]
+    fn reads_the_fade_target() {
+        let mut brightness = brightness(MAX_LEVEL);
+        brightness.handle_command(Command::Set { level: 10, fade: true, reason: "test" });
+        let (reply, answer) = oneshot::channel();
+        brightness.handle_command(Command::Read(reply));
+        assert_eq!(answer.blocking_recv().unwrap().unwrap(), 10);
+    }
+
+    #[test]
+    fn caps_the_level() {
+        let mut brightness = brightness(30);
+        brightness.handle_command(Command::Cap(Some(20)));
+        assert_eq!(brightness.fade_target, Some(20));
+        assert_eq!(*fade(&mut brightness).last().unwrap(), 20);
+
+        // The level asked for is kept, so that it's put back once the cap is
+        // lifted
+        brightness.handle_command(Command::Set { level: 40, fade: false, reason: "test" });
+        assert_eq!(brightness.backlight.get().unwrap(), 20);
+        let (reply, answer) = oneshot::channel();
+        brightness.handle_command(Command::Read(reply));
+        assert_eq!(answer.blocking_recv().unwrap().unwrap(), 40);
+
+        brightness.handle_command(Command::Cap(None));
+        brightness.handle_command(Command::Set { level: 40, fade: false, reason: "test" });
+        assert_eq!(brightness.backlight.get().unwrap(), 40);
+    }
+
+    #[test]
+    fn caps_a_fade_in_progress() {
+        let mut brightness = brightness(0);
+        brightness.handle_command(Command::Set { level: 30, fade: true, reason: "test" });
+        brightness.handle_command(Command::Cap(Some(5)));
+        assert_eq!(fade(&mut brightness), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn keeps_to_the_max_level() {
+        let mut brightness = brightness(0);
+        brightness.handle_command(Command::Set { level: MAX_LEVEL + 20, fade: false, reason: "test" });
+        assert_eq!(brightness.backlight.get().unwrap(), MAX_LEVEL);
+    }
+}
@@ -0,0 +1,75 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use bl_control::BacklightBackend;
+use bl_control::error::BlError;
+use crate::als;
+
+// How many readings are averaged for each point on the curve, and how far
+// apart they're taken
+const READINGS: u32 = 5;
+const READING_INTERVAL: Duration = Duration::from_millis(200);
+
+
+// Reads the ambient light a few times and averages the readings, so that a
+// flicker or a passing shadow doesn't end up in the curve
+fn average_lux(sensor: &Path) -> Result<f64, BlError> {
+    let mut total = 0.0;
+    for i in 0..READINGS {
+        if i > 0 {
+            thread::sleep(READING_INTERVAL);
+        }
+        total += als::read_lux(sensor).map_err(BlError::Protocol)?;
+    }
+    Ok(total / READINGS as f64)
+}
+
+
+// Has the user try levels under each lighting they want to cover, recording
+// the ambient light alongside the level they find comfortable under it. The
+// points come back sorted by lux, with a later point replacing an earlier one
+// for the same light. The backlight is put back as it was afterwards
+pub fn run(backlight: &mut dyn BacklightBackend, sensor: &Path) -> Result<Vec<(f64, u8)>, BlError> {
+    let max_level = backlight.capabilities().max_level;
+    let original = backlight.get()?;
+    let mut level = original;
+    let mut curve: Vec<(f64, u8)> = Vec::new();
+
+    println!("For each lighting you want to cover (e.g. dark, a lamp on, daylight), set up");
+    println!("the room, type levels (0 to {}) to try them and then `r` to record the one", max_level);
+    println!("you like. Press Enter on its own when done");
+
+    let stdin = io::stdin();
+    loop {
+        print!("level {}> ", level);
+        io::stdout().flush().map_err(|e| BlError::io("could not write the prompt", e))?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).map_err(|e| BlError::io("could not read from the terminal", e))? == 0 {
+            break;
+        }
+        match line.trim() {
+            "" => break,
+            "r" => {
+                let lux = average_lux(sensor)?;
+                let lux = (lux * 10.0).round() / 10.0;
+                curve.retain(|&(l, _)| l != lux);
+                curve.push((lux, level));
+                println!("Recorded level {} at {} lux", level, lux);
+            },
+            answer => match answer.parse::<u8>() {
+                Ok(new_level) if new_level <= max_level => {
+                    backlight.set(new_level)?;
+                    level = new_level;
+                },
+                _ => println!("Type a level from 0 to {}, `r` to record it, or nothing to finish", max_level)
+            }
+        }
+    }
+
+    backlight.set(original)?;
+    curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(curve)
+}
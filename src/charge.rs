@@ -0,0 +1,113 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::{debug, warn};
+use crate::Event;
+
+// Where the kernel lists batteries and chargers
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+// How often to check the charging state when not breathing
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// How long one breath takes, and how often the color is updated during it
+const BREATH_PERIOD: Duration = Duration::from_secs(4);
+const BREATH_STEP: Duration = Duration::from_millis(250);
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ChargeState {
+    Charging,
+    Full,
+    Discharging
+}
+
+
+// Reads the state of the first battery the kernel knows about
+fn read_state() -> Result<ChargeState, String> {
+    let entries = fs::read_dir(POWER_SUPPLY_PATH).map_err(|e| e.to_string())?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_battery = fs::read_to_string(path.join("type")).map(|t| t.trim() == "Battery").unwrap_or(false);
+        if !is_battery {
+            continue;
+        }
+
+        let status = fs::read_to_string(path.join("status")).map_err(|e| e.to_string())?;
+        return Ok(match status.trim() {
+            "Charging" => ChargeState::Charging,
+            "Full" => ChargeState::Full,
+            // "Not charging" is what some laptops report when plugged in at
+            // a charge threshold, which is full as far as the user cares
+            "Not charging" => ChargeState::Full,
+            _ => ChargeState::Discharging
+        });
+    }
+
+    Err(String::from("no battery found"))
+}
+
+
+// Scales a color by the brightness of a breath at the given point in time,
+// never going fully dark so it stays subtle rather than blinking
+fn breathe(color: (u8, u8, u8), elapsed: Duration) -> (u8, u8, u8) {
+    let phase = elapsed.as_secs_f64() / BREATH_PERIOD.as_secs_f64() * 2.0 * PI;
+    let scale = 0.6 + 0.4 * phase.cos();
+    let (r, g, b) = color;
+    ((r as f64 * scale) as u8, (g as f64 * scale) as u8, (b as f64 * scale) as u8)
+}
+
+
+// Creates a thread that shows the charging state using the backlight color:
+// breathing while charging, solid when full and the normal color otherwise
+pub fn spawn(color: (u8, u8, u8), s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("charge-indicator".to_string());
+    thread_builder.spawn(move || {
+        let mut last_state = None;
+        let mut last_poll = Instant::now();
+        let start = Instant::now();
+        let mut state = match read_state() {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Not showing the charging state: {}", e);
+                return;
+            }
+        };
+
+        loop {
+            // Check the state every so often, even while breathing
+            if last_poll.elapsed() >= POLL_INTERVAL {
+                last_poll = Instant::now();
+                match read_state() {
+                    Ok(s) => state = s,
+                    Err(e) => warn!("Failed to read the charging state: {}", e)
+                }
+            }
+
+            if last_state != Some(state) {
+                debug!("Battery is {:?}", state);
+            }
+
+            let event = match state {
+                ChargeState::Charging => Some(Event::ChargeColor(Some(breathe(color, start.elapsed())))),
+                ChargeState::Full if last_state != Some(state) => Some(Event::ChargeColor(Some(color))),
+                ChargeState::Discharging if last_state != Some(state) => Some(Event::ChargeColor(None)),
+                _ => None
+            };
+            if let Some(event) = event {
+                if let Err(e) = s.send(event) {
+                    warn!("{}", e);
+                }
+            }
+            last_state = Some(state);
+
+            thread::sleep(match state {
+                ChargeState::Charging => BREATH_STEP,
+                _ => POLL_INTERVAL
+            });
+        }
+    })
+}
@@ -0,0 +1,299 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use clap::{ArgAction, ArgMatches, Command};
+use clap::parser::ValueSource;
+use toml_edit::{Document, DocumentMut, Item, Value};
+
+// The config file used by everyone if there's no per-user one
+const SYSTEM_PATH: &str = "/etc/bl-control.toml";
+
+// Where packages and provisioning tools drop extra config files, read over
+// the main one
+pub const FRAGMENT_DIR: &str = "/etc/bl-control/conf.d";
+
+
+// Where the user's own config file goes, if we can tell where their home is
+fn user_path() -> Option<PathBuf> {
+    let user_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+    };
+    user_dir.map(|dir| dir.join("bl-control").join("config.toml"))
+}
+
+
+// Works out which config file to read when none was given: the user's own if
+// it exists, otherwise the system-wide one if that does
+pub fn default_path() -> Option<PathBuf> {
+    [user_path(), Some(PathBuf::from(SYSTEM_PATH))].into_iter()
+        .flatten()
+        .find(|path| path.exists())
+}
+
+
+// Works out which config file to write to when none was given: whichever
+// would be read, or a new one of the user's own if there isn't one
+pub fn default_write_path() -> PathBuf {
+    default_path().or_else(user_path).unwrap_or_else(|| PathBuf::from(SYSTEM_PATH))
+}
+
+
+// Sets an option in the config file, creating the file if there isn't one yet
+// and leaving the rest of it (comments included) as it was. Any setting the
+// option already had is replaced, whether it was written with dashes or
+// underscores
+pub fn set_option(path: &Path, name: &str, value: impl Into<Value>) -> Result<(), String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.to_string())
+    };
+    let mut doc: DocumentMut = contents.parse().map_err(|e: toml_edit::TomlError| e.to_string().trim_end().to_string())?;
+
+    doc.remove(&name.replace('_', "-"));
+    doc.insert(&name.replace('-', "_"), Item::Value(value.into()));
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    fs::write(path, doc.to_string()).map_err(|e| e.to_string())
+}
+
+
+// Gets the line number of a byte offset into the file
+fn line_number(contents: &str, offset: usize) -> usize {
+    contents[..offset].matches('\n').count() + 1
+}
+
+
+// Turns a single TOML value into the text that would be given on the command
+// line, if it's a type that has one
+fn value_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.value().clone()),
+        Value::Integer(i) => Some(i.value().to_string()),
+        Value::Float(f) => Some(f.value().to_string()),
+        Value::Boolean(b) => Some(b.value().to_string()),
+        Value::InlineTable(t) => table_to_string(t.iter().map(|(k, v)| (k, Some(v)))),
+        _ => None
+    }
+}
+
+
+// Turns a table into the KEY=VALUE,... text that would be given on the command
+// line, for options such as --schedule that have several parts
+fn table_to_string<'a>(pairs: impl Iterator<Item = (&'a str, Option<&'a Value>)>) -> Option<String> {
+    let pairs: Option<Vec<String>> = pairs.map(|(k, v)| Some(format!("{}={}", k, value_to_string(v?)?))).collect();
+    pairs.map(|pairs| pairs.join(","))
+}
+
+
+// Lists the config files in a directory of fragments, in the order they're
+// read. There being no directory is the same as it being empty
+pub fn fragments(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+            .collect(),
+        Err(_) => Vec::new()
+    };
+    paths.sort();
+    paths
+}
+
+
+// Reads config files and turns them into command line arguments. Each file
+// sets the options it gives over those from the files before it, so that an
+// option given more than once in an array is replaced rather than added to.
+// Mistakes are given with the file they're in
+pub fn to_args(paths: &[PathBuf], cmd: &Command, given: &ArgMatches) -> Result<Vec<OsString>, String> {
+    let mut options: Vec<(String, Vec<String>)> = Vec::new();
+    for path in paths {
+        for (name, option_args) in file_options(path, cmd, given).map_err(|e| format!("{}: {}", path.display(), e))? {
+            options.retain(|(other, _)| *other != name);
+            options.push((name, option_args));
+        }
+    }
+
+    Ok(options.into_iter().flat_map(|(_, option_args)| option_args).map(OsString::from).collect())
+}
+
+
+// Reads a config file and turns it into command line arguments, giving them
+// for each option in turn. Keys are the long option names, with either dashes
+// or underscores, and arrays give an option multiple times. Tables (including
+// arrays of them, e.g. [[schedule]]) are given as KEY=VALUE,... Options in
+// `given` that came from the command line are skipped, so that the command
+// line always wins
+fn file_options(path: &Path, cmd: &Command, given: &ArgMatches) -> Result<Vec<(String, Vec<String>)>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let doc = Document::parse(contents.as_str()).map_err(|e| e.to_string().trim_end().to_string())?;
+
+    let mut args = Vec::new();
+    for (key, item) in doc.as_table().iter() {
+        let line = match doc.as_table().get_key_value(key).and_then(|(k, _)| k.span()) {
+            Some(span) => line_number(&contents, span.start),
+            None => 0
+        };
+
+        let name = key.replace('_', "-");
+        let arg = match cmd.get_arguments().find(|a| a.get_long() == Some(name.as_str())) {
+            Some(arg) if !arg.is_positional() && !matches!(name.as_str(), "config" | "help" | "version") => arg,
+            _ => return Err(format!("line {}: unknown option `{}`", line, key))
+        };
+
+        if given.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+
+        let values = match item {
+            Item::Value(Value::Array(array)) => array.iter().map(value_to_string).collect(),
+            Item::Value(value) => value_to_string(value).map(|v| vec![v]),
+            Item::Table(table) => table_to_string(table.iter().map(|(k, i)| (k, i.as_value()))).map(|v| vec![v]),
+            Item::ArrayOfTables(tables) => tables.iter()
+                .map(|table| table_to_string(table.iter().map(|(k, i)| (k, i.as_value()))))
+                .collect(),
+            _ => None
+        };
+        let values = match values {
+            Some(values) => values,
+            None => return Err(format!("line {}: `{}` must be a string, number, boolean, table or array of them", line, key))
+        };
+
+        // Flags don't take a value, so they're either there or not
+        let option_args: Vec<String> = match arg.get_action() {
+            ArgAction::SetTrue => match values.as_slice() {
+                [v] if v == "true" => vec![format!("--{}", name)],
+                [v] if v == "false" => vec![],
+                _ => return Err(format!("line {}: `{}` must be true or false", line, key))
+            },
+            // Counted flags are given as how many times to repeat them
+            ArgAction::Count => match values.as_slice().first().and_then(|v| v.parse::<u8>().ok()) {
+                Some(n) if values.len() == 1 => vec![format!("--{}", name); n as usize],
+                _ => return Err(format!("line {}: `{}` must be a number", line, key))
+            },
+            _ => values.iter().map(|v| format!("--{}={}", name, v)).collect()
+        };
+
+        // Check the value on its own so that any mistake can be pinned to the
+        // line it's on, rather than coming out of the full parse later
+        let check = Command::new("config")
+            .no_binary_name(true)
+            .arg(arg.clone().required(false))
+            .try_get_matches_from(&option_args);
+        if let Err(e) = check {
+            let message = e.to_string();
+            let message = message.lines().next().unwrap_or_default().trim_start_matches("error: ");
+            return Err(format!("line {}: {}", line, message));
+        }
+
+        args.push((name, option_args));
+    }
+
+    Ok(args)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+    use clap::{value_parser, Arg};
+
+    fn command() -> Command {
+        Command::new("bl-control")
+            .arg(Arg::new("timeout").long("timeout").value_parser(value_parser!(f64)))
+            .arg(Arg::new("lock").long("lock").action(ArgAction::SetTrue))
+            .arg(Arg::new("zone").long("zone").action(ArgAction::Append))
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("bl-control-test-{}-{}.toml", process::id(), name))
+    }
+
+    // Reads the config from a file holding the given contents
+    fn args(name: &str, contents: &str) -> Result<Vec<String>, String> {
+        let path = test_path(name);
+        fs::write(&path, contents).unwrap();
+        let cmd = command();
+        let given = cmd.clone().get_matches_from(["bl-control"]);
+        let args = file_options(&path, &cmd, &given);
+        let _ = fs::remove_file(&path);
+        args.map(|options| options.into_iter().flat_map(|(_, option_args)| option_args).collect())
+    }
+
+    // Reads the config from files holding the given contents, one after the
+    // other
+    fn merged_args(name: &str, contents: &[&str]) -> Result<Vec<OsString>, String> {
+        let paths: Vec<PathBuf> = contents.iter().enumerate().map(|(i, contents)| {
+            let path = test_path(&format!("{}-{}", name, i));
+            fs::write(&path, contents).unwrap();
+            path
+        }).collect();
+        let cmd = command();
+        let given = cmd.clone().get_matches_from(["bl-control"]);
+        let args = to_args(&paths, &cmd, &given);
+        for path in paths {
+            let _ = fs::remove_file(&path);
+        }
+        args
+    }
+
+    #[test]
+    fn counts_lines_from_one() {
+        assert_eq!(line_number("a\nb\nc", 0), 1);
+        assert_eq!(line_number("a\nb\nc", 2), 2);
+        assert_eq!(line_number("a\nb\nc", 4), 3);
+    }
+
+    #[test]
+    fn turns_the_config_into_args() {
+        let args = args("ok", "timeout = 30\nlock = true\nzone = [\"bar=3\", \"logo=4\"]\n").unwrap();
+        assert_eq!(args, ["--timeout=30", "--lock", "--zone=bar=3", "--zone=logo=4"]);
+    }
+
+    #[test]
+    fn gives_the_line_of_an_unknown_option() {
+        let e = args("unknown", "timeout = 30\n\n# A comment\nbogus = 1\n").unwrap_err();
+        assert!(e.starts_with("line 4: unknown option `bogus`"), "{}", e);
+    }
+
+    #[test]
+    fn gives_the_line_of_a_bad_value() {
+        let e = args("value", "lock = true\ntimeout = \"soon\"\n").unwrap_err();
+        assert!(e.starts_with("line 2: "), "{}", e);
+
+        let e = args("flag", "\nlock = 3\n").unwrap_err();
+        assert!(e.starts_with("line 2: `lock` must be true or false"), "{}", e);
+    }
+
+    #[test]
+    fn reads_fragments_over_the_main_file() {
+        let args = merged_args("merge", &["timeout = 30\nzone = [\"bar=3\", \"logo=4\"]\n", "zone = \"bar=5\"\n", "lock = true\n"]).unwrap();
+        assert_eq!(args, ["--timeout=30", "--zone=bar=5", "--lock"]);
+    }
+
+    #[test]
+    fn names_the_fragment_with_a_mistake() {
+        let e = merged_args("fragment", &["timeout = 30\n", "\nbogus = 1\n"]).unwrap_err();
+        let fragment = test_path("fragment-1");
+        assert!(e.starts_with(&format!("{}: line 2: unknown option `bogus`", fragment.display())), "{}", e);
+    }
+
+    #[test]
+    fn reads_fragments_in_order() {
+        let dir = env::temp_dir().join(format!("bl-control-test-{}-conf.d", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["20-b.toml", "10-a.toml", "README"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let found = fragments(&dir);
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(found, [dir.join("10-a.toml"), dir.join("20-b.toml")]);
+        assert!(fragments(&dir).is_empty());
+    }
+}
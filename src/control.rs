@@ -0,0 +1,261 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+use tracing::{info, warn};
+use bl_control::error::BlError;
+
+use crate::{ActivitySource, Event, FadeSource};
+
+// How long a client can go without sending a command before it's dropped
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+// Works out where the daemon listens for commands by default
+pub fn default_path() -> PathBuf {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from("/run")
+    };
+    dir.join("bl-control").join("control")
+}
+
+
+// Works out where a one-shot command should look for the daemon: the socket
+// it was given, or else the user's own daemon's and then a system daemon's in
+// /run
+pub fn client_paths(given: Option<&Path>) -> Vec<PathBuf> {
+    if let Some(path) = given {
+        return vec![path.to_path_buf()];
+    }
+
+    let mut paths = vec![default_path()];
+    let system = PathBuf::from("/run/bl-control/control");
+    if !paths.contains(&system) {
+        paths.push(system);
+    }
+    paths
+}
+
+
+// Picks the first of the sockets that's there, for commands that can only go
+// to a daemon
+pub fn find_path(paths: &[PathBuf]) -> PathBuf {
+    paths.iter().find(|path| path.exists()).unwrap_or(&paths[0]).clone()
+}
+
+
+// Asks the main loop something, waiting for its answer
+fn query<T>(s: &UnboundedSender<Event>, event: impl FnOnce(oneshot::Sender<T>) -> Event) -> Result<T, String> {
+    let (reply, answer) = oneshot::channel();
+    s.send(event(reply)).map_err(|e| e.to_string())?;
+    answer.blocking_recv().map_err(|e| e.to_string())
+}
+
+
+// Runs a single JSON command, e.g. {"cmd":"set","level":30}, giving the fields
+// to answer with
+fn run_json_command(command: &Value, s: &UnboundedSender<Event>) -> Result<Value, String> {
+    let cmd = command["cmd"].as_str().ok_or("missing cmd")?;
+    match cmd {
+        // Set the level the user wants, bringing the backlight back on if
+        // it's dimmed
+        "set" => {
+            let level = command["level"].as_u64().ok_or("missing level")?;
+            let level = u8::try_from(level).map_err(|_| format!("level {} is out of range", level))?;
+            s.send(Event::SetLevel(level, FadeSource::Control)).map_err(|e| e.to_string())?;
+            Ok(json!({}))
+        },
+
+        // What the backlight is doing, and how long until it dims (null if
+        // it already has)
+        "status" => {
+            let state = query(s, Event::StateQuery)?;
+            let (idle, dim_in) = query(s, Event::IdleQuery)?;
+            Ok(json!({
+                "level": state.level,
+                "dimmed": state.dimmed,
+                "timeout": state.timeout.as_secs_f64(),
+                "idle": idle.as_secs_f64(),
+                "dim_in": dim_in.map(|d| d.as_secs_f64())
+            }))
+        },
+
+        // Don't dim on the timeout for this many seconds, replacing any
+        // earlier inhibit. Zero lifts it
+        "inhibit" => {
+            let secs = command["secs"].as_f64().ok_or("missing secs")?;
+            let secs = Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())?;
+            s.send(Event::InhibitFor(secs)).map_err(|e| e.to_string())?;
+            Ok(json!({}))
+        },
+
+        "activity" => {
+            s.send(Event::Activity(ActivitySource::Control)).map_err(|e| e.to_string())?;
+            Ok(json!({}))
+        },
+
+        other => Err(format!("unknown command {}", other))
+    }
+}
+
+
+// Runs a single command received on the socket, giving the answer to send back
+fn run_command(line: &str, s: &UnboundedSender<Event>) -> Result<String, String> {
+    match line.trim() {
+        // Anything that wants to count as the user being there
+        "activity" => {
+            s.send(Event::Activity(ActivitySource::Control)).map_err(|e| e.to_string())?;
+            Ok(String::new())
+        },
+
+        // How long the user has been idle and how long until we dim, in
+        // seconds, e.g. "idle=12.5 dim-in=2.5". dim-in is left out if the
+        // backlight is already dimmed
+        "idle-time" => {
+            let (idle, dim_in) = query(s, Event::IdleQuery)?;
+            Ok(match dim_in {
+                Some(dim_in) => format!("idle={:.1} dim-in={:.1}", idle.as_secs_f64(), dim_in.as_secs_f64()),
+                None => format!("idle={:.1}", idle.as_secs_f64())
+            })
+        },
+
+        other => Err(format!("unknown command {}", other))
+    }
+}
+
+
+// Handles the commands from a single client, one per line
+fn handle_client(stream: UnixStream, s: &UnboundedSender<Event>) {
+    let mut reply = match stream.try_clone() {
+        Ok(reply) => reply,
+        Err(e) => {
+            warn!("Failed to set up control connection: {}", e);
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return
+        };
+
+        // JSON commands are answered with their fields and "ok", or "ok" set
+        // to false and the "error". Others are answered with "ok" followed
+        // by any result, or "error" and why
+        let answer = match line.trim_start().starts_with('{') {
+            true => {
+                let result = serde_json::from_str(&line).map_err(|e| e.to_string())
+                    .and_then(|command| run_json_command(&command, s));
+                match result {
+                    Ok(mut fields) => {
+                        fields["ok"] = json!(true);
+                        fields.to_string()
+                    },
+                    Err(e) => json!({ "ok": false, "error": e }).to_string()
+                }
+            },
+            false => match run_command(&line, s) {
+                Ok(result) => format!("ok {}", result),
+                Err(e) => format!("error {}", e)
+            }
+        };
+        if writeln!(reply, "{}", answer.trim_end()).is_err() {
+            return;
+        }
+    }
+}
+
+
+// Creates a thread that listens for commands from other programs on a Unix
+// socket and posts them to the main loop
+pub fn spawn_listener(path: PathBuf, group: Option<u32>, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    // Clear out a socket left behind by an earlier run
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+
+    // Only the user running the daemon can connect, unless it's shared with a
+    // group (e.g. for a system daemon), rather than leaving it to the umask
+    let mode = match group {
+        Some(gid) => {
+            std::os::unix::fs::chown(&path, None, Some(gid))?;
+            0o660
+        },
+        None => 0o600
+    };
+    fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+    info!("Listening for commands on {}", path.display());
+
+    let thread_builder = thread::Builder::new().name("control".to_string());
+    thread_builder.spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept control connection: {}", e);
+                    continue;
+                }
+            };
+
+            // Each client gets its own thread, so that one that's slow to send
+            // its command doesn't hold up the others, and is dropped if it
+            // goes quiet
+            if let Err(e) = stream.set_read_timeout(Some(CLIENT_TIMEOUT)) {
+                warn!("Failed to set up control connection: {}", e);
+                continue;
+            }
+            let s = s.clone();
+            let client_builder = thread::Builder::new().name("control-client".to_string());
+            if let Err(e) = client_builder.spawn(move || handle_client(stream, &s)) {
+                warn!("Failed to start control connection thread: {}", e);
+            }
+        }
+    })
+}
+
+
+// Sends a single command to the running daemon and waits for its answer
+pub fn send_command(path: &Path, command: &str) -> Result<String, BlError> {
+    let mut stream = UnixStream::connect(path).map_err(|e| BlError::io(format!("could not connect to {}", path.display()), e))?;
+    writeln!(stream, "{}", command).map_err(|e| BlError::io("could not send command to the daemon", e))?;
+
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer).map_err(|e| BlError::io("could not read the daemon's answer", e))?;
+    match answer.trim().split_once(' ').unwrap_or((answer.trim(), "")) {
+        ("ok", result) => Ok(result.to_string()),
+        (_, reason) => Err(BlError::Protocol(format!("the daemon refused `{}`: {}", command, reason)))
+    }
+}
+
+
+// Sends a JSON command to the running daemon and gives the fields of its
+// answer, or None if there's no daemon listening to send it to
+pub fn send_json_command(path: &Path, command: &Value) -> Result<Option<Value>, BlError> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => return Ok(None),
+        Err(e) => return Err(BlError::io(format!("could not connect to {}", path.display()), e))
+    };
+    writeln!(stream, "{}", command).map_err(|e| BlError::io("could not send command to the daemon", e))?;
+
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer).map_err(|e| BlError::io("could not read the daemon's answer", e))?;
+    let answer: Value = serde_json::from_str(&answer)
+        .map_err(|e| BlError::Protocol(format!("the daemon's answer wasn't understood: {}", e)))?;
+    match answer["ok"].as_bool() {
+        Some(true) => Ok(Some(answer)),
+        _ => Err(BlError::Protocol(format!("the daemon refused `{}`: {}", command, answer["error"].as_str().unwrap_or("no reason given"))))
+    }
+}
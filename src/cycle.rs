@@ -0,0 +1,46 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use tracing::warn;
+
+
+// Works out where the position in the preset list is remembered between runs
+fn state_path() -> PathBuf {
+    let dir = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".local/state"),
+            None => PathBuf::from("/var/lib")
+        }
+    };
+    dir.join("bl-control").join("cycle-position")
+}
+
+
+// Returns the next preset level after the one used last time, and remembers
+// that it was used. Starts from the first preset if there's no saved position
+pub fn next_level(levels: &[u8]) -> Option<u8> {
+    if levels.is_empty() {
+        return None;
+    }
+
+    let path = state_path();
+    let next = match fs::read_to_string(&path) {
+        Ok(contents) => match contents.trim().parse::<usize>() {
+            Ok(position) => (position + 1) % levels.len(),
+            Err(_) => 0
+        },
+        Err(_) => 0
+    };
+
+    // Failing to save just means we start from the beginning next time
+    let saved = match path.parent() {
+        Some(parent) => fs::create_dir_all(parent).and_then(|_| fs::write(&path, next.to_string())),
+        None => Ok(())
+    };
+    if let Err(e) = saved {
+        warn!("Failed to save cycle position to {}: {}", path.display(), e);
+    }
+
+    Some(levels[next])
+}
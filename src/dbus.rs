@@ -0,0 +1,274 @@
+use std::io::Read;
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use futures::executor::block_on;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use zbus::{fdo, interface};
+use zbus::blocking::{Connection, Proxy};
+use zbus::blocking::connection::Builder;
+use zbus::object_server::SignalEmitter;
+
+use tracing::{debug, info, warn};
+use bl_control::error::BlError;
+use crate::{Event, FadeSource};
+
+// Where the service lives on the bus
+const SERVICE_NAME: &str = "org.blcontrol.Backlight1";
+const OBJECT_PATH: &str = "/org/blcontrol/Backlight1";
+
+
+// What the main loop is doing, for the D-Bus properties
+pub struct State {
+    pub level: u8,
+    pub dimmed: bool,
+    pub timeout: Duration
+}
+
+
+// A change the main loop has made, to be signalled on the bus
+enum Change {
+    Level(u8),
+    Dimmed(bool)
+}
+
+
+// Lets the main loop tell the service what's changed
+pub struct Signals {
+    s: Sender<Change>,
+    // The last level and dimming sent, so that we only send changes
+    level: Option<u8>,
+    dimmed: Option<bool>
+}
+
+impl Signals {
+    // Sends the level and whether we're dimmed if either has changed since the
+    // last time
+    pub fn send(&mut self, level: u8, dimmed: bool) {
+        if self.level != Some(level) {
+            let _ = self.s.send(Change::Level(level));
+            self.level = Some(level);
+        }
+        if self.dimmed != Some(dimmed) {
+            // There's nothing to signal when we first start out undimmed
+            if self.dimmed.is_some() || dimmed {
+                let _ = self.s.send(Change::Dimmed(dimmed));
+            }
+            self.dimmed = Some(dimmed);
+        }
+    }
+}
+
+
+// How many inhibitors are holding off each kind of dimming
+#[derive(Default)]
+struct Inhibitors {
+    idle: usize,
+    lock: usize
+}
+
+impl Inhibitors {
+    // Tells the main loop which kinds of dimming are inhibited
+    fn send(&self, s: &UnboundedSender<Event>) {
+        if let Err(e) = s.send(Event::Inhibit { idle: self.idle > 0, lock: self.lock > 0 }) {
+            warn!("{}", e);
+        }
+    }
+}
+
+
+struct Backlight {
+    s: UnboundedSender<Event>,
+    inhibitors: Arc<Mutex<Inhibitors>>
+}
+
+impl Backlight {
+    // Asks the main loop what it's doing
+    async fn state(&self) -> fdo::Result<State> {
+        let (reply, answer) = oneshot::channel();
+        self.s.send(Event::StateQuery(reply)).map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        answer.await.map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+}
+
+#[interface(name = "org.blcontrol.Backlight1")]
+impl Backlight {
+    // Sets the level the user wants, turning the backlight back on if it was
+    // dimmed
+    async fn set_brightness(&self, level: u32) -> fdo::Result<()> {
+        let level = level.min(u8::MAX as u32) as u8;
+        self.s.send(Event::SetLevel(level, FadeSource::Dbus)).map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    // The level the backlight is at, which is zero while dimmed
+    async fn get_brightness(&self) -> fdo::Result<u32> {
+        Ok(self.state().await?.level as u32)
+    }
+
+    // Stops the backlight being dimmed until the returned file descriptor is
+    // closed. `what` is a colon-separated list of "idle" (dimming on the
+    // timeout) and "lock" (dimming on the lock combination)
+    async fn inhibit(&self, what: String, why: String) -> fdo::Result<zbus::zvariant::OwnedFd> {
+        let (mut idle, mut lock) = (false, false);
+        for kind in what.split(':') {
+            match kind {
+                "idle" => idle = true,
+                "lock" => lock = true,
+                other => return Err(fdo::Error::InvalidArgs(format!("unknown inhibitor kind {}", other)))
+            }
+        }
+
+        let (ours, theirs) = UnixStream::pair().map_err(|e| fdo::Error::IOError(e.to_string()))?;
+        {
+            let mut inhibitors = self.inhibitors.lock().unwrap();
+            inhibitors.idle += idle as usize;
+            inhibitors.lock += lock as usize;
+            inhibitors.send(&self.s);
+        }
+        info!("Inhibiting {} dimming: {}", what, why);
+
+        // The caller's end closing (even if it exits without saying) ends
+        // the read, so wait for that to lift the inhibitor
+        let (s, inhibitors) = (self.s.clone(), self.inhibitors.clone());
+        let thread_builder = thread::Builder::new().name("dbus-inhibit".to_string());
+        let watcher = thread_builder.spawn(move || {
+            let _ = (&ours).read_to_end(&mut Vec::new());
+            let mut inhibitors = inhibitors.lock().unwrap();
+            inhibitors.idle -= idle as usize;
+            inhibitors.lock -= lock as usize;
+            inhibitors.send(&s);
+            info!("No longer inhibiting {} dimming: {}", what, why);
+        });
+        watcher.map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        Ok(OwnedFd::from(theirs).into())
+    }
+
+    // Sent when the backlight level changes
+    #[zbus(signal, name = "BrightnessChanged")]
+    async fn level_changed(emitter: &SignalEmitter<'_>, level: u32) -> zbus::Result<()>;
+
+    // Sent when the backlight is dimmed, and when it comes back on
+    #[zbus(signal)]
+    async fn dim_started(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn dim_ended(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(property(emits_changed_signal = "invalidates"))]
+    async fn brightness(&self) -> fdo::Result<u32> {
+        Ok(self.state().await?.level as u32)
+    }
+
+    #[zbus(property(emits_changed_signal = "invalidates"))]
+    async fn dimming(&self) -> fdo::Result<bool> {
+        Ok(self.state().await?.dimmed)
+    }
+
+    // The idle timeout in seconds
+    #[zbus(property)]
+    async fn timeout(&self) -> fdo::Result<f64> {
+        Ok(self.state().await?.timeout.as_secs_f64())
+    }
+
+    #[zbus(property)]
+    async fn set_timeout(&self, timeout: f64) -> fdo::Result<()> {
+        let timeout = Duration::try_from_secs_f64(timeout)
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+        self.s.send(Event::SetTimeout(timeout)).map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+}
+
+
+// Signals a change from the main loop, along with the property it affects
+fn signal(conn: &Connection, change: Change) -> Result<(), zbus::Error> {
+    let iface = conn.object_server().interface::<_, Backlight>(OBJECT_PATH)?;
+    let emitter = iface.signal_emitter();
+    match change {
+        Change::Level(level) => {
+            block_on(Backlight::level_changed(emitter, level as u32))?;
+            block_on(iface.get().brightness_invalidate(emitter))
+        },
+        Change::Dimmed(dimmed) => {
+            match dimmed {
+                true => block_on(Backlight::dim_started(emitter))?,
+                false => block_on(Backlight::dim_ended(emitter))?
+            }
+            block_on(iface.get().dimming_invalidate(emitter))
+        }
+    }
+}
+
+
+// Puts the service on the bus, then signals changes from the main loop until
+// it goes away
+fn serve(system: bool, s: UnboundedSender<Event>, changes: Receiver<Change>) -> Result<(), zbus::Error> {
+    let backlight = Backlight { s, inhibitors: Arc::new(Mutex::new(Inhibitors::default())) };
+    let builder = match system {
+        true => Builder::system()?,
+        false => Builder::session()?
+    };
+    let conn = builder
+        .name(SERVICE_NAME)?
+        .serve_at(OBJECT_PATH, backlight)?
+        .build()?;
+    info!("Serving {} on the {} bus", SERVICE_NAME, if system { "system" } else { "session" });
+
+    // Calls are handled by the connection on its own thread
+    for change in changes {
+        if let Err(e) = signal(&conn, change) {
+            warn!("Failed to send D-Bus signal: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+
+// Creates a thread that offers control of the daemon over D-Bus, on either the
+// system or the session bus, giving what the main loop uses to signal changes
+pub fn spawn_service(system: bool, s: UnboundedSender<Event>) -> std::io::Result<Signals> {
+    let (changes_s, changes_r) = mpsc::channel();
+    let thread_builder = thread::Builder::new().name("dbus".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = serve(system, s, changes_r) {
+            warn!("Stopped serving on D-Bus: {}", e);
+        }
+    })?;
+    Ok(Signals { s: changes_s, level: None, dimmed: None })
+}
+
+
+// Finds a daemon serving on the session bus or, failing that, the system bus,
+// for one-shot commands to go through when its control socket can't be
+// reached. It's asked for its level to make sure it's really there
+pub fn find_service() -> Option<(Proxy<'static>, u8)> {
+    for system in [false, true] {
+        let conn = match system {
+            true => Connection::system(),
+            false => Connection::session()
+        };
+        let found = conn
+            .and_then(|conn| Proxy::new(&conn, SERVICE_NAME, OBJECT_PATH, SERVICE_NAME))
+            .and_then(|proxy| {
+                let level: u32 = proxy.call("GetBrightness", &())?;
+                Ok((proxy, level.min(u8::MAX as u32) as u8))
+            });
+        match found {
+            Ok(found) => return Some(found),
+            Err(e) => debug!("No daemon on the {} bus: {}", if system { "system" } else { "session" }, e)
+        }
+    }
+    None
+}
+
+
+// Sets the level through a daemon found on D-Bus
+pub fn set_level(proxy: &Proxy, level: u8) -> Result<(), BlError> {
+    proxy.call::<_, _, ()>("SetBrightness", &(level as u32))
+        .map_err(|e| BlError::Protocol(format!("the daemon refused to set the level over D-Bus: {}", e)))
+}
@@ -0,0 +1,122 @@
+// HID report descriptor items, as prefixes with the size bits masked off
+const LOGICAL_MAXIMUM: u8 = 0x24;
+const REPORT_SIZE: u8 = 0x74;
+const REPORT_ID: u8 = 0x84;
+const REPORT_COUNT: u8 = 0x94;
+const PUSH: u8 = 0xa4;
+const POP: u8 = 0xb4;
+const FEATURE: u8 = 0xb0;
+
+// The prefix of a long item, whose size is in the byte after it
+const LONG_ITEM: u8 = 0xfe;
+
+
+// The global items in effect, which Push and Pop save and restore
+#[derive(Clone, Copy, Default)]
+struct Globals {
+    logical_max: u32,
+    report_size: usize,
+    report_count: usize,
+    report_id: u32
+}
+
+
+// Finds the highest value a report descriptor allows in a byte of the
+// unnumbered feature report, from the Logical Maximum of the field the byte
+// is in. There's nothing to go by if the byte is padding, shares its field
+// with others of a different size or isn't described at all
+pub fn feature_byte_max(descriptor: &[u8], index: usize) -> Option<u32> {
+    let mut globals = Globals::default();
+    let mut stack = Vec::new();
+    let mut bit = 0;
+    let mut i = 0;
+    while i < descriptor.len() {
+        let prefix = descriptor[i];
+        if prefix == LONG_ITEM {
+            i += 3 + *descriptor.get(i + 1)? as usize;
+            continue;
+        }
+
+        let len = match prefix & 0x03 {
+            3 => 4,
+            len => len as usize
+        };
+        let data = descriptor.get(i + 1..i + 1 + len)?;
+        let value = data.iter().rev().fold(0u32, |value, byte| (value << 8) | *byte as u32);
+        match prefix & 0xfc {
+            LOGICAL_MAXIMUM => globals.logical_max = value,
+            REPORT_SIZE => globals.report_size = value as usize,
+            REPORT_COUNT => globals.report_count = value as usize,
+            REPORT_ID => globals.report_id = value,
+            PUSH => stack.push(globals),
+            POP => globals = stack.pop()?,
+            FEATURE if globals.report_id == 0 => {
+                let end = bit + globals.report_size * globals.report_count;
+                if (bit..end).contains(&(index * 8)) {
+                    // Bit 0 of the data marks a constant field, i.e. padding
+                    let constant = value & 0x01 != 0;
+                    return (!constant && globals.report_size == 8).then_some(globals.logical_max);
+                }
+                bit = end;
+            },
+            _ => ()
+        }
+        i += 1 + len;
+    }
+
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A vendor page with a constant byte, then six bytes going up to 50 and
+    // one going up to 255
+    const DESCRIPTOR: [u8; 27] = [
+        0x06, 0x89, 0xff,  // Usage Page (vendor)
+        0x09, 0x01,        // Usage
+        0xa1, 0x01,        // Collection (application)
+        0x75, 0x08,        // Report Size (8)
+        0x95, 0x01,        // Report Count (1)
+        0xb1, 0x01,        // Feature (constant)
+        0x25, 0x32,        // Logical Maximum (50)
+        0x95, 0x06,        // Report Count (6)
+        0xb1, 0x02,        // Feature (data, variable)
+        0x26, 0xff, 0x00,  // Logical Maximum (255)
+        0x95, 0x01,        // Report Count (1)
+        0xb1, 0x02,        // Feature (data, variable)
+        0xc0               // End Collection
+    ];
+
+    #[test]
+    fn finds_the_range_of_a_byte() {
+        assert_eq!(feature_byte_max(&DESCRIPTOR, 4), Some(50));
+        assert_eq!(feature_byte_max(&DESCRIPTOR, 7), Some(255));
+    }
+
+    #[test]
+    fn has_no_range_for_padding_or_missing_bytes() {
+        assert_eq!(feature_byte_max(&DESCRIPTOR, 0), None);
+        assert_eq!(feature_byte_max(&DESCRIPTOR, 8), None);
+    }
+
+    #[test]
+    fn skips_numbered_reports() {
+        let descriptor = [0x85, 0x01, 0x75, 0x08, 0x95, 0x08, 0x25, 0x32, 0xb1, 0x02];
+        assert_eq!(feature_byte_max(&descriptor, 4), None);
+    }
+
+    #[test]
+    fn restores_pushed_globals() {
+        let descriptor = [0x75, 0x08, 0x95, 0x01, 0x25, 0x32, 0xa4, 0x25, 0x10, 0xb1, 0x02, 0xb4, 0xb1, 0x02];
+        assert_eq!(feature_byte_max(&descriptor, 0), Some(16));
+        assert_eq!(feature_byte_max(&descriptor, 1), Some(50));
+    }
+
+    #[test]
+    fn gives_up_on_truncated_descriptors() {
+        assert_eq!(feature_byte_max(&[0x75, 0x08, 0x26, 0xff], 0), None);
+    }
+}
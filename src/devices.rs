@@ -0,0 +1,204 @@
+use std::fs::{self, File};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::warn;
+use bl_control::log;
+use bl_control::error::BlError;
+
+use crate::{ActivitySource, Event};
+use crate::input;
+
+// Constants from /usr/include/linux/input-event-codes.h
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const REL_X: u16 = 0x00;
+const ABS_HAT0X: u16 = 0x10;
+const ABS_HAT0Y: u16 = 0x11;
+const BTN_LEFT: u16 = 0x110;
+const BTN_JOYSTICK: u16 = 0x120;
+const BTN_GAMEPAD: u16 = 0x130;
+const BTN_TOOL_PEN: u16 = 0x140;
+const BTN_TOOL_FINGER: u16 = 0x145;
+const BTN_TOUCH: u16 = 0x14a;
+
+// From /usr/include/linux/input.h. Touchpads and tablets like the Intuos move a
+// pointer, while touchscreens and screen tablets are used directly
+const INPUT_PROP_POINTER: u16 = 0x00;
+const INPUT_PROP_DIRECT: u16 = 0x01;
+
+// The letter keys, which anything we'd call a keyboard has to have. Macro pads
+// and media remotes report EV_KEY too, but not these
+const LETTER_KEYS: [u16; 26] = [
+    16, 17, 18, 19, 20, 21, 22, 23, 24, 25, // KEY_Q to KEY_P
+    30, 31, 32, 33, 34, 35, 36, 37, 38, // KEY_A to KEY_L
+    44, 45, 46, 47, 48, 49, 50 // KEY_Z to KEY_M
+];
+
+// Touch, pen, pointer and gamepad input comes in a flood of events, so only pass on
+// activity this often
+const MIN_INTERVAL: Duration = Duration::from_millis(500);
+
+
+// Checks whether a bit is set in a capability bitmap from sysfs, which is
+// given as hex words with the highest first
+pub fn has_bit(bitmap: &str, bit: u16) -> bool {
+    let words: Vec<&str> = bitmap.split_whitespace().rev().collect();
+    let bits_per_word = usize::BITS as usize;
+    match words.get(bit as usize / bits_per_word).and_then(|w| usize::from_str_radix(w, 16).ok()) {
+        Some(word) => word & (1 << (bit as usize % bits_per_word)) != 0,
+        None => false
+    }
+}
+
+
+// Finds the input devices that report absolute positions (or sticks) and
+// whose buttons and properties are what `wanted` is looking for
+fn find_devices<F>(wanted: F) -> Result<Vec<String>, BlError>
+where F: Fn(&str, &str) -> bool {
+    let entries = fs::read_dir("/sys/class/input").map_err(|e| BlError::io("could not list input devices", e))?;
+    let mut devices = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("event") {
+            continue;
+        }
+
+        let capabilities = entry.path().join("device/capabilities");
+        let abs = fs::read_to_string(capabilities.join("abs")).unwrap_or_default();
+        let key = fs::read_to_string(capabilities.join("key")).unwrap_or_default();
+        let properties = fs::read_to_string(capabilities.join("../properties")).unwrap_or_default();
+        if abs.trim().trim_start_matches('0').is_empty() {
+            continue;
+        }
+        if wanted(&key, &properties) {
+            devices.push(format!("/dev/input/{}", name));
+        }
+    }
+
+    Ok(devices)
+}
+
+
+// Finds the keyboards, which are the devices reporting every letter key (the
+// same bitmap EVIOCGBIT gives). The laptop's own keyboard, which is on the
+// i8042 controller, comes first and the rest are in the order they were added
+pub fn find_keyboards() -> Result<Vec<String>, BlError> {
+    let entries = fs::read_dir("/sys/class/input").map_err(|e| BlError::io("could not list input devices", e))?;
+    let mut keyboards = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let number = match name.strip_prefix("event").and_then(|n| n.parse::<u32>().ok()) {
+            Some(number) => number,
+            None => continue
+        };
+
+        let key = fs::read_to_string(entry.path().join("device/capabilities/key")).unwrap_or_default();
+        if !LETTER_KEYS.iter().all(|k| has_bit(&key, *k)) {
+            continue;
+        }
+
+        let real_path = fs::canonicalize(entry.path()).unwrap_or_default();
+        let internal = real_path.to_string_lossy().contains("/serio");
+        keyboards.push((!internal, number, format!("/dev/input/{}", name)));
+    }
+
+    keyboards.sort();
+    Ok(keyboards.into_iter().map(|(_, _, path)| path).collect())
+}
+
+
+// Finds the touchscreens and drawing tablets. Touchscreens are touched
+// directly, unlike touchpads, while anything with a pen is a tablet whether
+// it's a screen or moves a pointer
+pub fn find_touch_devices() -> Result<Vec<String>, BlError> {
+    find_devices(|key, properties| {
+        has_bit(key, BTN_TOOL_PEN) || (has_bit(key, BTN_TOUCH) && has_bit(properties, INPUT_PROP_DIRECT))
+    })
+}
+
+
+// Finds the touchpads, which track fingers and move a pointer. Tablets can
+// report fingers too, but they're already found with the pen
+pub fn find_touchpads() -> Result<Vec<String>, BlError> {
+    find_devices(|key, properties| {
+        has_bit(key, BTN_TOOL_FINGER) && has_bit(properties, INPUT_PROP_POINTER) && !has_bit(key, BTN_TOOL_PEN)
+    })
+}
+
+
+// Finds the gamepads and joysticks
+pub fn find_gamepads() -> Result<Vec<String>, BlError> {
+    find_devices(|key, properties| {
+        (has_bit(key, BTN_GAMEPAD) || has_bit(key, BTN_JOYSTICK)) && !has_bit(properties, INPUT_PROP_POINTER)
+    })
+}
+
+
+// Finds the mice and trackpoints, which report relative movement and have a
+// left button
+pub fn find_mice() -> Result<Vec<String>, BlError> {
+    let entries = fs::read_dir("/sys/class/input").map_err(|e| BlError::io("could not list input devices", e))?;
+    let mut mice = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("event") {
+            continue;
+        }
+
+        let capabilities = entry.path().join("device/capabilities");
+        let rel = fs::read_to_string(capabilities.join("rel")).unwrap_or_default();
+        let key = fs::read_to_string(capabilities.join("key")).unwrap_or_default();
+        if has_bit(&rel, REL_X) && has_bit(&key, BTN_LEFT) {
+            mice.push(format!("/dev/input/{}", name));
+        }
+    }
+
+    Ok(mice)
+}
+
+
+// Works out whether an event counts as activity. Analog sticks drift and
+// report tiny movements all the time, so only buttons and the d-pad count
+// for gamepads
+fn is_activity(source: ActivitySource, in_type: u16, code: u16) -> bool {
+    match (source, in_type) {
+        (ActivitySource::Gamepad, EV_ABS) => code == ABS_HAT0X || code == ABS_HAT0Y,
+        (ActivitySource::Mouse, EV_REL) => true,
+        (_, EV_ABS) | (_, EV_KEY) => true,
+        _ => false
+    }
+}
+
+
+// Creates a thread that reads a touchscreen, tablet, mouse, touchpad or
+// gamepad and posts activity to the main loop whenever it's used
+pub fn spawn_reader(event_path: String, source: ActivitySource, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let mut reader = input::EventReader::new(File::open(&event_path)?);
+    let thread_builder = thread::Builder::new().name(format!("{:?}-reader", source).to_lowercase());
+    thread_builder.spawn(move || {
+        let mut last_sent: Option<Instant> = None;
+        loop {
+            let event = match reader.next_event() {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!(target: log::INPUT, device = %event_path, "Stopped reading {}: {}", event_path, e);
+                    return;
+                }
+            };
+            if !is_activity(source, event.in_type, event.code) {
+                continue;
+            }
+            if last_sent.is_some_and(|t| t.elapsed() < MIN_INTERVAL) {
+                continue;
+            }
+
+            if let Err(e) = s.send(Event::Activity(source)) {
+                warn!(target: log::INPUT, "{}", e);
+            }
+            last_sent = Some(Instant::now());
+        }
+    })
+}
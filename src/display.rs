@@ -0,0 +1,157 @@
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::{debug, info, warn};
+use bl_control::error::BlError;
+
+use crate::Event;
+
+// Where the kernel lists display backlights
+const BACKLIGHT_PATH: &str = "/sys/class/backlight";
+
+// Where the kernel lists graphics cards and their connectors, and how often
+// to check whether the displays on them are on
+const DRM_PATH: &str = "/sys/class/drm";
+const DRM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+
+// Finds the first display backlight the kernel knows about. Laptops with more
+// than one (e.g. both the firmware's and the GPU's) usually only change one
+// of them, so it may need picking with --display-backlight
+pub fn find_backlight() -> Result<PathBuf, BlError> {
+    let entries = fs::read_dir(BACKLIGHT_PATH).map_err(|e| BlError::io(format!("could not list {}", BACKLIGHT_PATH), e))?;
+    let mut found: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+    found.sort();
+    found.into_iter().next().ok_or_else(|| BlError::DeviceNotFound(format!("no display backlight in {}", BACKLIGHT_PATH)))
+}
+
+
+fn read_value(path: &Path) -> Result<u32, String> {
+    let value = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    value.trim().parse().map_err(|e| format!("could not read {}: {}", path.display(), e))
+}
+
+
+// Works out the keyboard level that's as far up its range as the display's
+// brightness is up its own
+fn scaled_level(backlight: &Path, max_level: u8) -> Result<u8, String> {
+    let brightness = read_value(&backlight.join("brightness"))?;
+    let max_brightness = read_value(&backlight.join("max_brightness"))?;
+    if max_brightness == 0 {
+        return Err(format!("{} has no brightness range", backlight.display()));
+    }
+    Ok((brightness.min(max_brightness) as f64 / max_brightness as f64 * max_level as f64).round() as u8)
+}
+
+
+// Creates a thread that follows the display's brightness, posting the
+// keyboard level that matches it to the main loop whenever that changes.
+// inotify only hears about the brightness being written to, which is how
+// desktops and brightness keys handled by software change it, but not changes
+// made by the firmware behind the kernel's back
+pub fn spawn_follower(backlight: PathBuf, max_level: u8, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+
+    let brightness_path = CString::new(backlight.join("brightness").as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::inotify_add_watch(fd, brightness_path.as_ptr(), libc::IN_MODIFY) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let thread_builder = thread::Builder::new().name("display".to_string());
+    thread_builder.spawn(move || {
+        info!("Following the display backlight at {}", backlight.display());
+
+        // The events themselves don't matter, as the brightness is read
+        // afresh after each batch of them
+        let mut buf = [0u8; 4096];
+        let mut level: Option<u8> = None;
+        loop {
+            match scaled_level(&backlight, max_level) {
+                Ok(new_level) if level != Some(new_level) => {
+                    debug!("Display brightness changed, so the level is {}", new_level);
+                    if let Err(e) = s.send(Event::DisplayLevel(new_level)) {
+                        warn!("{}", e);
+                        return;
+                    }
+                    level = Some(new_level);
+                },
+                Ok(_) => (),
+                Err(e) => warn!("Failed to read the display brightness: {}", e)
+            }
+
+            if let Err(e) = file.read(&mut buf) {
+                warn!("Stopped following the display backlight: {}", e);
+                return;
+            }
+        }
+    })
+}
+
+
+// Tells the main loop that the display has turned off or back on, if that's
+// changed, for whichever watcher is following it. Watchers start out with the
+// display on, as the main loop does. Gives false once the main loop has gone
+pub fn send_power(on: bool, was_on: &mut bool, source: &str, s: &UnboundedSender<Event>) -> bool {
+    if on == *was_on {
+        return true;
+    }
+
+    debug!("{} says the display is {}", source, if on { "on" } else { "off" });
+    *was_on = on;
+    s.send(Event::DisplayPower(on)).is_ok()
+}
+
+
+// Checks whether any connected display is on, going by the kernel's DPMS
+// state for its connector (e.g. card0-eDP-1). This follows whatever turned
+// the display off, whether that was X, a Wayland compositor or the console
+fn displays_on() -> Result<bool, String> {
+    let entries = fs::read_dir(DRM_PATH).map_err(|e| format!("could not list {}: {}", DRM_PATH, e))?;
+    let mut connected = false;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if fs::read_to_string(path.join("status")).is_ok_and(|status| status.trim() == "connected") {
+            connected = true;
+            if fs::read_to_string(path.join("dpms")).is_ok_and(|dpms| dpms.trim() == "On") {
+                return Ok(true);
+            }
+        }
+    }
+
+    // Without any displays there's nothing to say they're off
+    Ok(!connected)
+}
+
+
+// Creates a thread that checks once a second whether the displays are on,
+// posting to the main loop whenever they all turn off or one comes back on
+pub fn spawn_power_poller(s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("drm-dpms".to_string());
+    thread_builder.spawn(move || {
+        info!("Polling {} for the display turning off", DRM_PATH);
+
+        let mut was_on = true;
+        loop {
+            match displays_on() {
+                Ok(on) => if !send_power(on, &mut was_on, "DRM", &s) {
+                    return;
+                },
+                Err(e) => warn!("Failed to check whether the display is on: {}", e)
+            }
+
+            thread::sleep(DRM_POLL_INTERVAL);
+        }
+    })
+}
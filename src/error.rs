@@ -0,0 +1,65 @@
+// The errors that can come out of talking to the backlight, split up by what
+// went wrong so that callers (and the daemon's exit code) can tell them apart
+
+use std::fmt::Display;
+use std::io;
+use thiserror::Error;
+
+// Exit codes, from sysexits.h so that systemd can name them
+const EX_CONFIG: u8 = 78;
+const EX_IOERR: u8 = 74;
+const EX_NOPERM: u8 = 77;
+const EX_OSERR: u8 = 71;
+const EX_PROTOCOL: u8 = 76;
+const EX_TEMPFAIL: u8 = 75;
+const EX_UNAVAILABLE: u8 = 69;
+
+#[derive(Debug, Error)]
+pub enum BlError {
+    // A libusb call or USB transfer failed
+    #[error("{0}: {1}")]
+    Usb(String, #[source] rusb::Error),
+    // Reading or writing a file, socket or device node failed
+    #[error("{0}: {1}")]
+    Io(String, #[source] io::Error),
+    // The controller, or another device we need, isn't there
+    #[error("{0}")]
+    DeviceNotFound(String),
+    // The device doesn't answer the way we expect, or can't do what was asked
+    #[error("{0}")]
+    Protocol(String),
+    // An option, or a file we were given, doesn't make sense
+    #[error("{0}")]
+    Config(String),
+    // Another instance is already driving the backlight
+    #[error("{0}")]
+    AlreadyRunning(String)
+}
+
+impl BlError {
+    // Wraps a libusb error with what we were doing at the time
+    pub fn usb(context: impl Display, error: rusb::Error) -> BlError {
+        BlError::Usb(context.to_string(), error)
+    }
+
+    // Wraps an I/O error with what we were doing at the time
+    pub fn io(context: impl Display, error: io::Error) -> BlError {
+        BlError::Io(context.to_string(), error)
+    }
+
+    // The process exit code for the error. Not being allowed to use a device
+    // gets its own code, as it's usually fixed by a udev rule
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            BlError::Usb(_, rusb::Error::Access) => EX_NOPERM,
+            BlError::Usb(_, rusb::Error::NoDevice | rusb::Error::NotFound) => EX_UNAVAILABLE,
+            BlError::Usb(_, _) => EX_OSERR,
+            BlError::Io(_, e) if e.kind() == io::ErrorKind::PermissionDenied => EX_NOPERM,
+            BlError::Io(_, _) => EX_IOERR,
+            BlError::DeviceNotFound(_) => EX_UNAVAILABLE,
+            BlError::Protocol(_) => EX_PROTOCOL,
+            BlError::Config(_) => EX_CONFIG,
+            BlError::AlreadyRunning(_) => EX_TEMPFAIL
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::{debug, info, warn};
+use crate::Event;
+
+// Every message starts with this so that stray datagrams are ignored
+const MAGIC: &str = "bl-control";
+
+// How far a message's counter, which is the time it was sent in
+// milliseconds, can be from our own clock before it's taken to be a replay
+const MAX_CLOCK_SKEW_MS: u64 = 30_000;
+
+type HmacSha256 = Hmac<Sha256>;
+
+
+// The current time in milliseconds, which leaders use as a counter
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+
+// Works out the MAC of a message with the shared key
+fn mac(key: &[u8], msg: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(msg.as_bytes());
+    mac
+}
+
+
+// Signs a message with the shared key, adding the MAC to the end of it as hex
+fn sign(key: &[u8], msg: &str) -> String {
+    let tag: String = mac(key, msg).finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{} mac={}", msg, tag)
+}
+
+
+// Checks the MAC at the end of a signed message, giving back the message
+// without it if it was signed with the shared key
+fn verify<'a>(key: &[u8], signed: &'a str) -> Option<&'a str> {
+    let (msg, tag) = signed.rsplit_once(" mac=")?;
+    if tag.len() % 2 != 0 {
+        return None;
+    }
+    let tag: Vec<u8> = (0..tag.len()).step_by(2)
+        .map(|i| u8::from_str_radix(tag.get(i..i + 2)?, 16).ok())
+        .collect::<Option<_>>()?;
+    mac(key, msg).verify_slice(&tag).ok()?;
+    Some(msg)
+}
+
+
+// Gets the counter from a message
+fn counter(msg: &str) -> Option<u64> {
+    msg.split_whitespace().find_map(|part| part.strip_prefix("counter=")?.parse().ok())
+}
+
+
+// The backlight state shared between machines
+#[derive(Clone, Copy, PartialEq)]
+pub struct State {
+    // The level the user has set
+    pub level: u8,
+    // Whether the backlight is currently dimmed
+    pub dimmed: bool,
+    // The backlight color, if one was set
+    pub color: Option<(u8, u8, u8)>
+}
+
+impl State {
+    // Encodes the state as a single line of text, e.g.
+    // "bl-control level=30 dimmed=0 color=255,0,0"
+    fn encode(&self) -> String {
+        let mut msg = format!("{} level={} dimmed={}", MAGIC, self.level, self.dimmed as u8);
+        if let Some((r, g, b)) = self.color {
+            msg.push_str(&format!(" color={},{},{}", r, g, b));
+        }
+        msg
+    }
+
+    // Decodes a message sent by encode()
+    fn decode(msg: &str) -> Option<State> {
+        let mut parts = msg.split_whitespace();
+        if parts.next()? != MAGIC {
+            return None;
+        }
+
+        let mut state = State { level: 0, dimmed: false, color: None };
+        for part in parts {
+            match part.split_once('=')? {
+                ("level", v) => state.level = v.parse().ok()?,
+                ("dimmed", v) => state.dimmed = v == "1",
+                ("color", v) => {
+                    let rgb: Vec<u8> = v.split(',').filter_map(|c| c.parse().ok()).collect();
+                    if rgb.len() != 3 {
+                        return None;
+                    }
+                    state.color = Some((rgb[0], rgb[1], rgb[2]));
+                },
+
+                // Ignore anything added by newer versions
+                _ => ()
+            }
+        }
+
+        Some(state)
+    }
+}
+
+
+// Sends our state to an instance that is following us
+pub struct Leader {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    key: Vec<u8>,
+    // The last state sent, so that we only send changes
+    last: Option<State>,
+    // The counter of the last message, which has to go up with every message
+    // so that followers can tell a replay
+    counter: u64
+}
+
+impl Leader {
+    // Creates a socket to send state to the given address, which may be a
+    // broadcast address, signing it with the shared key
+    pub fn new(addr: SocketAddr, key: &str) -> std::io::Result<Leader> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_broadcast(true)?;
+        Ok(Leader { socket, addr, key: key.as_bytes().to_vec(), last: None, counter: 0 })
+    }
+
+    // Sends the state if it has changed since the last time
+    pub fn send(&mut self, state: State) {
+        if self.last == Some(state) {
+            return;
+        }
+
+        self.counter = now_ms().max(self.counter + 1);
+        let msg = sign(&self.key, &format!("{} counter={}", state.encode(), self.counter));
+        match self.socket.send_to(msg.as_bytes(), self.addr) {
+            Ok(_) => self.last = Some(state),
+            Err(e) => warn!("Failed to send state to {}: {}", self.addr, e)
+        }
+    }
+}
+
+
+// Checks a message from a leader, giving back the state in it if it was
+// signed with the shared key and isn't a replay of an earlier one
+fn accept(key: &[u8], msg: &str, last_counter: Option<u64>, now: u64) -> Result<(State, u64), &'static str> {
+    let msg = verify(key, msg).ok_or("bad signature")?;
+    let counter = counter(msg).ok_or("no counter")?;
+    if last_counter.is_some_and(|last| counter <= last) || counter.abs_diff(now) > MAX_CLOCK_SKEW_MS {
+        return Err("replayed or stale");
+    }
+    let state = State::decode(msg).ok_or("malformed")?;
+    Ok((state, counter))
+}
+
+
+// Creates a thread that listens for state from a leader on the given address
+// and posts it to the main loop. Only datagrams from the allowed leaders that
+// are signed with the shared key are believed
+pub fn spawn_follower(addr: SocketAddr, key: &str, allowed: Vec<IpAddr>, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let socket = UdpSocket::bind(addr)?;
+    let key = key.as_bytes().to_vec();
+    info!("Following state sent to {}", addr);
+
+    let thread_builder = thread::Builder::new().name("follower".to_string());
+    thread_builder.spawn(move || {
+        let mut buf = [0; 256];
+        let mut counters: HashMap<IpAddr, u64> = HashMap::new();
+        loop {
+            let (count, from) = match socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("Error receiving state: {}", e);
+                    continue;
+                }
+            };
+
+            let ip = from.ip().to_canonical();
+            if !allowed.contains(&ip) {
+                debug!("Ignoring state from {}, which isn't an allowed leader", from);
+                continue;
+            }
+
+            let msg = match std::str::from_utf8(&buf[..count]) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    warn!("Ignoring malformed state from {}", from);
+                    continue;
+                }
+            };
+            let state = match accept(&key, msg, counters.get(&ip).copied(), now_ms()) {
+                Ok((state, counter)) => {
+                    counters.insert(ip, counter);
+                    state
+                },
+                Err(e) => {
+                    warn!("Ignoring state from {}: {}", from, e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = s.send(Event::Follow(state)) {
+                warn!("{}", e);
+            }
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"shared secret";
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn signed(state: State, counter: u64) -> String {
+        sign(KEY, &format!("{} counter={}", state.encode(), counter))
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let state = State { level: 30, dimmed: true, color: Some((255, 0, 10)) };
+        assert_eq!(state.encode(), "bl-control level=30 dimmed=1 color=255,0,10");
+        assert!(State::decode(&state.encode()) == Some(state));
+
+        let state = State { level: 5, dimmed: false, color: None };
+        assert!(State::decode(&state.encode()) == Some(state));
+    }
+
+    #[test]
+    fn ignores_unknown_fields() {
+        let state = State::decode("bl-control level=12 dimmed=0 counter=4 effect=wave").unwrap();
+        assert_eq!((state.level, state.dimmed, state.color), (12, false, None));
+    }
+
+    #[test]
+    fn rejects_malformed_state() {
+        assert!(State::decode("something-else level=12").is_none());
+        assert!(State::decode("bl-control level=300").is_none());
+        assert!(State::decode("bl-control level=12 color=1,2").is_none());
+        assert!(State::decode("bl-control level").is_none());
+        assert!(State::decode("").is_none());
+    }
+
+    #[test]
+    fn accepts_signed_state() {
+        let state = State { level: 30, dimmed: false, color: None };
+        let (accepted, counter) = accept(KEY, &signed(state, NOW), None, NOW).unwrap();
+        assert!(accepted == state);
+        assert_eq!(counter, NOW);
+    }
+
+    #[test]
+    fn rejects_the_wrong_key_and_tampering() {
+        let state = State { level: 30, dimmed: false, color: None };
+        let msg = signed(state, NOW);
+        assert_eq!(accept(b"another key", &msg, None, NOW).err(), Some("bad signature"));
+        assert_eq!(accept(KEY, &msg.replace("level=30", "level=50"), None, NOW).err(), Some("bad signature"));
+        assert_eq!(accept(KEY, &state.encode(), None, NOW).err(), Some("bad signature"));
+    }
+
+    #[test]
+    fn rejects_replays_and_stale_state() {
+        let state = State { level: 30, dimmed: false, color: None };
+        assert_eq!(accept(KEY, &signed(state, NOW), Some(NOW), NOW).err(), Some("replayed or stale"));
+        assert_eq!(accept(KEY, &signed(state, NOW - 1), Some(NOW), NOW).err(), Some("replayed or stale"));
+        assert_eq!(accept(KEY, &signed(state, NOW - MAX_CLOCK_SKEW_MS - 1), None, NOW).err(), Some("replayed or stale"));
+        assert!(accept(KEY, &signed(state, NOW + 1), Some(NOW), NOW).is_ok());
+    }
+
+    #[test]
+    fn rejects_state_without_a_counter() {
+        let msg = sign(KEY, &State { level: 30, dimmed: false, color: None }.encode());
+        assert_eq!(accept(KEY, &msg, None, NOW).err(), Some("no counter"));
+    }
+}
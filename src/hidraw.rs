@@ -0,0 +1,133 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::error::BlError;
+
+// Request numbers for feature reports from /usr/include/linux/hidraw.h
+const HIDIOCSFEATURE: u64 = 0x06;
+const HIDIOCGFEATURE: u64 = 0x07;
+
+// The report descriptor requests, as _IOR('H', 0x01, int) and
+// _IOR('H', 0x02, struct hidraw_report_descriptor), which is the size followed
+// by up to HID_MAX_DESCRIPTOR_SIZE bytes
+const HIDIOCGRDESCSIZE: u64 = (2 << 30) | (4 << 16) | ((b'H' as u64) << 8) | 0x01;
+const HIDIOCGRDESC: u64 = (2 << 30) | ((4 + HID_MAX_DESCRIPTOR_SIZE as u64) << 16) | ((b'H' as u64) << 8) | 0x02;
+const HID_MAX_DESCRIPTOR_SIZE: usize = 4096;
+
+
+// Builds the ioctl request for a feature report of the given length, as the
+// _IOC(_IOC_WRITE|_IOC_READ, 'H', nr, len) macro does
+fn feature_ioctl(nr: u64, len: usize) -> u64 {
+    (3 << 30) | ((len as u64) << 16) | ((b'H' as u64) << 8) | nr
+}
+
+
+// Sends a feature report. The controller doesn't use numbered reports, so the
+// report number in front of the data is always zero
+fn ioctl_feature(file: &File, nr: u64, buffer: &mut [u8]) -> Result<(), BlError> {
+    let request = feature_ioctl(nr, buffer.len());
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), request as libc::Ioctl, buffer.as_mut_ptr()) };
+    match result < 0 {
+        true => Err(BlError::io("feature report failed", std::io::Error::last_os_error())),
+        false => Ok(())
+    }
+}
+
+
+// Lists the hidraw nodes for an interface of every USB device from a vendor,
+// along with their product IDs. Each interface of a device gets its own node,
+// so the interface number has to be checked
+pub fn find_all(vendor_id: u16, interface: u8) -> Result<Vec<(PathBuf, u16)>, BlError> {
+    let entries = fs::read_dir("/sys/class/hidraw").map_err(|e| BlError::io("could not list hidraw devices", e))?;
+    let prefix = format!("HID_ID=0003:{:08X}:", vendor_id);
+
+    let mut found = Vec::new();
+    for entry in entries.flatten() {
+        let device = entry.path().join("device");
+        let uevent = match fs::read_to_string(device.join("uevent")) {
+            Ok(uevent) => uevent,
+            Err(_) => continue
+        };
+        let product_id = uevent.lines()
+            .find_map(|line| line.strip_prefix(&prefix))
+            .and_then(|id| u32::from_str_radix(id, 16).ok());
+        let product_id = match product_id {
+            Some(id) => id as u16,
+            None => continue
+        };
+
+        // The HID device sits under the USB interface it belongs to
+        let number = fs::read_to_string(device.join("../bInterfaceNumber")).ok()
+            .and_then(|n| u8::from_str_radix(n.trim(), 16).ok());
+        if number == Some(interface) {
+            found.push((Path::new("/dev").join(entry.file_name()), product_id));
+        }
+    }
+
+    found.sort();
+    Ok(found)
+}
+
+
+// Finds the hidraw node for an interface of a USB device
+pub fn find(vendor_id: u16, product_id: u16, interface: u8) -> Result<PathBuf, BlError> {
+    find_all(vendor_id, interface)?.into_iter()
+        .find(|(_, id)| *id == product_id)
+        .map(|(path, _)| path)
+        .ok_or_else(|| BlError::DeviceNotFound(format!("no hidraw device for 0x{:04x}:0x{:04x} interface {}", vendor_id, product_id, interface)))
+}
+
+
+// Opens a hidraw node for reading and writing reports
+pub fn open(path: &Path) -> Result<File, BlError> {
+    OpenOptions::new().read(true).write(true).open(path)
+        .map_err(|e| BlError::io(format!("could not open {}", path.display()), e))
+}
+
+
+// Writes a feature report to the controller
+pub fn set_feature_report(file: &File, data: &[u8]) -> Result<(), BlError> {
+    let mut buffer = vec![0];
+    buffer.extend_from_slice(data);
+    ioctl_feature(file, HIDIOCSFEATURE, &mut buffer)
+}
+
+
+// Sends a request to the controller and reads back its reply, which is the
+// same length as the request
+pub fn get_feature_report(file: &File, request: &[u8]) -> Result<Vec<u8>, BlError> {
+    set_feature_report(file, request)?;
+
+    let mut buffer = vec![0; request.len() + 1];
+    ioctl_feature(file, HIDIOCGFEATURE, &mut buffer)?;
+    buffer.remove(0);
+    Ok(buffer)
+}
+
+
+// Reads the report descriptor, which says how the controller's reports are
+// laid out
+pub fn report_descriptor(file: &File) -> Result<Vec<u8>, BlError> {
+    let mut size: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), HIDIOCGRDESCSIZE as libc::Ioctl, &mut size) } < 0 {
+        return Err(BlError::io("could not read the report descriptor size", std::io::Error::last_os_error()));
+    }
+    let size = (size.max(0) as usize).min(HID_MAX_DESCRIPTOR_SIZE);
+
+    let mut buffer = vec![0u8; 4 + HID_MAX_DESCRIPTOR_SIZE];
+    buffer[..4].copy_from_slice(&(size as u32).to_ne_bytes());
+    if unsafe { libc::ioctl(file.as_raw_fd(), HIDIOCGRDESC as libc::Ioctl, buffer.as_mut_ptr()) } < 0 {
+        return Err(BlError::io("could not read the report descriptor", std::io::Error::last_os_error()));
+    }
+    Ok(buffer[4..4 + size].to_vec())
+}
+
+
+// Writes an output report, which goes to the controller's interrupt endpoint
+pub fn write_report(file: &mut File, data: &[u8]) -> Result<(), BlError> {
+    let mut buffer = vec![0];
+    buffer.extend_from_slice(data);
+    file.write_all(&buffer).map_err(|e| BlError::io("output report failed", e))
+}
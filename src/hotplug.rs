@@ -0,0 +1,115 @@
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+use bl_control::log;
+
+// Devices are created before udev has set their permissions and finished
+// filling in sysfs, so wait a little before looking at them
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+
+// The size of a struct inotify_event, not counting the name after it
+const EVENT_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+
+
+// Starts watching a directory for the given inotify events
+fn watch(dir: &OsStr, mask: u32) -> std::io::Result<File> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+
+    let path = CString::new(dir.as_bytes()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+
+// Creates a thread that calls the given function whenever something is added
+// to a directory or has its permissions changed, e.g. a device node in
+// /dev/input
+pub fn spawn_watcher<F>(dir: &str, name: &str, mut changed: F) -> std::io::Result<thread::JoinHandle<()>>
+where F: FnMut() + Send + 'static {
+    let mut file = watch(OsStr::new(dir), libc::IN_CREATE | libc::IN_ATTRIB)?;
+
+    let thread_builder = thread::Builder::new().name(name.to_string());
+    thread_builder.spawn(move || {
+        // Big enough for plenty of events with their names. Which files they
+        // were for doesn't matter, as everything gets looked at again
+        let mut buf = [0u8; 4096];
+        loop {
+            if let Err(e) = file.read(&mut buf) {
+                warn!(target: log::INPUT, "Stopped watching for new devices: {}", e);
+                return;
+            }
+
+            thread::sleep(SETTLE_TIME);
+            changed();
+        }
+    })
+}
+
+
+// Creates a thread that calls the given function whenever a file is written
+// or replaced, e.g. by an editor that saves to a new file and renames it over
+// the old one. This watches the directory the file is in, so the file doesn't
+// have to exist yet
+pub fn spawn_file_watcher<F>(path: &Path, name: &str, changed: F) -> std::io::Result<thread::JoinHandle<()>>
+where F: FnMut() + Send + 'static {
+    let file_name = path.file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a path to a file"))?
+        .to_owned();
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    spawn_dir_watcher(dir, name, move |changed_name| changed_name == file_name, changed)
+}
+
+
+// Creates a thread that calls the given function whenever a file in a
+// directory that `wanted` picks out by name is written, replaced or removed
+pub fn spawn_dir_watcher<W, F>(dir: &Path, name: &str, wanted: W, mut changed: F) -> std::io::Result<thread::JoinHandle<()>>
+where W: Fn(&OsStr) -> bool + Send + 'static, F: FnMut() + Send + 'static {
+    let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_DELETE;
+    let mut file = watch(dir.as_os_str(), mask)?;
+
+    let dir = dir.to_owned();
+    let thread_builder = thread::Builder::new().name(name.to_string());
+    thread_builder.spawn(move || {
+        // Reads always give whole events, each followed by its name padded
+        // out with nuls
+        let mut buf = [0u8; 4096];
+        loop {
+            let count = match file.read(&mut buf) {
+                Ok(count) => count,
+                Err(e) => {
+                    warn!("Stopped watching {}: {}", dir.display(), e);
+                    return;
+                }
+            };
+
+            let mut matched = false;
+            let mut offset = 0;
+            while offset + EVENT_SIZE <= count {
+                // The kernel lines the events up, but the buffer needn't be
+                let event: libc::inotify_event = unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event) };
+                let name_start = offset + EVENT_SIZE;
+                offset = name_start + event.len as usize;
+                let name = buf.get(name_start..offset).unwrap_or_default();
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                matched |= wanted(OsStr::from_bytes(name));
+            }
+
+            if matched {
+                changed();
+            }
+        }
+    })
+}
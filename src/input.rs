@@ -0,0 +1,109 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::mem;
+
+// The size of a struct input_event, which depends on the size of the time in
+// front of it
+const EVENT_SIZE: usize = mem::size_of::<libc::input_event>();
+
+// How many events to ask for at once. Fast typing and pointer movement often
+// have several waiting by the time we read
+const EVENTS_PER_READ: usize = 64;
+
+// An event read from an input device, without its timestamp
+pub struct InputEvent {
+    pub in_type: u16,
+    pub code: u16,
+    pub value: i32
+}
+
+
+// Reads events from an input device, as many as are waiting at a time
+pub struct EventReader {
+    file: File,
+    // Bytes read that don't make up a whole event yet
+    partial: Vec<u8>,
+    // Whole events read but not handed out yet
+    pending: VecDeque<InputEvent>
+}
+
+impl EventReader {
+    pub fn new(file: File) -> EventReader {
+        EventReader { file, partial: Vec::new(), pending: VecDeque::new() }
+    }
+
+    // Returns the next event, reading more from the device when all of the
+    // ones from the last read have been handed out
+    pub fn next_event(&mut self) -> std::io::Result<InputEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+
+            let mut buf = [0u8; EVENT_SIZE * EVENTS_PER_READ];
+            let count = match self.file.read(&mut buf) {
+                Ok(0) => return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "input device went away")),
+                Ok(count) => count,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            };
+
+            // Anything left over from a short read goes in front, and anything
+            // short of a whole event is kept for next time
+            self.partial.extend_from_slice(&buf[..count]);
+            let whole = self.partial.len() / EVENT_SIZE * EVENT_SIZE;
+            for chunk in self.partial[..whole].chunks_exact(EVENT_SIZE) {
+                // Each chunk is exactly one struct long, and any bit pattern
+                // is a valid input_event
+                let event: libc::input_event = unsafe { std::ptr::read_unaligned(chunk.as_ptr() as *const libc::input_event) };
+                self.pending.push_back(InputEvent { in_type: event.type_, code: event.code, value: event.value });
+            }
+            self.partial.drain(..whole);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn event_bytes(code: u16, value: i32) -> Vec<u8> {
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+        event.type_ = 1;
+        event.code = code;
+        event.value = value;
+        // The struct is plain data, so its bytes are what the kernel would send
+        let bytes = unsafe { std::slice::from_raw_parts(&event as *const libc::input_event as *const u8, EVENT_SIZE) };
+        bytes.to_vec()
+    }
+
+    fn codes(reader: &mut EventReader, count: usize) -> Vec<(u16, i32)> {
+        (0..count).map(|_| reader.next_event().map(|event| (event.code, event.value)).unwrap()).collect()
+    }
+
+    #[test]
+    fn reads_events_split_across_and_joined_in_reads() {
+        let (read, mut write) = std::io::pipe().unwrap();
+        let mut reader = EventReader::new(File::from(std::os::fd::OwnedFd::from(read)));
+
+        // One whole event and the front half of another
+        let second = event_bytes(31, 0);
+        let mut bytes = event_bytes(30, 1);
+        bytes.extend_from_slice(&second[..EVENT_SIZE / 2]);
+        write.write_all(&bytes).unwrap();
+        assert_eq!(codes(&mut reader, 1), [(30, 1)]);
+
+        // The back half, then two more in the same write
+        let mut bytes = second[EVENT_SIZE / 2..].to_vec();
+        bytes.extend(event_bytes(32, 2));
+        bytes.extend(event_bytes(33, 1));
+        write.write_all(&bytes).unwrap();
+        assert_eq!(codes(&mut reader, 3), [(31, 0), (32, 2), (33, 1)]);
+
+        drop(write);
+        assert_eq!(reader.next_event().err().map(|e| e.kind()), Some(ErrorKind::UnexpectedEof));
+    }
+}
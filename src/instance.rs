@@ -0,0 +1,91 @@
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use bl_control::error::BlError;
+
+
+// Works out where the lock that keeps to one daemon lives by default
+pub fn default_path() -> PathBuf {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from("/run")
+    };
+    dir.join("bl-control.lock")
+}
+
+
+// Works out which locks a one-shot command should check for a daemon: the
+// lock it was given, or else the user's own daemon's and then a system
+// daemon's in /run
+pub fn client_paths(given: Option<&Path>) -> Vec<PathBuf> {
+    if let Some(path) = given {
+        return vec![path.to_path_buf()];
+    }
+
+    let mut paths = vec![default_path()];
+    let system = PathBuf::from("/run/bl-control.lock");
+    if !paths.contains(&system) {
+        paths.push(system);
+    }
+    paths
+}
+
+
+// Says who holds the lock, for when a daemon already has it
+fn already_running(file: &mut File, path: &Path) -> BlError {
+    let mut pid = String::new();
+    let _ = file.read_to_string(&mut pid);
+    BlError::AlreadyRunning(match pid.trim() {
+        "" => format!("another bl-control is already running (it holds {})", path.display()),
+        pid => format!("another bl-control is already running as PID {} (it holds {})", pid, path.display())
+    })
+}
+
+
+// Fails if a daemon holds the lock, without taking it. This is for one-shot
+// commands that would otherwise go to the device underneath a daemon they
+// couldn't reach
+pub fn check(path: &Path) -> Result<(), BlError> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(BlError::io(format!("could not open lock file {}", path.display()), e))
+    };
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::WouldBlock {
+            return Err(BlError::io(format!("could not check {}", path.display()), e));
+        }
+        return Err(already_running(&mut file, path));
+    }
+    Ok(())
+}
+
+
+// Takes the lock, so that two daemons don't fight over the keyboard, failing
+// if another daemon has it. It's held until the returned file is closed, which
+// happens when we exit however that is. Our PID is written to it to say who
+// has it
+pub fn lock(path: &Path) -> Result<File, BlError> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+        .map_err(|e| BlError::io(format!("could not open lock file {}", path.display()), e))?;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::WouldBlock {
+            return Err(BlError::io(format!("could not lock {}", path.display()), e));
+        }
+        return Err(already_running(&mut file, path));
+    }
+
+    let written = file.set_len(0)
+        .and_then(|_| file.rewind())
+        .and_then(|_| writeln!(file, "{}", process::id()));
+    written.map_err(|e| BlError::io(format!("could not write to lock file {}", path.display()), e))?;
+    Ok(file)
+}
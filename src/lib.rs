@@ -0,0 +1,1089 @@
+// Controls the backlight of ITE 8291 based keyboards over USB. This is what
+// the bl-control daemon is built on, and can be used by other tools (status
+// bars, applets) to read and set the backlight without shelling out
+
+extern crate rusb;
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use rusb::UsbContext;
+use clap_num::maybe_hex;
+use tracing::{trace, debug, info, warn};
+
+pub mod log;
+pub mod descriptor;
+pub mod error;
+pub mod hidraw;
+pub mod sysfs;
+pub mod template;
+
+use error::BlError;
+use template::{ReportTemplate, DEFAULT_REPORT_LENGTH, pad_report};
+
+// The effect channel of the keyboard itself
+pub const KEYBOARD_CHANNEL: u8 = 0x02;
+
+// The highest brightness level of the ITE 8291
+pub const MAX_LEVEL: u8 = 50;
+
+// Default report templates for setting and querying the brightness.
+// 0x08 is "set effect", 0x88 is "get effect"
+pub const DEFAULT_SET_TEMPLATE: &str = "[0x08, {channel}, 0x33, 0x00, {level}, 0x00, 0x00, 0x00]";
+pub const DEFAULT_GET_TEMPLATE: &str = "[0x88, {channel}, 0x33, 0x00, {level}, 0x00, 0x00, 0x00]";
+
+// The ways of talking to the controller
+pub enum Device {
+    // Claiming its USB interface, which detaches the kernel driver while we do
+    Usb(rusb::DeviceHandle<rusb::Context>),
+    // Through the kernel's hidraw node, leaving the kernel driver attached
+    Hidraw(std::fs::File)
+}
+
+// Where a controller was opened from, so that it can be opened again if it
+// goes away, e.g. after it re-enumerates on resume or on a dock
+#[derive(Clone)]
+pub enum DeviceSource {
+    Usb { vendor_id: u16, product_id: u16 },
+    // The node is looked up again from the IDs unless it was given, as it can
+    // get a different number when it comes back
+    Hidraw { vendor_id: u16, product_id: u16, path: Option<PathBuf> }
+}
+
+impl DeviceSource {
+    // Opens the controller again
+    pub fn open(&self) -> Result<Device, BlError> {
+        match self {
+            DeviceSource::Usb { vendor_id, product_id } => {
+                let context = rusb::Context::new().map_err(|e| BlError::usb("could not initialise libusb", e))?;
+                match context.open_device_with_vid_pid(*vendor_id, *product_id) {
+                    Some(handle) => Ok(Device::Usb(handle)),
+                    None => Err(BlError::DeviceNotFound(format!("couldn't find USB device 0x{:04x}:0x{:04x}", vendor_id, product_id)))
+                }
+            },
+            DeviceSource::Hidraw { vendor_id, product_id, path } => {
+                let path = match path {
+                    Some(path) => path.clone(),
+                    None => hidraw::find(*vendor_id, *product_id, 1)?
+                };
+                hidraw::open(&path).map(Device::Hidraw)
+            }
+        }
+    }
+}
+
+// How long to leave it between attempts to reopen a controller that's gone
+// away, so that a fade doesn't go looking for it at every step
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+
+// An auxiliary LED zone (e.g. a lightbar or logo) driven by the same controller
+// on a different effect channel
+#[derive(Clone)]
+pub struct Zone {
+    pub name: String,
+    pub channel: u8,
+    // Fixed level for the zone, otherwise it follows the keyboard
+    pub level: Option<u8>
+}
+
+// Revisions of the ITE protocol that we know about
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Protocol {
+    // Firmware before 0.3, some of which does not answer the version request
+    Rev2,
+    // Firmware 0.3 and later
+    Rev3
+}
+
+// Product IDs of known controllers and the protocol they speak, or None where
+// the same ID has shipped with more than one revision
+pub const KNOWN_CONTROLLERS: [(u16, Option<Protocol>); 3] = [
+    (0x6004, Some(Protocol::Rev3)),
+    (0x6006, Some(Protocol::Rev3)),
+    (0xce00, None)
+];
+
+// What the controller supports, as determined at startup
+#[derive(Clone, Copy)]
+pub struct Capabilities {
+    // Firmware version reported by the controller, if it answered
+    pub firmware: Option<[u8; 4]>,
+    // Highest brightness level the controller accepts
+    pub max_level: u8,
+    // Whether the backlight color can be changed
+    pub color: bool,
+    // Whether hardware lighting effects are available
+    pub effects: bool
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            firmware: None,
+            max_level: MAX_LEVEL,
+            color: true,
+            effects: false
+        }
+    }
+}
+
+// How to retry USB transfers that fail, e.g. by timing out during heavy USB
+// traffic. The delay doubles after each attempt
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub retries: u8,
+    pub delay: Duration
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { retries: 2, delay: Duration::from_millis(50) }
+    }
+}
+
+// Everything needed to talk to a particular controller
+pub struct DeviceDescription {
+    // Length of the feature reports the controller uses
+    pub report_length: usize,
+    // Report that sets the brightness of a channel
+    pub set_template: ReportTemplate,
+    // Report that queries the brightness of a channel
+    pub get_template: ReportTemplate,
+    // Auxiliary zones driven alongside the keyboard
+    pub zones: Vec<Zone>,
+    // How many times to retry a brightness write that doesn't read back as
+    // expected, or zero to not read back at all
+    pub verify_retries: u8,
+    // How to retry USB transfers that fail
+    pub retry: RetryPolicy,
+    // How many reads or writes have to fail in a row before the USB device is
+    // reset, or zero to never reset it
+    pub reset_after: u8,
+    // Whether the USB interface is claimed once and kept, rather than being
+    // claimed and released around every transfer
+    pub hold_interface: bool
+}
+
+impl Default for DeviceDescription {
+    // The description of the controller this program was written for
+    fn default() -> Self {
+        DeviceDescription {
+            report_length: DEFAULT_REPORT_LENGTH,
+            set_template: ReportTemplate::parse(DEFAULT_SET_TEMPLATE).unwrap(),
+            get_template: ReportTemplate::parse(DEFAULT_GET_TEMPLATE).unwrap(),
+            zones: Vec::new(),
+            verify_retries: 0,
+            retry: RetryPolicy::default(),
+            reset_after: 3,
+            hold_interface: false
+        }
+    }
+}
+
+
+// Parses a zone given on the command line
+pub fn parse_zone(s: &str) -> Result<Zone, String> {
+    let (name, rest) = match s.split_once('=') {
+        Some(parts) => parts,
+        None => return Err(String::from("expected NAME=CHANNEL[:LEVEL]"))
+    };
+
+    let (channel, level) = match rest.split_once(':') {
+        Some((channel, level)) => (channel, Some(maybe_hex::<u8>(level)?)),
+        None => (rest, None)
+    };
+
+    Ok(Zone {
+        name: String::from(name),
+        channel: maybe_hex::<u8>(channel)?,
+        level
+    })
+}
+
+
+// Detaches the kernel driver from the interface if it's bound, returning
+// whether it was
+fn detach_kernel_driver(handle: &mut rusb::DeviceHandle<rusb::Context>) -> bool {
+    let is_active = match handle.kernel_driver_active(1) {
+        Ok(a) => a,
+        Err(e) => {
+            warn!(target: log::USB, "Error determining driver activity: {}", e);
+            return false;
+        }
+    };
+
+    if is_active {
+        match handle.detach_kernel_driver(1) {
+            Err(e) => {
+                warn!(target: log::USB, "Error detaching kernel driver: {}", e);
+                false
+            },
+            _ => true
+        }
+    } else {
+        false
+    }
+}
+
+
+// Gives the interface back, reattaching the kernel driver if it was detached
+fn release_control(handle: &mut rusb::DeviceHandle<rusb::Context>, is_active: bool) {
+    if let Err(e) = handle.release_interface(1) {
+        warn!(target: log::USB, "Release Error: {}", e);
+    }
+
+    if is_active {
+        if let Err(e) = handle.attach_kernel_driver(1) {
+            warn!(target: log::USB, "Error attaching kernel driver: {}", e);
+        }
+    }
+}
+
+
+// Control of the controller's interface for as long as it's in scope. The
+// interface is released and the kernel driver reattached when it's dropped,
+// so an early return or a panic can't leave the keyboard without its driver
+struct InterfaceGuard<'a> {
+    handle: &'a mut rusb::DeviceHandle<rusb::Context>,
+    // Whether we claimed the interface, rather than it already being held
+    claimed: bool,
+    // Whether the kernel driver was detached to claim it
+    is_active: bool
+}
+
+impl<'a> InterfaceGuard<'a> {
+    // Detaches the kernel driver and claims the interface, unless it's already
+    // held, in which case the guard leaves it alone. The driver is given back
+    // straight away if the interface can't be claimed
+    fn claim(handle: &'a mut rusb::DeviceHandle<rusb::Context>, held: bool) -> rusb::Result<InterfaceGuard<'a>> {
+        if held {
+            return Ok(InterfaceGuard { handle, claimed: false, is_active: false });
+        }
+
+        let is_active = detach_kernel_driver(handle);
+        if let Err(e) = handle.claim_interface(1) {
+            if is_active {
+                if let Err(e) = handle.attach_kernel_driver(1) {
+                    warn!(target: log::USB, "Error attaching kernel driver: {}", e);
+                }
+            }
+            return Err(e);
+        }
+        Ok(InterfaceGuard { handle, claimed: true, is_active })
+    }
+
+    // Keeps the interface claimed after the guard goes, returning whether the
+    // kernel driver was detached so that it can be given back later by
+    // release_held_interface
+    fn keep(mut self) -> bool {
+        self.claimed = false;
+        self.is_active
+    }
+}
+
+impl std::ops::Deref for InterfaceGuard<'_> {
+    type Target = rusb::DeviceHandle<rusb::Context>;
+
+    fn deref(&self) -> &Self::Target {
+        self.handle
+    }
+}
+
+impl Drop for InterfaceGuard<'_> {
+    fn drop(&mut self) {
+        if self.claimed {
+            release_control(self.handle, self.is_active);
+        }
+    }
+}
+
+
+// Claims the interface for good, so that transfers don't have to detach the
+// kernel driver each time. Returns whether the driver was detached, so that it
+// can be given back by release_held_interface
+fn hold_interface(device: &mut Device) -> Result<bool, BlError> {
+    match device {
+        Device::Usb(handle) => InterfaceGuard::claim(handle, false)
+            .map(InterfaceGuard::keep)
+            .map_err(|e| BlError::usb("could not claim interface", e)),
+        Device::Hidraw(_) => Ok(false)
+    }
+}
+
+
+// Gives back an interface claimed by hold_interface
+fn release_held_interface(device: &mut Device, is_active: bool) {
+    if let Device::Usb(handle) = device {
+        release_control(handle, is_active);
+    }
+}
+
+
+// Works out whether a failed transfer is worth trying again. A device that's
+// gone or that we aren't allowed to use won't get any better
+fn is_transient(error: rusb::Error) -> bool {
+    matches!(error, rusb::Error::Timeout | rusb::Error::Pipe | rusb::Error::Io | rusb::Error::Busy
+        | rusb::Error::Overflow | rusb::Error::Interrupted)
+}
+
+
+// Runs a USB transaction, trying it again with a growing delay if it fails in
+// a way that might not happen next time. The reports all go over the control
+// pipe, and a stall there clears itself with the next request
+fn usb_with_retries<T, F>(handle: &mut rusb::DeviceHandle<rusb::Context>, retry: &RetryPolicy, mut transaction: F) -> Result<T, BlError>
+where F: FnMut(&mut rusb::DeviceHandle<rusb::Context>) -> rusb::Result<T> {
+    let mut delay = retry.delay;
+    let mut attempt = 0;
+    loop {
+        let error = match transaction(handle) {
+            Ok(result) => return Ok(result),
+            Err(e) => e
+        };
+        if attempt >= retry.retries || !is_transient(error) {
+            return Err(BlError::usb("USB transfer failed", error));
+        }
+
+        debug!(target: log::USB, "USB transfer failed ({}), retrying in {:?}", error, delay);
+        std::thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+
+// Sends a request to the controller over USB and reads back its reply
+fn usb_get_feature_report(handle: &mut rusb::DeviceHandle<rusb::Context>, desc: &DeviceDescription, request: &[u8]) -> Result<Vec<u8>, BlError> {
+    usb_with_retries(handle, &desc.retry, |handle| {
+        let handle = InterfaceGuard::claim(handle, desc.hold_interface)?;
+
+        // Set up some request types
+        let request_type_in = rusb::request_type(rusb::Direction::In, rusb::RequestType::Class, rusb::Recipient::Interface);
+        let request_type_out = rusb::request_type(rusb::Direction::Out, rusb::RequestType::Class, rusb::Recipient::Interface);
+
+        // The reply is the same length as the request
+        let mut data = request.to_vec();
+
+        // Write out the request, then read the reply
+        // request 0x09 is HID set_report, 0x01 is HID get_report
+        // value 0x0300 is HID feature
+        // index 0x0001 is whatever
+        let result = handle.write_control(request_type_out, 0x09, 0x0300, 0x0001, &data, Duration::from_secs(1))
+            .and_then(|_| handle.read_control(request_type_in, 0x01, 0x0300, 0x0001, &mut data, Duration::from_secs(1)));
+
+        result.map(|_| data)
+    })
+}
+
+
+// Sends a request to the controller and reads back its reply
+fn get_feature_report(device: &mut Device, desc: &DeviceDescription, request: &[u8]) -> Result<Vec<u8>, BlError> {
+    trace!(target: log::USB, "Request: {:02x?}", request);
+    let reply = match device {
+        Device::Usb(handle) => usb_get_feature_report(handle, desc, request),
+        Device::Hidraw(file) => hidraw::get_feature_report(file, request)
+    }?;
+    trace!(target: log::USB, "Reply: {:02x?}", reply);
+    Ok(reply)
+}
+
+
+// Determines the current brightness level of a single effect channel
+pub fn read_channel_level(handle: &mut Device, desc: &DeviceDescription, channel: u8) -> Result<u8, BlError> {
+    let request = desc.get_template.fill(channel, 0, desc.report_length);
+    let data = get_feature_report(handle, desc, &request)?;
+
+    Ok(data[desc.get_template.level_index()])
+}
+
+
+// Determines the current brightness level of the keyboard backlight
+pub fn read_brightness_level(handle: &mut Device, desc: &DeviceDescription) -> Result<u8, BlError> {
+    read_channel_level(handle, desc, KEYBOARD_CHANNEL)
+}
+
+
+// Reads the controller's HID report descriptor, which says how its reports
+// are laid out
+fn read_report_descriptor(device: &mut Device, desc: &DeviceDescription) -> Result<Vec<u8>, BlError> {
+    match device {
+        Device::Usb(handle) => usb_with_retries(handle, &desc.retry, |handle| {
+            let handle = InterfaceGuard::claim(handle, desc.hold_interface)?;
+
+            // request 0x06 is get_descriptor, value 0x2200 is the HID report
+            // descriptor
+            let request_type = rusb::request_type(rusb::Direction::In, rusb::RequestType::Standard, rusb::Recipient::Interface);
+            let mut data = vec![0; 4096];
+            let len = handle.read_control(request_type, 0x06, 0x2200, 0x0001, &mut data, Duration::from_secs(1))?;
+            data.truncate(len);
+            Ok(data)
+        }),
+        Device::Hidraw(file) => hidraw::report_descriptor(file)
+    }
+}
+
+
+// Works out the highest level from the range the report descriptor gives the
+// level byte. Vendor reports usually allow the whole byte, which doesn't say
+// anything, so then it's taken to be the ITE 8291's
+fn read_max_level(handle: &mut Device, desc: &DeviceDescription) -> u8 {
+    let descriptor = match read_report_descriptor(handle, desc) {
+        Ok(descriptor) => descriptor,
+        Err(e) => {
+            warn!(target: log::USB, "Failed to read the report descriptor: {}", e);
+            return MAX_LEVEL;
+        }
+    };
+
+    match descriptor::feature_byte_max(&descriptor, desc.set_template.level_index()) {
+        Some(max) if max > 0 && max < u8::MAX as u32 => max as u8,
+        _ => MAX_LEVEL
+    }
+}
+
+
+// Asks the controller how far its level goes and to identify itself. It has
+// no way to report whether it has color and effects, so this is only a guess
+// from the firmware version: revisions from 0.2 on are taken to have both.
+// Per-key lighting can't be asked about either, so it isn't offered at all.
+// Controllers that don't answer are assumed to be the model this program was
+// originally written for
+pub fn read_capabilities(handle: &mut Device, desc: &DeviceDescription) -> Capabilities {
+    let max_level = read_max_level(handle, desc);
+
+    // 0x80 is "get firmware version"
+    let request = pad_report(&[0x80], desc.report_length);
+    let data = match get_feature_report(handle, desc, &request) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!(target: log::USB, "Failed to identify controller: {}", e);
+            return Capabilities { max_level, ..Capabilities::default() };
+        }
+    };
+
+    // The version follows the command byte as major.minor.patch.build. Only
+    // the major and minor numbers are used for the guess
+    let firmware = [data[1], data[2], data[3], data[4]];
+    let revision = (firmware[0], firmware[1]);
+    Capabilities {
+        firmware: Some(firmware),
+        max_level,
+        color: revision >= (0, 2),
+        effects: revision >= (0, 2)
+    }
+}
+
+
+// Checks whether the controller answers for an effect channel, for auxiliary
+// zones, using a query so that nothing changes
+pub fn has_channel(handle: &mut Device, desc: &DeviceDescription, channel: u8) -> bool {
+    match read_channel_level(handle, desc, channel) {
+        Ok(_) => true,
+        Err(e) => {
+            debug!(target: log::USB, "Controller did not answer for channel 0x{:02x}: {}", channel, e);
+            false
+        }
+    }
+}
+
+
+// Works out which protocol revision the controller speaks. If the product ID
+// doesn't tell us for certain, the controller is probed using only read-only
+// requests so that nothing changes on a firmware we don't understand
+pub fn probe_protocol(handle: &mut Device, desc: &DeviceDescription, product_id: u16, caps: &Capabilities) -> Option<Protocol> {
+    let known = KNOWN_CONTROLLERS.iter().find(|(id, _)| *id == product_id);
+    if let Some((_, Some(protocol))) = known {
+        return Some(*protocol);
+    }
+
+    info!(target: log::USB, "Product ID 0x{:04x} is ambiguous, probing protocol revision", product_id);
+
+    // The firmware version request has already been tried when reading the
+    // capabilities, so use its answer if there was one
+    let protocol = match caps.firmware {
+        Some(f) if (f[0], f[1]) >= (0, 3) => Some(Protocol::Rev3),
+        Some(_) => Some(Protocol::Rev2),
+
+        // Otherwise see whether it at least answers a brightness query
+        None => match read_brightness_level(handle, desc) {
+            Ok(_) => Some(Protocol::Rev2),
+            Err(e) => {
+                warn!(target: log::USB, "Controller did not answer brightness query: {}", e);
+                None
+            }
+        }
+    };
+
+    match protocol {
+        Some(p) => warn!(target: log::USB, "Product ID 0x{:04x} appears to speak protocol {:?}, please report this so it can be added to the list of known controllers", product_id, p),
+        None => warn!(target: log::USB, "Could not determine the protocol revision of product ID 0x{:04x}", product_id)
+    }
+
+    protocol
+}
+
+
+// Writes a report to the controller over USB without waiting for a reply
+fn usb_set_feature_report(handle: &mut rusb::DeviceHandle<rusb::Context>, desc: &DeviceDescription, data: &[u8]) -> Result<(), BlError> {
+    usb_with_retries(handle, &desc.retry, |handle| {
+        let handle = InterfaceGuard::claim(handle, desc.hold_interface)?;
+
+        // Set up the request type
+        let request_type = rusb::request_type(rusb::Direction::Out, rusb::RequestType::Class, rusb::Recipient::Interface);
+
+        // request 0x09 is HID set_report
+        // value 0x0300 is HID feature
+        // index 0x0001 is whatever
+        let result = handle.write_control(request_type, 0x09, 0x0300, 0x0001, data, Duration::from_secs(1));
+
+        result.map(|_| ())
+    })
+}
+
+
+// Writes a report to the controller without waiting for a reply
+fn set_feature_report(device: &mut Device, desc: &DeviceDescription, data: &[u8]) -> Result<(), BlError> {
+    trace!(target: log::USB, "Report: {:02x?}", data);
+    match device {
+        Device::Usb(handle) => usb_set_feature_report(handle, desc, data),
+        Device::Hidraw(file) => hidraw::set_feature_report(file, data)
+    }
+}
+
+
+// Writes the backlight level of a single effect channel
+pub fn write_channel_level(handle: &mut Device, desc: &DeviceDescription, channel: u8, level: u8) -> Result<(), BlError> {
+    let data = desc.set_template.fill(channel, level, desc.report_length);
+    set_feature_report(handle, desc, &data)
+}
+
+
+// Switches the keyboard to the controller's own breathing effect at the given
+// level. Setting the level again goes back to a steady backlight
+pub fn set_breathing_effect(handle: &mut Device, desc: &DeviceDescription, level: u8) -> Result<(), BlError> {
+    // 0x08 0x02 is "set effect", 0x02 is breathing and 0x09 is its slowest speed
+    let data = pad_report(&[0x08, 0x02, 0x02, 0x09, level], desc.report_length);
+    set_feature_report(handle, desc, &data)
+}
+
+
+// Sets the backlight level of a single effect channel
+fn set_channel_level(handle: &mut Device, desc: &DeviceDescription, channel: u8, level: u8) {
+    if let Err(e) = write_channel_level(handle, desc, channel, level) {
+        warn!(target: log::USB, "{}", e);
+    }
+}
+
+
+// Reads back the keyboard backlight level after a write and writes it again
+// if it doesn't match, as some firmware occasionally ignores a set_report
+fn verify_backlight_level(handle: &mut Device, desc: &DeviceDescription, level: u8) {
+    for attempt in 0..=desc.verify_retries {
+        let problem = match read_brightness_level(handle, desc) {
+            Ok(l) if l == level => return,
+            Ok(l) => format!("backlight level is {} rather than {}", l, level),
+            Err(e) => format!("failed to read back backlight level: {}", e)
+        };
+
+        if attempt == desc.verify_retries {
+            warn!(target: log::USB, "Device problem: {} after {} retries", problem, desc.verify_retries);
+        } else {
+            debug!(target: log::USB, "Retrying write, {}", problem);
+            set_channel_level(handle, desc, KEYBOARD_CHANNEL, level);
+        }
+    }
+}
+
+
+// Sets the keyboard backlight level, taking any auxiliary zones along with it.
+// Only a failure to write the keyboard's own level is returned
+pub fn set_backlight_level(handle: &mut Device, desc: &DeviceDescription, level: u8) -> Result<(), BlError> {
+    let result = write_channel_level(handle, desc, KEYBOARD_CHANNEL, level);
+    if desc.verify_retries > 0 {
+        verify_backlight_level(handle, desc, level);
+    }
+
+    for zone in &desc.zones {
+        // Zones with their own level never go brighter than the keyboard so
+        // that they still dim alongside it
+        let zone_level = match zone.level {
+            Some(l) => l.min(level),
+            None => level
+        };
+        set_channel_level(handle, desc, zone.channel, zone_level);
+    }
+
+    result
+}
+
+
+// Sets the keyboard backlight color, stopping at the first report that fails
+pub fn set_backlight_color(device: &mut Device, desc: &DeviceDescription, r: u8, g: u8, b: u8) -> Result<(), BlError> {
+    let handle = match device {
+        Device::Usb(handle) => handle,
+        Device::Hidraw(file) => {
+            let data = pad_report(&[0x12, 0x00, 0x00, 0x08], desc.report_length);
+            hidraw::set_feature_report(file, &data)?;
+
+            // As over USB, the color goes once for each of the eight zones
+            let color_data: [u8; 64] = [0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b];
+            for _ in 0..8 {
+                hidraw::write_report(file, &color_data)?;
+            }
+            return Ok(());
+        }
+    };
+
+    let handle = InterfaceGuard::claim(handle, desc.hold_interface).map_err(|e| BlError::usb("could not claim interface", e))?;
+
+    // Set up the request type
+    let request_type = rusb::request_type(rusb::Direction::Out, rusb::RequestType::Class, rusb::Recipient::Interface);
+
+    // request 0x09 is HID set_report
+    // value 0x0300 is HID feature
+    // index 0x0001 is whatever
+    let data = pad_report(&[0x12, 0x00, 0x00, 0x08], desc.report_length);
+    handle.write_control(request_type, 0x09, 0x0300, 0x0001, &data, Duration::from_secs(1))
+        .map_err(|e| BlError::usb("could not start setting the color", e))?;
+
+    // Send the color eight times for the eight zones (we send to endpoint 2, which is the output
+    // endpoint
+    for _ in 0..8 {
+        let color_data: [u8; 64] = [0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b];
+        handle.write_bulk(2, &color_data, Duration::from_secs(1))
+            .map_err(|e| BlError::usb("could not send the color", e))?;
+    }
+    Ok(())
+}
+
+
+// Asks a possible controller for its keyboard level, which changes nothing,
+// to check that it's one we can talk to
+fn answers_get_report(device: &mut Device, product_id: u16) -> bool {
+    match read_brightness_level(device, &DeviceDescription::default()) {
+        Ok(_) => true,
+        Err(e) => {
+            warn!(target: log::USB, "Product 0x{:04x} did not answer: {}", product_id, e);
+            false
+        }
+    }
+}
+
+
+// Looks on the USB bus for a controller from the vendor with one of the given
+// product IDs, returning the product ID of the first one that answers
+pub fn detect_usb_controller(vendor_id: u16, product_ids: &[u16]) -> Result<u16, BlError> {
+    let context = rusb::Context::new().map_err(|e| BlError::usb("could not initialise libusb", e))?;
+    let devices = context.devices().map_err(|e| BlError::usb("could not list USB devices", e))?;
+
+    for device in devices.iter() {
+        let product_id = match device.device_descriptor() {
+            Ok(d) if d.vendor_id() == vendor_id && product_ids.contains(&d.product_id()) => d.product_id(),
+            _ => continue
+        };
+
+        match device.open() {
+            Ok(handle) => if answers_get_report(&mut Device::Usb(handle), product_id) {
+                return Ok(product_id);
+            },
+            Err(e) => warn!(target: log::USB, "Could not open product 0x{:04x}: {}", product_id, e)
+        }
+    }
+
+    Err(BlError::DeviceNotFound(format!("no known controller from vendor 0x{:04x}", vendor_id)))
+}
+
+
+// Does the same as detect_usb_controller, but through the hidraw nodes so that
+// the kernel driver is left alone
+pub fn detect_hidraw_controller(vendor_id: u16, product_ids: &[u16]) -> Result<u16, BlError> {
+    for (path, product_id) in hidraw::find_all(vendor_id, 1)? {
+        if !product_ids.contains(&product_id) {
+            continue;
+        }
+
+        match hidraw::open(&path) {
+            Ok(file) => if answers_get_report(&mut Device::Hidraw(file), product_id) {
+                return Ok(product_id);
+            },
+            Err(e) => warn!(target: log::USB, "{}", e)
+        }
+    }
+
+    Err(BlError::DeviceNotFound(format!("no known controller from vendor 0x{:04x}", vendor_id)))
+}
+
+
+// Something that can read and set a keyboard backlight. Only the level has
+// to be supported, the rest is for backlights that can do more
+pub trait BacklightBackend: Send {
+    // Gets the current backlight level
+    fn get(&mut self) -> Result<u8, BlError>;
+
+    // Sets the backlight level
+    fn set(&mut self, level: u8) -> Result<(), BlError>;
+
+    // Describes what the backlight can do
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { color: false, ..Capabilities::default() }
+    }
+
+    // Changes the backlight color
+    fn set_color(&mut self, _r: u8, _g: u8, _b: u8) -> Result<(), BlError> {
+        Err(BlError::Protocol(String::from("backlight has no color")))
+    }
+
+    // Breathes slowly up to the given level and back
+    fn breathe(&mut self, _level: u8) -> Result<(), BlError> {
+        Err(BlError::Protocol(String::from("backlight has no breathing effect")))
+    }
+
+    // Gives the device back before the system sleeps
+    fn suspend(&mut self) {}
+
+    // Opens the device again once the system has woken, as what was open
+    // before may not work any more
+    fn resume(&mut self) -> Result<(), BlError> {
+        Ok(())
+    }
+}
+
+
+// Counts the reads and writes that fail in a row, giving whether there have
+// now been enough to reset the device. A reset_after of zero never resets
+fn count_failure(failures: &mut u8, reset_after: u8, ok: bool) -> bool {
+    if ok || reset_after == 0 {
+        *failures = 0;
+        return false;
+    }
+
+    *failures = failures.saturating_add(1);
+    if *failures < reset_after {
+        return false;
+    }
+    *failures = 0;
+    true
+}
+
+
+// A keyboard backlight driven by an ITE controller
+pub struct KeyboardBacklight {
+    device: Device,
+    desc: DeviceDescription,
+    caps: Capabilities,
+    // Where to open the controller again from if it goes away
+    source: Option<DeviceSource>,
+    last_reopen: Option<Instant>,
+    // How many reads and writes have failed in a row
+    failures: u8,
+    // The level last asked for, to put back after a reset
+    last_level: Option<u8>,
+    // Whether the kernel driver was detached to hold the interface, so that
+    // it can be given back when we're done
+    driver_detached: bool,
+    // Whether the interface has been given back for the system to sleep
+    suspended: bool
+}
+
+impl KeyboardBacklight {
+    // Wraps a controller that's already been opened and described, claiming
+    // its interface straight away if it's to be held
+    pub fn new(device: Device, desc: DeviceDescription, caps: Capabilities) -> KeyboardBacklight {
+        let mut backlight = KeyboardBacklight {
+            device, desc, caps, source: None, last_reopen: None, failures: 0, last_level: None, driver_detached: false,
+            suspended: false
+        };
+        backlight.hold();
+        backlight
+    }
+
+    // Claims the interface if it's to be held, going back to claiming it for
+    // each transfer if that can't be done
+    fn hold(&mut self) {
+        if !self.desc.hold_interface {
+            return;
+        }
+        match hold_interface(&mut self.device) {
+            Ok(detached) => self.driver_detached = detached,
+            Err(e) => {
+                warn!(target: log::USB, "Failed to hold the controller's interface, claiming it for each transfer instead: {}", e);
+                self.desc.hold_interface = false;
+            }
+        }
+    }
+
+    // Lets the controller be opened again from where it was found when it
+    // stops answering
+    pub fn reconnect_from(mut self, source: DeviceSource) -> KeyboardBacklight {
+        self.source = Some(source);
+        self
+    }
+
+    // Opens the controller again after a failure, returning whether it was
+    // reopened
+    fn reopen(&mut self) -> bool {
+        let source = match &self.source {
+            Some(source) => source,
+            None => return false
+        };
+        if self.last_reopen.is_some_and(|t| t.elapsed() < REOPEN_INTERVAL) {
+            return false;
+        }
+        self.last_reopen = Some(Instant::now());
+
+        match source.open() {
+            Ok(device) => {
+                info!(target: log::USB, "Reopened the controller");
+                self.device = device;
+                self.hold();
+                true
+            },
+            Err(e) => {
+                warn!(target: log::USB, "Failed to reopen the controller: {}", e);
+                false
+            }
+        }
+    }
+
+    // Keeps count of failed reads and writes, resetting the USB device when
+    // too many fail in a row as some controllers wedge until they're reset.
+    // The last level asked for is put back afterwards
+    fn track<T>(&mut self, result: Result<T, BlError>) -> Result<T, BlError> {
+        if result.is_ok() {
+            count_failure(&mut self.failures, self.desc.reset_after, true);
+            return result;
+        }
+
+        if !count_failure(&mut self.failures, self.desc.reset_after, false) {
+            return result;
+        }
+
+        let handle = match &mut self.device {
+            Device::Usb(handle) => handle,
+            Device::Hidraw(_) => return result
+        };
+        info!(target: log::USB, "Resetting the controller after {} failures in a row", self.desc.reset_after);
+        match handle.reset() {
+            Ok(_) => (),
+            // The device re-enumerated, so has to be found again
+            Err(rusb::Error::NotFound) => {
+                self.last_reopen = None;
+                if !self.reopen() {
+                    return result;
+                }
+            },
+            Err(e) => {
+                warn!(target: log::USB, "Failed to reset the controller: {}", e);
+                return result;
+            }
+        }
+
+        if let Some(level) = self.last_level {
+            match set_backlight_level(&mut self.device, &self.desc, level) {
+                Ok(_) => info!(target: log::USB, brightness = level, "Restored backlight level {} after reset", level),
+                Err(e) => warn!(target: log::USB, "Failed to restore backlight level after reset: {}", e)
+            }
+        }
+        result
+    }
+
+    // Opens the controller with the given USB IDs, assuming it speaks the
+    // default report format
+    pub fn open(vendor_id: u16, product_id: u16) -> Result<KeyboardBacklight, BlError> {
+        let context = rusb::Context::new().map_err(|e| BlError::usb("could not initialise libusb", e))?;
+
+        let mut device = match context.open_device_with_vid_pid(vendor_id, product_id) {
+            Some(handle) => Device::Usb(handle),
+            None => return Err(BlError::DeviceNotFound(format!("couldn't find USB device 0x{:04x}:0x{:04x}", vendor_id, product_id)))
+        };
+        let desc = DeviceDescription::default();
+        let caps = read_capabilities(&mut device, &desc);
+        Ok(KeyboardBacklight::new(device, desc, caps).reconnect_from(DeviceSource::Usb { vendor_id, product_id }))
+    }
+}
+
+impl Drop for KeyboardBacklight {
+    // Gives the interface back to the kernel driver if it was held
+    fn drop(&mut self) {
+        if self.desc.hold_interface && !self.suspended {
+            release_held_interface(&mut self.device, self.driver_detached);
+        }
+    }
+}
+
+impl BacklightBackend for KeyboardBacklight {
+    fn get(&mut self) -> Result<u8, BlError> {
+        let result = match read_brightness_level(&mut self.device, &self.desc) {
+            Err(_) if self.reopen() => read_brightness_level(&mut self.device, &self.desc),
+            result => result
+        };
+        self.track(result)
+    }
+
+    fn set(&mut self, level: u8) -> Result<(), BlError> {
+        let level = level.min(self.caps.max_level);
+        self.last_level = Some(level);
+        let result = match set_backlight_level(&mut self.device, &self.desc, level) {
+            Err(_) if self.reopen() => set_backlight_level(&mut self.device, &self.desc, level),
+            result => result
+        };
+        self.track(result)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.caps
+    }
+
+    fn set_color(&mut self, r: u8, g: u8, b: u8) -> Result<(), BlError> {
+        set_backlight_color(&mut self.device, &self.desc, r, g, b)
+    }
+
+    fn breathe(&mut self, level: u8) -> Result<(), BlError> {
+        set_breathing_effect(&mut self.device, &self.desc, level)
+    }
+
+    // The kernel driver gets the interface back, so that it can put the
+    // keyboard to sleep and wake it up as it expects
+    fn suspend(&mut self) {
+        if self.suspended {
+            return;
+        }
+        if self.desc.hold_interface {
+            release_held_interface(&mut self.device, self.driver_detached);
+            self.driver_detached = false;
+        }
+        self.suspended = true;
+    }
+
+    // The controller may have been reset or come back as another device while
+    // asleep, so it's always opened again rather than trusting the old handle
+    fn resume(&mut self) -> Result<(), BlError> {
+        self.suspend();
+        let reopened = match &self.source {
+            Some(source) => source.open().map(|device| self.device = device),
+            None => Ok(())
+        };
+        self.suspended = false;
+        self.failures = 0;
+        self.hold();
+        reopened
+    }
+}
+
+
+// A pretend backlight that only remembers its level, for trying things out
+// without the hardware. It can also be made to fail every read and write,
+// counting the resets a real controller would be given
+pub struct MockBacklight {
+    level: u8,
+    failing: bool,
+    failures: u8,
+    reset_after: u8,
+    resets: usize
+}
+
+impl MockBacklight {
+    pub fn new(level: u8) -> MockBacklight {
+        MockBacklight { level, failing: false, failures: 0, reset_after: 0, resets: 0 }
+    }
+
+    // A mock whose reads and writes all fail, as a wedged controller's do,
+    // resetting after this many in a row as --reset-after does
+    pub fn failing(reset_after: u8) -> MockBacklight {
+        MockBacklight { failing: true, reset_after, ..MockBacklight::new(0) }
+    }
+
+    // How many times it's been reset for failing
+    pub fn resets(&self) -> usize {
+        self.resets
+    }
+
+    // Fails if it's meant to, keeping count as a real controller does
+    fn transfer(&mut self) -> Result<(), BlError> {
+        if count_failure(&mut self.failures, self.reset_after, !self.failing) {
+            self.resets += 1;
+        }
+        match self.failing {
+            true => Err(BlError::Protocol(String::from("the mock backlight is failing"))),
+            false => Ok(())
+        }
+    }
+}
+
+impl BacklightBackend for MockBacklight {
+    fn get(&mut self) -> Result<u8, BlError> {
+        self.transfer()?;
+        Ok(self.level)
+    }
+
+    fn set(&mut self, level: u8) -> Result<(), BlError> {
+        self.transfer()?;
+        self.level = level.min(MAX_LEVEL);
+        debug!("Mock backlight level is now {}", self.level);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_sets_and_gets_the_level() {
+        let mut backlight: Box<dyn BacklightBackend> = Box::new(MockBacklight::new(MAX_LEVEL));
+        assert_eq!(backlight.get().unwrap(), MAX_LEVEL);
+        backlight.set(20).unwrap();
+        assert_eq!(backlight.get().unwrap(), 20);
+    }
+
+    #[test]
+    fn mock_keeps_to_the_max_level() {
+        let mut backlight = MockBacklight::new(0);
+        backlight.set(MAX_LEVEL + 10).unwrap();
+        assert_eq!(backlight.get().unwrap(), MAX_LEVEL);
+        assert_eq!(backlight.capabilities().max_level, MAX_LEVEL);
+        assert!(!backlight.capabilities().color);
+    }
+
+    #[test]
+    fn never_resets_with_reset_after_zero() {
+        let mut backlight = MockBacklight::failing(0);
+        for _ in 0..300 {
+            assert!(backlight.set(10).is_err());
+        }
+        assert_eq!(backlight.resets(), 0);
+    }
+
+    #[test]
+    fn resets_after_failures_in_a_row() {
+        let mut backlight = MockBacklight::failing(3);
+        for _ in 0..300 {
+            assert!(backlight.get().is_err());
+        }
+        assert_eq!(backlight.resets(), 100);
+    }
+
+    #[test]
+    fn counts_past_the_most_failures_in_a_byte() {
+        let mut backlight = MockBacklight::failing(u8::MAX);
+        for _ in 0..600 {
+            assert!(backlight.set(10).is_err());
+        }
+        assert_eq!(backlight.resets(), 2);
+    }
+
+    #[test]
+    fn parses_zones() {
+        let zone = parse_zone("lightbar=0x03").unwrap();
+        assert_eq!((zone.name.as_str(), zone.channel, zone.level), ("lightbar", 3, None));
+
+        let zone = parse_zone("logo=4:20").unwrap();
+        assert_eq!((zone.name.as_str(), zone.channel, zone.level), ("logo", 4, Some(20)));
+    }
+
+    #[test]
+    fn rejects_bad_zones() {
+        assert!(parse_zone("lightbar").is_err());
+        assert!(parse_zone("lightbar=x").is_err());
+        assert!(parse_zone("lightbar=3:300").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::{info, warn};
+use bl_control::log;
+use bl_control::error::BlError;
+
+use crate::Event;
+use crate::devices::has_bit;
+use crate::input;
+
+// Constants from /usr/include/linux/input-event-codes.h
+const EV_SW: u16 = 0x05;
+const SW_LID: u16 = 0x00;
+
+// EVIOCGSW(len) from /usr/include/linux/input.h, which reads the state of
+// all of a device's switches. SW_MAX is 0x10, so they fit in a few bytes
+const SW_BYTES: usize = 4;
+const EVIOCGSW: libc::c_ulong = (2 << 30) | ((SW_BYTES as libc::c_ulong) << 16) | ((b'E' as libc::c_ulong) << 8) | 0x1b;
+
+
+// Finds the input devices that report the lid switch, which is usually just
+// the "Lid Switch" ACPI device
+pub fn find_lid_switches() -> Result<Vec<String>, BlError> {
+    let entries = fs::read_dir("/sys/class/input").map_err(|e| BlError::io("could not list input devices", e))?;
+    let mut switches = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("event") {
+            continue;
+        }
+
+        let sw = fs::read_to_string(entry.path().join("device/capabilities/sw")).unwrap_or_default();
+        if has_bit(&sw, SW_LID) {
+            switches.push(format!("/dev/input/{}", name));
+        }
+    }
+
+    Ok(switches)
+}
+
+
+// Asks the device whether the lid is closed right now, as only changes are
+// reported as events
+fn is_closed(file: &File) -> io::Result<bool> {
+    let mut state = [0u8; SW_BYTES];
+    if unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGSW, state.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(state[SW_LID as usize / 8] & (1 << (SW_LID % 8)) != 0)
+}
+
+
+// Creates a thread that reads the lid switch and posts to the main loop
+// whenever the lid is closed or opened, starting with whether it's closed now
+pub fn spawn_reader(event_path: String, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let file = File::open(&event_path)?;
+    let closed = is_closed(&file)?;
+    let mut reader = input::EventReader::new(file);
+    let thread_builder = thread::Builder::new().name("lid-reader".to_string());
+    thread_builder.spawn(move || {
+        info!(target: log::INPUT, device = %event_path, "Reading the lid switch from {}", event_path);
+        if closed {
+            let _ = s.send(Event::Lid(true));
+        }
+
+        loop {
+            let event = match reader.next_event() {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!(target: log::INPUT, device = %event_path, "Stopped reading {}: {}", event_path, e);
+                    return;
+                }
+            };
+            if event.in_type != EV_SW || event.code != SW_LID {
+                continue;
+            }
+
+            if let Err(e) = s.send(Event::Lid(event.value != 0)) {
+                warn!(target: log::INPUT, "{}", e);
+            }
+        }
+    })
+}
@@ -0,0 +1,171 @@
+use std::fs;
+use rusb::UsbContext;
+
+use bl_control::error::BlError;
+
+use crate::devices::has_bit;
+
+// The USB interface class of HID devices
+const HID_CLASS: u8 = 0x03;
+
+// Names of the event types from /usr/include/linux/input-event-codes.h
+const EVENT_TYPES: [(u16, &str); 10] = [
+    (0x00, "SYN"), (0x01, "KEY"), (0x02, "REL"), (0x03, "ABS"), (0x04, "MSC"),
+    (0x05, "SW"), (0x11, "LED"), (0x12, "SND"), (0x14, "REP"), (0x15, "FF")
+];
+
+
+// What we know about a USB device
+struct UsbDevice {
+    bus: u8,
+    address: u8,
+    vendor_id: u16,
+    product_id: u16,
+    name: Option<String>,
+    // Each interface number and whether it's a HID interface
+    interfaces: Vec<(u8, bool)>,
+    // Whether it's one of the controllers we know how to drive
+    known: bool
+}
+
+
+// Quotes a string for JSON output
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+
+// Reads what we need from every device on the bus. Names need the device to be
+// opened, so they're missing for anything we don't have permission for
+fn usb_devices(vendor_id: u16, product_ids: &[u16]) -> Result<Vec<UsbDevice>, BlError> {
+    let context = rusb::Context::new().map_err(|e| BlError::usb("could not initialise libusb", e))?;
+    let devices = context.devices().map_err(|e| BlError::usb("could not list USB devices", e))?;
+
+    let mut found = Vec::new();
+    for device in devices.iter() {
+        let descriptor = match device.device_descriptor() {
+            Ok(d) => d,
+            Err(_) => continue
+        };
+
+        let interfaces = match device.active_config_descriptor() {
+            Ok(config) => config.interfaces()
+                .filter_map(|i| i.descriptors().next())
+                .map(|d| (d.interface_number(), d.class_code() == HID_CLASS))
+                .collect(),
+            Err(_) => Vec::new()
+        };
+
+        found.push(UsbDevice {
+            bus: device.bus_number(),
+            address: device.address(),
+            vendor_id: descriptor.vendor_id(),
+            product_id: descriptor.product_id(),
+            name: device.open().and_then(|h| h.read_product_string_ascii(&descriptor)).ok(),
+            interfaces,
+            known: descriptor.vendor_id() == vendor_id && product_ids.contains(&descriptor.product_id())
+        });
+    }
+
+    Ok(found)
+}
+
+
+// Prints every USB device, marking the HID devices and the controllers we
+// know about, as a table or as JSON
+pub fn print_usb_devices(vendor_id: u16, product_ids: &[u16], json: bool) -> Result<(), BlError> {
+    let devices = usb_devices(vendor_id, product_ids)?;
+
+    if json {
+        let entries: Vec<String> = devices.iter().map(|d| {
+            let interfaces: Vec<String> = d.interfaces.iter()
+                .map(|(number, hid)| format!("{{\"number\": {}, \"hid\": {}}}", number, hid))
+                .collect();
+            format!("{{\"bus\": {}, \"address\": {}, \"vendor_id\": \"0x{:04x}\", \"product_id\": \"0x{:04x}\", \"name\": {}, \"interfaces\": [{}], \"known_controller\": {}}}",
+                d.bus, d.address, d.vendor_id, d.product_id,
+                d.name.as_deref().map(json_string).unwrap_or_else(|| String::from("null")),
+                interfaces.join(", "), d.known)
+        }).collect();
+        println!("[{}]", entries.join(",\n "));
+        return Ok(());
+    }
+
+    println!("{:<4} {:<4} {:<10} {:<14} {:<11} NAME", "BUS", "ADDR", "ID", "INTERFACES", "TYPE");
+    for d in &devices {
+        let interfaces: Vec<String> = d.interfaces.iter()
+            .map(|(number, hid)| format!("{}{}", number, if *hid { "*" } else { "" }))
+            .collect();
+        let kind = match (d.known, d.interfaces.iter().any(|(_, hid)| *hid)) {
+            (true, _) => "controller",
+            (false, true) => "hid",
+            (false, false) => ""
+        };
+        println!("{:<4} {:<4} {:04x}:{:04x}  {:<14} {:<11} {}", d.bus, d.address, d.vendor_id, d.product_id,
+            interfaces.join(","), kind, d.name.as_deref().unwrap_or(""));
+    }
+    println!("Interfaces marked with * are HID interfaces");
+
+    Ok(())
+}
+
+
+// Prints every input event device with its name and the types of event it
+// reports, along with what we'd use it for: the keyboards we'd read, and the
+// other kinds of device that would be read if those sources were on
+pub fn print_input_devices(keyboards: &[String], others: &[(&str, Vec<String>)], json: bool) -> Result<(), BlError> {
+    let entries = fs::read_dir("/sys/class/input").map_err(|e| BlError::io("could not list input devices", e))?;
+    let mut numbers: Vec<u32> = entries.flatten()
+        .filter_map(|e| e.file_name().to_str().and_then(|n| n.strip_prefix("event")).and_then(|n| n.parse().ok()))
+        .collect();
+    numbers.sort();
+
+    let mut rows = Vec::new();
+    for number in numbers {
+        let sys_path = format!("/sys/class/input/event{}/device", number);
+        let path = format!("/dev/input/event{}", number);
+        let name = fs::read_to_string(format!("{}/name", sys_path)).unwrap_or_default().trim().to_string();
+        let ev = fs::read_to_string(format!("{}/capabilities/ev", sys_path)).unwrap_or_default();
+        let events: Vec<&str> = EVENT_TYPES.iter().filter(|(bit, _)| has_bit(&ev, *bit)).map(|(_, n)| *n).collect();
+
+        let used_as = match keyboards.contains(&path) {
+            true => "keyboard",
+            false => others.iter().find(|(_, found)| found.contains(&path)).map(|(kind, _)| *kind).unwrap_or("")
+        };
+        rows.push((path, name, events, used_as));
+    }
+
+    if json {
+        let entries: Vec<String> = rows.iter().map(|(path, name, events, used_as)| {
+            let events: Vec<String> = events.iter().map(|e| json_string(e)).collect();
+            let used_as = match used_as.is_empty() {
+                true => String::from("null"),
+                false => json_string(used_as)
+            };
+            format!("{{\"device\": {}, \"name\": {}, \"events\": [{}], \"used_as\": {}}}",
+                json_string(path), json_string(name), events.join(", "), used_as)
+        }).collect();
+        println!("[{}]", entries.join(",\n "));
+        return Ok(());
+    }
+
+    let name_width = rows.iter().map(|(_, name, _, _)| name.len()).max().unwrap_or(0).max(4);
+    println!("{:<20} {:<name_width$} {:<24} USED AS", "DEVICE", "NAME", "EVENTS");
+    for (path, name, events, used_as) in &rows {
+        println!("{:<20} {:<name_width$} {:<24} {}", path, name, events.join(","), used_as);
+    }
+    if keyboards.is_empty() {
+        println!("No device reports all of the letter keys, so no keyboard would be found");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::fd::AsFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use chrono::{DateTime, Utc};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+
+
+// Targets for the parts of the daemon that are worth turning up on their own,
+// e.g. --log-filter usb=trace
+pub const USB: &str = "usb";
+pub const INPUT: &str = "input";
+pub const STATEMACHINE: &str = "statemachine";
+
+
+// A log file that is rotated when it gets too big or too old. Rotated files
+// are renamed to file.1, file.2 and so on, with the oldest removed
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    // Rotate once the file reaches this many bytes (zero for never)
+    max_size: u64,
+    // Rotate once the file has been written to for this long
+    max_age: Option<Duration>,
+    // How many rotated files to keep
+    keep: u32,
+    size: u64,
+    opened: SystemTime
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: u64, max_age: Option<Duration>, keep: u32) -> std::io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile { path, file, max_size, max_age, keep, size, opened: SystemTime::now() })
+    }
+
+    // Moves the current file out of the way and starts a new one
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |n: u32| PathBuf::from(format!("{}.{}", self.path.display(), n));
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // Shuffle the older files along, dropping the oldest
+            let _ = fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened = SystemTime::now();
+        Ok(())
+    }
+
+    fn needs_rotating(&self) -> bool {
+        let too_big = self.max_size > 0 && self.size >= self.max_size;
+        let too_old = match (self.max_age, self.opened.elapsed()) {
+            (Some(max_age), Ok(age)) => age >= max_age,
+            _ => false
+        };
+        too_big || too_old
+    }
+
+    fn write_line(&mut self, line: &str, timestamped: bool) -> std::io::Result<()> {
+        if self.needs_rotating() {
+            self.rotate()?;
+        }
+
+        let line = match timestamped {
+            true => format!("{}\n", line),
+            false => format!("{} {}\n", timestamp(SystemTime::now()), line)
+        };
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+
+// Where log lines go. Until a log file is set up they go to stdout
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+
+// Sends all further log lines to a file instead of stdout
+pub fn init_file(path: PathBuf, max_size: u64, max_age: Option<Duration>, keep: u32) -> std::io::Result<()> {
+    let log_file = LogFile::open(path, max_size, max_age, keep)?;
+    *LOG_FILE.lock().unwrap() = Some(log_file);
+    Ok(())
+}
+
+
+// Writes a line to the log file if there is one, otherwise to stdout
+pub fn write_line(line: &str) {
+    write(line, false);
+}
+
+
+// Writes a line that may already carry its own timestamp, in which case the
+// log file doesn't add one
+fn write(line: &str, timestamped: bool) {
+    let mut log_file = LOG_FILE.lock().unwrap();
+    match log_file.as_mut() {
+        Some(f) => {
+            // There's nowhere else to report a failure to log to, so fall back
+            // to stdout rather than losing the line
+            if let Err(e) = f.write_line(line, timestamped) {
+                println!("Failed to write to log file: {}", e);
+                println!("{}", line);
+            }
+        },
+        None => println!("{}", line)
+    }
+}
+
+
+// Collects one formatted event and writes it out as a line when it's done, so
+// that tracing output goes through the log file and its rotation
+pub struct LineWriter {
+    buf: Vec<u8>,
+    timestamped: bool
+}
+
+impl Write for LineWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LineWriter {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.buf);
+        let line = line.trim_end();
+        if !line.is_empty() {
+            write(line, self.timestamped);
+        }
+    }
+}
+
+// Makes a LineWriter for each event. JSON events have their own timestamp
+struct MakeLineWriter {
+    timestamped: bool
+}
+
+impl<'a> MakeWriter<'a> for MakeLineWriter {
+    type Writer = LineWriter;
+
+    fn make_writer(&'a self) -> LineWriter {
+        LineWriter { buf: Vec::new(), timestamped: self.timestamped }
+    }
+}
+
+
+// Whether stdout is connected to the journal, which systemd tells us by
+// setting JOURNAL_STREAM to the device and inode of the stream it gave us
+fn stdout_is_journal() -> bool {
+    let stream = match std::env::var("JOURNAL_STREAM") {
+        Ok(stream) => stream,
+        Err(_) => return false
+    };
+    let stdout = match std::io::stdout().as_fd().try_clone_to_owned() {
+        Ok(fd) => File::from(fd),
+        Err(_) => return false
+    };
+    match stdout.metadata() {
+        Ok(metadata) => stream == format!("{}:{}", metadata.dev(), metadata.ino()),
+        Err(_) => false
+    }
+}
+
+
+// Starts sending tracing events that pass the filter to the log, either as
+// plain lines or, for log pipelines, one JSON object per line. When we're
+// running under systemd and not logging to a file, plain events go straight
+// to journald with their priority and fields (e.g. BRIGHTNESS, DEVICE) so
+// that they can be filtered with journalctl
+pub fn init(filter: Targets, json: bool) {
+    let journald = if !json && LOG_FILE.lock().unwrap().is_none() && stdout_is_journal() {
+        tracing_journald::layer().ok().map(|layer| layer.with_field_prefix(None))
+    } else {
+        None
+    };
+
+    let lines = match (&journald, json) {
+        (None, false) => Some(tracing_subscriber::fmt::layer()
+            .without_time()
+            .with_ansi(false)
+            .with_writer(MakeLineWriter { timestamped: false })),
+        _ => None
+    };
+
+    let json_lines = match json {
+        true => Some(tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(false)
+            .with_writer(MakeLineWriter { timestamped: true })),
+        false => None
+    };
+
+    tracing_subscriber::registry()
+        .with(journald.with_filter(filter.clone()))
+        .with(lines.with_filter(filter.clone()))
+        .with(json_lines.with_filter(filter))
+        .init();
+}
+
+
+// Formats a time as an ISO 8601 UTC timestamp, e.g. 2024-03-01T12:34:56Z
+pub fn timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn formats_the_epoch() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(1709296496)), "2024-03-01T12:34:56Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(1709164800)), "2024-02-29T00:00:00Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951868799)), "2000-02-29T23:59:59Z");
+    }
+
+    #[test]
+    fn formats_times_before_the_epoch() {
+        assert_eq!(timestamp(UNIX_EPOCH - Duration::from_secs(60)), "1969-12-31T23:59:00Z");
+    }
+}
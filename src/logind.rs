@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::env;
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use zbus::MatchRule;
+use zbus::blocking::{Connection, MessageIterator, Proxy};
+use zbus::message::Type;
+use zbus::zvariant::{OwnedFd, OwnedObjectPath, OwnedValue};
+
+use tracing::{debug, info, warn};
+use crate::Event;
+
+// Where logind lives on the system bus
+const LOGIND_NAME: &str = "org.freedesktop.login1";
+const SESSIONS_PATH: &str = "/org/freedesktop/login1/session";
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+const MANAGER_PATH: &str = "/org/freedesktop/login1";
+const MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+
+
+// Finds our own session when we're run from one, so that only its locks
+// count. A system service has no session, and watches them all
+fn own_session(conn: &Connection) -> Result<Option<OwnedObjectPath>, zbus::Error> {
+    let id = match env::var("XDG_SESSION_ID") {
+        Ok(id) => id,
+        Err(_) => return Ok(None)
+    };
+    let manager = Proxy::new(conn, LOGIND_NAME, MANAGER_PATH, MANAGER_INTERFACE)?;
+    Ok(Some(manager.call("GetSession", &(id,))?))
+}
+
+
+// Sends whether the session is locked to the main loop, if it's changed
+fn send_locked(locked: bool, was_locked: &mut Option<bool>, s: &UnboundedSender<Event>) {
+    if Some(locked) != *was_locked {
+        debug!("Session {}", if locked { "locked" } else { "unlocked" });
+        if let Err(e) = s.send(Event::Locked(locked)) {
+            warn!("{}", e);
+        }
+        *was_locked = Some(locked);
+    }
+}
+
+
+// Follows the session being locked and unlocked, whether it's logind asking
+// the screen locker to (e.g. `loginctl lock-session`) or the locker saying
+// it has through LockedHint
+fn watch_locks(s: &UnboundedSender<Event>) -> Result<(), zbus::Error> {
+    let conn = Connection::system()?;
+    let session = own_session(&conn)?;
+
+    let rule = MatchRule::builder().msg_type(Type::Signal).sender(LOGIND_NAME)?;
+    let rule = match &session {
+        Some(path) => rule.path(path.clone())?,
+        None => rule.path_namespace(SESSIONS_PATH)?
+    };
+    let messages = MessageIterator::for_match_rule(rule.build(), &conn, None)?;
+    match &session {
+        Some(path) => info!("Watching logind for locks of session {}", path.as_str()),
+        None => info!("Watching logind for locks of any session")
+    }
+
+    let mut was_locked = None;
+    for message in messages {
+        let message = message?;
+        let header = message.header();
+        match (header.interface().map(|i| i.as_str()), header.member().map(|m| m.as_str())) {
+            (Some(SESSION_INTERFACE), Some("Lock")) => send_locked(true, &mut was_locked, s),
+            (Some(SESSION_INTERFACE), Some("Unlock")) => send_locked(false, &mut was_locked, s),
+            (Some("org.freedesktop.DBus.Properties"), Some("PropertiesChanged")) => {
+                let (interface, changed, _): (String, HashMap<String, OwnedValue>, Vec<String>) = message.body().deserialize()?;
+                if interface != SESSION_INTERFACE {
+                    continue;
+                }
+                if let Some(Ok(locked)) = changed.get("LockedHint").map(bool::try_from) {
+                    send_locked(locked, &mut was_locked, s);
+                }
+            },
+            _ => ()
+        }
+    }
+
+    Ok(())
+}
+
+
+// Creates a thread that posts to the main loop whenever logind says the
+// session was locked or unlocked
+pub fn spawn_lock_watcher(s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("logind".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = watch_locks(&s) {
+            warn!("Stopped watching logind for locks: {}", e);
+        }
+    })
+}
+
+
+// Asks logind to hold off sleeping until we've given the keyboard back, which
+// lasts until the returned file descriptor is closed
+fn delay_sleep(manager: &Proxy) -> Result<OwnedFd, zbus::Error> {
+    manager.call("Inhibit", &("sleep", "bl-control", "Giving the keyboard back to its driver", "delay"))
+}
+
+
+// Has the main loop give the device back before each sleep, holding the sleep
+// off until it has, and open it again on waking
+fn watch_sleep(s: &UnboundedSender<Event>) -> Result<(), zbus::Error> {
+    let conn = Connection::system()?;
+    let manager = Proxy::new(&conn, LOGIND_NAME, MANAGER_PATH, MANAGER_INTERFACE)?;
+
+    // Subscribe before taking the inhibitor so that a sleep in between isn't
+    // missed
+    let signals = manager.receive_signal("PrepareForSleep")?;
+    let mut inhibitor = Some(delay_sleep(&manager)?);
+    info!("Watching logind for the system sleeping");
+
+    for message in signals {
+        let sleeping: bool = message.body().deserialize()?;
+        if sleeping {
+            debug!("System is about to sleep");
+            let (done_s, done_r) = oneshot::channel();
+            if s.send(Event::Sleep(done_s)).is_err() {
+                break;
+            }
+            let _ = done_r.blocking_recv();
+
+            // Let the sleep go ahead
+            inhibitor = None;
+        } else {
+            debug!("System has woken up");
+            if s.send(Event::Wake).is_err() {
+                break;
+            }
+            if inhibitor.is_none() {
+                match delay_sleep(&manager) {
+                    Ok(fd) => inhibitor = Some(fd),
+                    Err(e) => warn!("Failed to hold off the next sleep: {}", e)
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+// Creates a thread that posts to the main loop when the system is about to
+// sleep and when it wakes up again
+pub fn spawn_sleep_watcher(s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("logind-sleep".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = watch_sleep(&s) {
+            warn!("Stopped watching logind for sleep: {}", e);
+        }
+    })
+}
@@ -1,37 +1,478 @@
-extern crate libusb;
+extern crate rusb;
+extern crate bl_control;
 
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::fs::File;
-use std::io::Read;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::net::{IpAddr, SocketAddr};
 use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::Sender as StdSender;
 use tokio::time::sleep;
-use tokio::sync::mpsc;
-use clap::Parser;
+use tokio::sync::{mpsc, oneshot};
+use tokio::signal::unix::{signal, SignalKind};
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
 use clap_num::maybe_hex;
+use rusb::UsbContext;
+use serde_json::json;
+
+mod acpid;
+mod als;
+mod bell;
+mod bench;
+mod calibrate;
+mod charge;
+mod config;
+mod control;
+mod brightness;
+mod cycle;
+mod dbus;
+mod devices;
+mod display;
+mod follow;
+mod hotplug;
+mod input;
+mod lid;
+mod instance;
+mod list;
+mod logind;
+mod monitor;
+mod mutter;
+mod night;
+mod notify;
+mod portal;
+mod power;
+mod presence;
+mod privileges;
+mod process;
+mod schedule;
+mod screen;
+mod screensaver;
+mod seccomp;
+mod service;
+mod snapshot;
+mod status;
+mod sunrise;
+mod thermal;
+mod udev;
+mod upower;
+mod wayland;
+mod x11;
+
+use tracing::{debug, info, warn, error};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use bl_control::log;
+use bl_control::error::BlError;
+use bl_control::template::{ReportTemplate, DEFAULT_REPORT_LENGTH};
+use bl_control::hidraw;
+use bl_control::sysfs::{self, SysfsBacklight};
+use bl_control::{
+    BacklightBackend, Capabilities, Device, DeviceDescription, DeviceSource, KeyboardBacklight, MockBacklight, Protocol, RetryPolicy, Zone,
+    KNOWN_CONTROLLERS, MAX_LEVEL, DEFAULT_SET_TEMPLATE, DEFAULT_GET_TEMPLATE, has_channel, parse_zone, probe_protocol, read_capabilities,
+    detect_hidraw_controller, detect_usb_controller
+};
 
 // Constants from /usr/include/linux/input-event-codes.h
 const EV_KEY: u16 = 0x01;
-const KEY_LEFT_META: u16 = 125;
-const KEY_RIGHT_META: u16 = 125;
-const KEY_L: u16 = 38;
+const KEY_LEFTMETA: u16 = 125;
+const KEY_RIGHTMETA: u16 = 126;
+
+// Where to hear about the display turning off and on
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum DisplayPowerSource {
+    /// The X server's DPMS state, polled once a second
+    Dpms,
+    /// wlr-output-power-management, on wlroots compositors such as sway
+    Wayland,
+    /// The kernel's DPMS state for each connected display, polled once a
+    /// second, which works whatever turned the display off
+    Drm
+}
+
+// Sources of user activity that can keep the backlight on
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum IdleSource {
+    /// Key presses read from the keyboard input device
+    Keyboard,
+    /// Lid, brightness key and power button events from acpid
+    Acpid,
+    /// Touchscreens and drawing tablets
+    Touch,
+    /// Gamepad and joystick buttons
+    Gamepad,
+    /// Mouse and trackpoint movement and clicks
+    Mouse,
+    /// Touchpad movement and taps
+    Touchpad,
+    /// Any input the Wayland compositor sees, through ext-idle-notify-v1
+    Wayland,
+    /// Any input the X server sees, polled through MIT-SCREEN-SAVER
+    X11,
+    /// Any input GNOME sees, through Mutter's idle monitor
+    Mutter
+}
+
+// Where activity came from, so that each source can be weighted differently
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+enum ActivitySource {
+    /// Key presses read from the keyboard input device
+    Keyboard,
+    /// Lid, brightness key and power button events from acpid
+    Acpid,
+    /// The user's phone coming back
+    Phone,
+    /// Other programs, through the control socket
+    Control,
+    /// Touchscreens and drawing tablets
+    Touch,
+    /// Gamepad and joystick buttons
+    Gamepad,
+    /// Mouse and trackpoint movement and clicks
+    Mouse,
+    /// Touchpad movement and taps
+    Touchpad,
+    /// Any input the Wayland compositor sees
+    Wayland,
+    /// Any input the X server sees
+    X11,
+    /// Any input GNOME sees
+    Mutter
+}
+
+// Where to find out whether we're on battery
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum PowerMonitor {
+    /// Check /sys/class/power_supply every 10 seconds
+    Sysfs,
+    /// Follow upower's signals over D-Bus as things change
+    Upower
+}
+
+// What to do with the backlight when idle
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum IdleEffect {
+    /// Turn the backlight off
+    Off,
+    /// Breathe slowly at a low level
+    Breathe
+}
+
+// How to talk to the controller
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Backend {
+    /// Claim the USB interface, briefly detaching the kernel driver
+    Usb,
+    /// Use the kernel's hidraw node, which can be done without root
+    Hidraw,
+    /// Use a keyboard backlight LED from /sys/class/leds, for laptops without
+    /// the ITE controller
+    Sysfs,
+    /// Pretend to have a backlight, to try things out without the hardware
+    Mock
+}
+
+// The D-Bus buses the service can be offered on
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Bus {
+    /// The system bus, for running as a system service
+    System,
+    /// The session bus of the user running the daemon
+    Session
+}
+
+// How locking the screen is noticed
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum LockSource {
+    /// The lock combination being pressed on the keyboard
+    Keys,
+    /// logind saying the session was locked, however that happened
+    Logind,
+    /// The desktop's screensaver or locker coming on
+    Screensaver
+}
+
+// How log messages are written
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum LogFormat {
+    /// One line of text per message
+    Text,
+    /// One JSON object per message, for log pipelines
+    Json
+}
+
+// Things that change the brightness, each of which can fade or not
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum FadeSource {
+    /// Dimming when idle, locked or asked to by another source
+    Idle,
+    /// Restoring the brightness on activity
+    Activity,
+    /// Levels set over D-Bus
+    Dbus,
+    /// Levels set through the control socket, e.g. by `set` and `cycle`
+    Control,
+    /// Steps from SIGUSR1 and SIGUSR2
+    Signal
+}
+
+// Events sent to the main loop by the activity sources
+enum Event {
+    // User activity that should keep (or turn) the backlight on
+    Activity(ActivitySource),
+    // The lock combination (Meta+L) was released
+    LockKey,
+    // logind or the screensaver says the session was locked (or unlocked)
+    Locked(bool),
+    // The system is about to sleep, so give the device back and answer
+    Sleep(oneshot::Sender<()>),
+    // The system has woken up, so open the device again
+    Wake,
+    // Something wants the backlight dimmed right away (e.g. the lid closed)
+    DimNow,
+    // The lid switch says the lid was closed (or opened)
+    Lid(bool),
+    // The display turned on (or off)
+    DisplayPower(bool),
+    // The instance we're following changed its state
+    Follow(follow::State),
+    // A presence check found someone at the machine (or stopped finding them)
+    Presence(bool),
+    // The bell rang, so flash the backlight
+    Bell,
+    // A step of the sunrise ramp, starting from zero
+    Sunrise(u8),
+    // The average color of the screen changed
+    ScreenColor(u8, u8, u8),
+    // A step of the charging indicator, or None to go back to the normal color
+    ChargeColor(Option<(u8, u8, u8)>),
+    // Asks how long the user has been idle and how long until we dim
+    IdleQuery(oneshot::Sender<(Duration, Option<Duration>)>),
+    // The room became brighter (or darker) than the ambient light threshold
+    AmbientBright(bool),
+    // The ambient light calls for a different level
+    AmbientLevel(u8),
+    // The display's brightness changed, and with it the level to match
+    DisplayLevel(u8),
+    // The periods of the schedule in force changed, capping the level (or
+    // lifting the cap) and setting it if one has just started
+    Schedule { cap: Option<u8>, level: Option<u8> },
+    // The sun set or rose further, so the level has to be capped lower or
+    // higher, or the cap can be lifted for the day
+    NightCap(Option<u8>),
+    // A game started (or stopped) running
+    GameMode(bool),
+    // A process that inhibits dimming started (or they all stopped) running
+    ProcessInhibit(bool),
+    // The backlight level has to be capped because of the temperature, or the
+    // cap can be lifted
+    ThermalCap(Option<u8>),
+    // The system switched to dark mode (or back to light)
+    DarkMode(bool),
+    // We went on to battery (or back on to mains power)
+    OnBattery(bool),
+    // How full the battery is now, as a percentage
+    BatteryLevel(f64),
+    // Another program set the level the user wants, and how it did
+    SetLevel(u8, FadeSource),
+    // Another program changed the idle timeout
+    SetTimeout(Duration),
+    // Asks what the backlight is doing
+    StateQuery(oneshot::Sender<dbus::State>),
+    // Whether other programs are holding off dimming on the timeout and on
+    // the lock combination
+    Inhibit { idle: bool, lock: bool },
+    // Step the level the user wants up or down, and what asked to
+    Step { up: bool, source: FadeSource },
+    // Read the config file again, e.g. on SIGHUP or after it was edited
+    Reload,
+    // Don't dim on the timeout for a while, or zero to allow it again
+    InhibitFor(Duration),
+    // Check that the main loop and the backlight are still answering, for
+    // systemd's watchdog
+    Watchdog(oneshot::Sender<Result<u8, BlError>>)
+}
+
+// What to run. Without one of these, the daemon is run
+#[derive(Subcommand)]
+enum Commands {
+    /// Run the daemon, dimming the backlight when idle (the default)
+    Daemon,
+    /// Print the current brightness level and exit
+    Get,
+    /// Set the brightness level and exit
+    Set {
+        /// The level to set
+        level: u8
+    },
+    /// Print whether the running daemon has dimmed the backlight, how long
+    /// the user has been idle and how long until it dims
+    Status,
+    /// Step to the next of a list of preset levels, wrapping around at the end
+    Cycle {
+        /// The preset levels to step through
+        #[arg(long, value_delimiter=',', default_value="0,10,25,50")]
+        levels: Vec<u8>
+    },
+    /// Time a series of reads and writes to measure the controller's latency
+    Bench {
+        /// The number of reads and writes to time
+        #[arg(long, default_value_t = 50)]
+        count: u32
+    },
+    /// Save the level of the keyboard and every zone to a file
+    DumpState {
+        /// The file to save the state to
+        file: PathBuf
+    },
+    /// Restore levels saved by dump-state
+    LoadState {
+        /// The file to restore the state from
+        file: PathBuf
+    },
+    /// Wait until the running daemon has dimmed the backlight, exiting with a
+    /// failure if that doesn't happen in time
+    WaitIdle {
+        /// Wait until the backlight has been dimmed for this many seconds
+        #[arg(long, default_value_t = 0.0)]
+        idle_for: f64,
+        /// Give up after this many seconds
+        #[arg(long)]
+        max_wait: Option<f64>
+    },
+    /// Tell the running daemon that there was activity, as if a key had been
+    /// pressed
+    SimulateActivity,
+    /// Print how long the running daemon considers the user idle, and how
+    /// long until it dims
+    IdleTime {
+        /// Print the times as JSON
+        #[arg(long)]
+        json: bool
+    },
+    /// Print each event from an input device as it happens, to find the
+    /// codes of keys
+    Monitor {
+        /// The input device to watch (defaults to the keyboard)
+        #[arg(long)]
+        device: Option<String>
+    },
+    /// List the USB devices, marking HID devices and known controllers, to
+    /// find the right product ID
+    ListDevices {
+        /// Print the devices as JSON
+        #[arg(long)]
+        json: bool
+    },
+    /// List the input event devices, showing which would be used as the
+    /// keyboard or other activity sources
+    ListInputs {
+        /// Print the devices as JSON
+        #[arg(long)]
+        json: bool
+    },
+    /// Record the levels that are comfortable under different ambient light,
+    /// and write them to the config file as the curve for --als-auto
+    CalibrateAls,
+    /// Record a key combination to use for locking
+    LearnLock {
+        /// The input device to watch (defaults to the keyboard)
+        #[arg(long)]
+        device: Option<String>
+    },
+    /// Write a systemd unit that runs the daemon with the options given
+    /// before this command
+    InstallService {
+        /// Install it for the user's own systemd rather than the system's
+        #[arg(long, conflicts_with = "system")]
+        user: bool,
+        /// Install it for the system's systemd (the default)
+        #[arg(long)]
+        system: bool,
+        /// Enable and start the service once it's installed
+        #[arg(long)]
+        enable: bool
+    },
+    /// Write udev rules that let the daemon run without being root, giving
+    /// the plugdev group the controller and the input group the keyboards
+    InstallUdevRules {
+        /// Give the devices to whoever is logged in at the seat instead of to
+        /// the groups
+        #[arg(long)]
+        uaccess: bool
+    }
+}
 
 #[derive(Parser)]
 #[command(version, about = "Controls the dimming of the keyboard backlight", long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+    /// The config file to read options from (defaults to
+    /// $XDG_CONFIG_HOME/bl-control/config.toml, then /etc/bl-control.toml)
+    #[arg(long)]
+    config: Option<PathBuf>,
     /// The USB Vendor ID of the controller
     #[arg(short, long, value_parser=maybe_hex::<u16>, default_value_t=1165)]
     vendor_id: u16,
-    /// The USB Product ID of the controller
+    /// The USB Product ID of the controller (found by looking for known
+    /// controllers by default)
     #[arg(short, long, value_parser=maybe_hex::<u16>)]
-    product_id: u16,
+    product_id: Option<u16>,
+    /// More product IDs to look for when the product ID isn't given
+    #[arg(long, value_delimiter=',', value_parser=maybe_hex::<u16>)]
+    known_product: Vec<u16>,
+    /// How to talk to the controller
+    #[arg(long, value_enum, default_value_t=Backend::Usb)]
+    backend: Backend,
+    /// The hidraw node of the controller for the hidraw backend (found from
+    /// the USB IDs by default)
+    #[arg(long)]
+    hidraw_device: Option<PathBuf>,
+    /// The LED to use for the sysfs backend (the first *kbd_backlight* in
+    /// /sys/class/leds by default)
+    #[arg(long)]
+    led: Option<PathBuf>,
     /// The number of seconds to wait after a keypress before dimming
     #[arg(short, long, default_value_t = 5.0)]
     timeout: f64,
+    /// The keyboard input device to read, e.g. a link in /dev/input/by-id
+    /// (every keyboard found from the devices' keys by default)
+    #[arg(long)]
+    input_device: Option<PathBuf>,
     /// Whether to dim the keyboard when Meta+L is pressed
     #[arg(short, long)]
     lock: bool,
+    /// The keys that make up the lock combination, as names or codes. The
+    /// last is pressed while holding the rest, and either Meta key will do
+    /// for a Meta key (see learn-lock)
+    #[arg(long, value_delimiter=',', value_parser=monitor::parse_key, default_value="KEY_LEFTMETA,KEY_L")]
+    lock_keys: Vec<u16>,
+    /// How to notice the screen being locked: the lock combination, logind
+    /// (which also sees locks from menus, the lid or loginctl) or the
+    /// desktop's screensaver. More than one can be given
+    #[arg(long, value_enum, value_delimiter=',', default_value="keys")]
+    lock_source: Vec<LockSource>,
+    /// The level to keep the keyboard at while locked, so that it can still be
+    /// found in the dark
+    #[arg(long, default_value_t = 0)]
+    lock_level: u8,
+    /// The number of seconds to wait after locking before dimming, so that
+    /// unlocking straight away doesn't flash the backlight off and on
+    #[arg(long, default_value_t = 0.0)]
+    lock_grace: f64,
+    /// Turn the backlight off straight away when the lid switch says the lid
+    /// is closed, and back on when it's opened
+    #[arg(long)]
+    lid: bool,
+    /// Turn the backlight off straight away when the display turns off, and
+    /// back on when it wakes, hearing about it from the given source
+    #[arg(long, value_enum)]
+    display_power: Option<DisplayPowerSource>,
     /// Color to set at startup, red component
     #[arg(short, long, value_parser=maybe_hex::<u8>, default_value_t=0)]
     red: u8,
@@ -40,457 +481,2281 @@ struct Cli {
     green: u8,
     /// Color to set at startup, blue component
     #[arg(short, long, value_parser=maybe_hex::<u8>, default_value_t=0)]
-    blue: u8
+    blue: u8,
+    /// Sources of user activity to monitor
+    #[arg(long, value_enum, value_delimiter=',', default_value="keyboard")]
+    idle_sources: Vec<IdleSource>,
+    /// Path to the acpid event socket
+    #[arg(long, default_value="/var/run/acpid.socket")]
+    acpid_socket: String,
+    /// An extra LED zone to control alongside the keyboard, given as
+    /// NAME=CHANNEL or NAME=CHANNEL:LEVEL (e.g. lightbar=0x22)
+    #[arg(long = "zone", value_parser=parse_zone)]
+    zones: Vec<Zone>,
+    /// Template for the report that sets the brightness, with {level} and
+    /// {channel} placeholders
+    #[arg(long, value_parser=ReportTemplate::parse, default_value=DEFAULT_SET_TEMPLATE)]
+    set_template: ReportTemplate,
+    /// Template for the report that queries the brightness. {level} marks
+    /// where the level is found in the reply
+    #[arg(long, value_parser=ReportTemplate::parse, default_value=DEFAULT_GET_TEMPLATE)]
+    get_template: ReportTemplate,
+    /// The length in bytes of the controller's feature reports
+    #[arg(long, default_value_t=DEFAULT_REPORT_LENGTH)]
+    report_length: usize,
+    /// Read the level back after each write and retry up to this many times
+    /// if the controller ignored it
+    #[arg(long, default_value_t = 0)]
+    verify_retries: u8,
+    /// How many times to retry a USB transfer that times out or stalls
+    #[arg(long, default_value_t = 2)]
+    transfer_retries: u8,
+    /// The number of seconds to wait before the first retry of a USB
+    /// transfer, doubling for each one after
+    #[arg(long, default_value_t = 0.05)]
+    retry_delay: f64,
+    /// Reset the USB device after this many reads or writes fail in a row
+    /// (0 to never reset it)
+    #[arg(long, default_value_t = 3)]
+    reset_after: u8,
+    /// Claim the USB interface once at startup and keep it until exit, rather
+    /// than detaching the kernel driver for every transfer
+    #[arg(long)]
+    hold_interface: bool,
+    /// Change the brightness instantly rather than fading for these sources
+    #[arg(long, value_enum, value_delimiter=',')]
+    no_fade: Vec<FadeSource>,
+    /// Send the backlight state to followers at this address (which may be a
+    /// broadcast address, e.g. 192.168.1.255:47800)
+    #[arg(long, requires = "follow_key")]
+    lead: Option<SocketAddr>,
+    /// Mirror the backlight state of a leader, listening on this address
+    /// (e.g. 0.0.0.0:47800)
+    #[arg(long, requires_all = ["follow_key", "follow_from"])]
+    follow: Option<SocketAddr>,
+    /// The secret shared by the leader and its followers, which signs the
+    /// state sent between them. Best kept in the config file
+    #[arg(long)]
+    follow_key: Option<String>,
+    /// The address of a leader to accept state from. Can be given more than
+    /// once
+    #[arg(long)]
+    follow_from: Vec<IpAddr>,
+    /// Bluetooth address of a paired phone. The backlight is dimmed as soon
+    /// as the phone goes away and restored when it comes back
+    #[arg(long)]
+    phone: Option<String>,
+    /// The number of seconds between checks on the phone
+    #[arg(long, default_value_t = 5.0)]
+    phone_interval: f64,
+    /// Treat the phone as gone when its signal strength (in dBm) drops below
+    /// this, rather than only when it disconnects
+    #[arg(long, allow_hyphen_values=true)]
+    phone_min_rssi: Option<i16>,
+    /// A command that exits successfully while someone is at the machine (e.g.
+    /// a webcam check), which stops the backlight from dimming when idle
+    #[arg(long)]
+    presence_command: Option<String>,
+    /// The number of seconds between runs of the presence command
+    #[arg(long, default_value_t = 30.0)]
+    presence_interval: f64,
+    /// Flash the backlight when the X11 bell rings, as a visual alert
+    #[arg(long)]
+    visual_bell: bool,
+    /// The number of times to flash for each bell
+    #[arg(long, default_value_t = 2)]
+    flash_count: u8,
+    /// Write log messages to this file rather than stdout
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// Rotate the log file once it reaches this many bytes (0 for never)
+    #[arg(long, default_value_t = 1048576)]
+    log_max_size: u64,
+    /// Rotate the log file once it has been written to for this many hours
+    #[arg(long)]
+    log_max_age: Option<f64>,
+    /// The number of rotated log files to keep
+    #[arg(long, default_value_t = 5)]
+    log_keep: u32,
+    /// Log more detail. Give twice to also log every report sent to and
+    /// read from the controller
+    #[arg(long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Log less: only warnings, or only errors if given twice
+    #[arg(long, action = ArgAction::Count)]
+    quiet: u8,
+    /// How to write log messages
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Per-target log levels, e.g. "usb=trace,input=debug,info". Overrides
+    /// --verbose and --quiet. Targets are usb, input and statemachine
+    #[arg(long)]
+    log_filter: Option<Targets>,
+    /// Wall-clock time (HH:MM) to gradually bring the backlight up each day
+    /// as a wake-up light
+    #[arg(long, value_parser=sunrise::parse_time)]
+    sunrise: Option<chrono::NaiveTime>,
+    /// The number of minutes the sunrise takes to reach its level
+    #[arg(long, default_value_t = 30.0)]
+    sunrise_duration: f64,
+    /// The level the sunrise brings the backlight up to (defaults to the maximum)
+    #[arg(long)]
+    sunrise_level: Option<u8>,
+    /// The color to use during the sunrise, as R,G,B
+    #[arg(long, value_parser=parse_color)]
+    sunrise_color: Option<(u8, u8, u8)>,
+    /// A time of day to cap the backlight or change its level, given as
+    /// from=HH:MM,to=HH:MM with max_level=LEVEL and/or level=LEVEL (e.g.
+    /// from=22:00,to=07:00,max_level=15). Can be given more than once
+    #[arg(long, value_parser=schedule::parse_period)]
+    schedule: Vec<schedule::Period>,
+    /// Cap the backlight at this level after dark, ramping between it and
+    /// the maximum through twilight. Needs --location
+    #[arg(long, requires = "location")]
+    night_level: Option<u8>,
+    /// Where we are, for working out when it's dark: LATITUDE,LONGITUDE in
+    /// degrees (north and east are positive), or geoclue to look it up,
+    /// optionally as geoclue:LATITUDE,LONGITUDE to go by until it answers
+    #[arg(long, value_parser=night::parse_location, allow_hyphen_values=true)]
+    location: Option<night::Location>,
+    /// Match the backlight color to the average color of the screen
+    #[arg(long)]
+    screen_color: bool,
+    /// The number of seconds between samples of the screen color
+    #[arg(long, default_value_t = 1.0)]
+    screen_color_interval: f64,
+    /// Show the charging state with the backlight color: breathing while
+    /// charging and solid once full
+    #[arg(long)]
+    charge_indicator: bool,
+    /// The color of the charging indicator, as R,G,B
+    #[arg(long, value_parser=parse_color, default_value="0,255,0")]
+    charge_color: (u8, u8, u8),
+    /// Where to publish whether the backlight is dimmed, for wait-idle
+    /// (defaults to $XDG_RUNTIME_DIR/bl-control/status)
+    #[arg(long)]
+    status_file: Option<PathBuf>,
+    /// Where the daemon listens for commands from other programs (defaults
+    /// to $XDG_RUNTIME_DIR/bl-control/control, or /run/bl-control/control)
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+    /// Let this group use the control socket as well as the user running the
+    /// daemon, e.g. for a system daemon
+    #[arg(long)]
+    control_group: Option<String>,
+    /// The lock that stops two daemons running at once (defaults to
+    /// $XDG_RUNTIME_DIR/bl-control.lock, or /run/bl-control.lock)
+    #[arg(long)]
+    lock_file: Option<PathBuf>,
+    /// How much SIGUSR1 and SIGUSR2 step the level up and down by (defaults
+    /// to a tenth of the maximum level)
+    #[arg(long)]
+    step: Option<u8>,
+    /// Offer control of the daemon as org.blcontrol.Backlight1 on this D-Bus
+    /// bus
+    #[arg(long, value_enum)]
+    dbus: Option<Bus>,
+    /// When started as root, switch to this user once the devices are open
+    #[arg(long)]
+    run_as: Option<String>,
+    /// Also shut the daemon into this (ideally empty) directory when
+    /// switching user
+    #[arg(long, requires = "run_as")]
+    chroot: Option<PathBuf>,
+    /// Once running, restrict the daemon to the system calls it needs. This
+    /// can't be used with options that run other programs
+    #[arg(long, conflicts_with_all = ["phone", "presence_command"])]
+    seccomp: bool,
+    /// How much activity from a source counts towards the timeout, given as
+    /// SOURCE=WEIGHT. A weight of 1 (the default) restarts the timeout, while
+    /// 0.25 only extends it by a quarter of the timeout (e.g. acpid=0.25)
+    #[arg(long = "activity-weight", value_parser=parse_activity_weight)]
+    activity_weights: Vec<(ActivitySource, f64)>,
+    /// Only dim when idle if the ambient light is below this many lux, as the
+    /// backlight can't be seen in a bright room anyway
+    #[arg(long)]
+    als_gate: Option<f64>,
+    /// The IIO device of the ambient light sensor (defaults to the first one
+    /// found)
+    #[arg(long)]
+    als_device: Option<PathBuf>,
+    /// The number of seconds between ambient light readings
+    #[arg(long, default_value_t = 10.0)]
+    als_interval: f64,
+    /// Set the level from the ambient light, following --als-curve, in place
+    /// of a fixed level
+    #[arg(long)]
+    als_auto: bool,
+    /// A point on the curve that maps ambient light to a level for
+    /// --als-auto, given as LUX=LEVEL (e.g. 0=50). Levels in between points
+    /// are interpolated (defaults to full brightness in the dark, half at 50
+    /// lux and off from 300 lux)
+    #[arg(long = "als-curve", value_parser=parse_curve_point)]
+    als_curve: Vec<(f64, u8)>,
+    /// Set the level to follow the display's brightness, scaled to the
+    /// keyboard's range, in place of a fixed level
+    #[arg(long, conflicts_with = "als_auto")]
+    follow_display: bool,
+    /// The display backlight to follow for --follow-display, e.g.
+    /// /sys/class/backlight/intel_backlight (defaults to the first one found)
+    #[arg(long)]
+    display_backlight: Option<PathBuf>,
+    /// Don't dim, either when idle or on the lock combination, while a game is
+    /// running (gamescope, Steam Big Picture or a --game-process)
+    #[arg(long)]
+    game_mode: bool,
+    /// The name of another process that counts as a game for --game-mode
+    #[arg(long = "game-process")]
+    game_processes: Vec<String>,
+    /// The name of a process that stops the backlight dimming when idle while
+    /// it's running (e.g. obs, zoom or orca)
+    #[arg(long = "inhibit-process")]
+    inhibit_processes: Vec<String>,
+    /// Cap the backlight level while the temperature is at least this many
+    /// degrees Celsius
+    #[arg(long)]
+    thermal_limit: Option<f64>,
+    /// The level to cap the backlight at while running hot
+    #[arg(long, default_value_t = 10)]
+    thermal_level: u8,
+    /// The hwmon tempN_input file to read the temperature from (defaults to
+    /// the hottest sensor)
+    #[arg(long)]
+    thermal_sensor: Option<PathBuf>,
+    /// Save power while on battery by fading and polling less often and not
+    /// matching the screen color
+    #[arg(long)]
+    low_power: bool,
+    /// Where to find out whether we're on battery, for --low-power and the
+    /// battery profile
+    #[arg(long, value_enum, default_value_t = PowerMonitor::Sysfs)]
+    power_monitor: PowerMonitor,
+    /// After the backlight has been off for this many minutes, run a very dim
+    /// animation until there's activity
+    #[arg(long)]
+    screensaver: Option<f64>,
+    /// What to do with the backlight when idle
+    #[arg(long, value_enum, default_value_t = IdleEffect::Off)]
+    idle_effect: IdleEffect,
+    /// The level to breathe up to with --idle-effect breathe
+    #[arg(long, default_value_t = 5)]
+    breathe_level: u8,
+    /// The level to dim to when idle with --idle-effect off, rather than
+    /// turning the backlight off
+    #[arg(long, default_value_t = 0)]
+    idle_level: u8,
+    /// Switch to this backlight level when the system switches to dark mode,
+    /// going back to the previous level in light mode
+    #[arg(long)]
+    dark_mode_level: Option<u8>,
+    /// Use this many seconds as the timeout while the system is in dark mode
+    #[arg(long)]
+    dark_mode_timeout: Option<f64>,
+    /// Use this many seconds as the timeout while on battery
+    #[arg(long)]
+    battery_timeout: Option<f64>,
+    /// Cap the backlight at this level while on battery
+    #[arg(long)]
+    battery_max_level: Option<u8>,
+    /// The level to dim to when idle while on battery, in place of
+    /// --idle-level
+    #[arg(long)]
+    battery_idle_level: Option<u8>,
+    /// Cap the backlight while on battery once the battery is below this
+    /// percentage
+    #[arg(long)]
+    low_battery: Option<f64>,
+    /// The level to cap the backlight at while the battery is low
+    #[arg(long, default_value_t = 10)]
+    low_battery_level: u8,
+    /// Flash the backlight as a warning while on battery once the battery is
+    /// below this percentage, and again each time it drops further
+    #[arg(long)]
+    critical_battery: Option<f64>,
+    /// The number of times to flash for each critical battery warning
+    #[arg(long, default_value_t = 2)]
+    critical_battery_flashes: u8
 }
 
 
-// Spawns a sleep
-async fn create_timeout(duration: Duration) {
-    tokio::spawn(sleep(duration)).await.unwrap();
+// Parses a per-source activity weight given as SOURCE=WEIGHT
+fn parse_activity_weight(s: &str) -> Result<(ActivitySource, f64), String> {
+    let (source, weight) = match s.split_once('=') {
+        Some(parts) => parts,
+        None => return Err(String::from("expected SOURCE=WEIGHT"))
+    };
+
+    let source = ActivitySource::from_str(source, true)?;
+    let weight: f64 = weight.parse().map_err(|e| format!("invalid weight: {}", e))?;
+    if !(0.0..=1.0).contains(&weight) {
+        return Err(String::from("weight must be between 0 and 1"));
+    }
+    Ok((source, weight))
 }
 
 
-// Determines which device under /dev/input is the keyboard and returns that
-// path
-fn get_keyboard_event() -> Result<String, String> {
-    // Read dir listing of /sys/class/input
-    let entries = match fs::read_dir("/sys/class/input") {
-        Ok(e) => e,
-        Err(e) => return Err(e.to_string())
-    };
+// The lowest of the caps on the level, if there are any
+fn lowest_cap(caps: &[Option<u8>]) -> Option<u8> {
+    caps.iter().flatten().copied().min()
+}
 
-    // Iterate over entries
-    for path in entries {
-        if let Ok(path) = path {
-            // Only search for event*
-            if !path.file_name().to_str().unwrap().starts_with("event") {
-                continue;
-            }
 
-            // Get the path to the device name file
-            let name_path = Path::new("/sys/class/input").join(path.file_name()).join("device/name");
-            let name_path_str = match name_path.to_str() {
-                Some(e) => e,
-                None => continue
-            };
+// Whether anything needs to know when we're on battery, so that the power
+// monitor has to run
+fn watches_power(args: &Cli) -> bool {
+    args.low_power || args.battery_timeout.is_some() || args.battery_max_level.is_some() || args.battery_idle_level.is_some()
+        || args.low_battery.is_some() || args.critical_battery.is_some()
+}
 
 
-            // Open the file
-            let mut file = match File::open(name_path_str) {
-                Ok(file) => file,
-                Err(_) => continue
-            };
+// Names the options that changed on reloading the configuration but are only
+// read at startup, by the threads that were started with them, so won't take
+// effect until the daemon is restarted
+fn needs_restart(old: &Cli, new: &Cli) -> Vec<&'static str> {
+    let watches_lock = |args: &Cli| args.lock && args.lock_source.iter().any(|source| *source != LockSource::Keys);
+    let watches_dark_mode = |args: &Cli| args.dark_mode_level.is_some() || args.dark_mode_timeout.is_some();
+    let changed = [
+        (old.lock_keys != new.lock_keys, "--lock-keys"),
+        (old.lock_source != new.lock_source, "--lock-source"),
+        (watches_lock(old) != watches_lock(new), "--lock"),
+        (old.schedule != new.schedule, "--schedule"),
+        (old.night_level != new.night_level, "--night-level"),
+        (old.location != new.location, "--location"),
+        (old.sunrise != new.sunrise, "--sunrise"),
+        (old.sunrise_duration != new.sunrise_duration, "--sunrise-duration"),
+        (old.sunrise_level != new.sunrise_level, "--sunrise-level"),
+        (old.als_gate != new.als_gate, "--als-gate"),
+        (old.als_auto != new.als_auto, "--als-auto"),
+        (old.als_curve != new.als_curve, "--als-curve"),
+        (old.als_device != new.als_device, "--als-device"),
+        (old.als_interval != new.als_interval, "--als-interval"),
+        (old.thermal_limit != new.thermal_limit, "--thermal-limit"),
+        (old.thermal_level != new.thermal_level, "--thermal-level"),
+        (old.thermal_sensor != new.thermal_sensor, "--thermal-sensor"),
+        (old.low_power != new.low_power, "--low-power"),
+        (old.power_monitor != new.power_monitor, "--power-monitor"),
+        (watches_power(old) != watches_power(new), "the battery profile"),
+        (watches_dark_mode(old) != watches_dark_mode(new), "the dark mode profile"),
+        (old.follow_display != new.follow_display, "--follow-display"),
+        (old.display_backlight != new.display_backlight, "--display-backlight")
+    ];
+    changed.into_iter().filter(|(changed, _)| *changed).map(|(_, name)| name).collect()
+}
 
-            // Read the contents
-            let mut contents = String::new();
-            match file.read_to_string(&mut contents) {
-                Ok(_) => (),
-                Err(_) => continue
-            }
 
-            // Check the contents
-            if contents.contains("keyboard") {
-                let result = Path::new("/dev/input").join(path.file_name());
-                match result.to_str() {
-                    Some(e) => return Ok(String::from(e)),
-                    None => continue
-                };
-            }
-        }
+// Works out the cap that being on battery puts on the level: the battery
+// profile's, and the low battery level once the battery is low
+fn battery_cap(args: &Cli, on_battery: bool, battery_level: Option<f64>) -> Option<u8> {
+    if !on_battery {
+        return None;
     }
 
-    return Err(String::from("not found"))
+    let low_cap = match (args.low_battery, battery_level) {
+        (Some(low), Some(battery_level)) if battery_level < low => Some(args.low_battery_level),
+        _ => None
+    };
+    lowest_cap(&[args.battery_max_level, low_cap])
 }
 
 
-// Takes control of a USB device and interface
-fn take_control(handle: &mut libusb::DeviceHandle) -> bool {
-    let is_active = match handle.kernel_driver_active(1) {
-        Ok(a) => a,
-        Err(e) => {
-            println!("Error determining driver activity: {}", e);
-            return false;
-        }
+// Works out the timeout to dim after: dark mode's if we're in it and it has
+// one, otherwise the battery's if we're on it and it has one, and otherwise
+// the normal one
+fn current_timeout(args: &Cli, dark_mode: bool, on_battery: bool, light_timeout: Duration) -> Duration {
+    match (dark_mode, args.dark_mode_timeout, on_battery, args.battery_timeout) {
+        (true, Some(t), _, _) | (_, _, true, Some(t)) => Duration::from_secs_f64(t),
+        _ => light_timeout
+    }
+}
+
+
+// Finds how much activity from a source counts towards the timeout, with the
+// last weight given for it winning
+fn activity_weight(weights: &[(ActivitySource, f64)], source: ActivitySource) -> f64 {
+    weights.iter().rev()
+        .find(|(s, _)| *s == source)
+        .map_or(1.0, |(_, w)| *w)
+}
+
+
+// Parses a point on the ambient light curve given as LUX=LEVEL
+fn parse_curve_point(s: &str) -> Result<(f64, u8), String> {
+    let (lux, level) = match s.split_once('=') {
+        Some(parts) => parts,
+        None => return Err(String::from("expected LUX=LEVEL"))
     };
 
-    if is_active {
-        match handle.detach_kernel_driver(1) {
-            Err(e) => {
-                println!("Error detaching kernel driver: {}", e);
-                return false;
-            },
-            _ => {
-                return true;
-            }
-        }
-    } else {
-        return false;
+    let lux: f64 = lux.trim().parse().map_err(|e| format!("invalid lux: {}", e))?;
+    if !lux.is_finite() || lux < 0.0 {
+        return Err(String::from("lux must not be negative"));
     }
+    let level = level.trim().parse().map_err(|e| format!("invalid level: {}", e))?;
+    Ok((lux, level))
 }
 
 
-// Releases control of a USB device and interface if it was taken
-fn release_control(handle: &mut libusb::DeviceHandle, is_active: bool) {
-    match handle.release_interface(1) {
-        Err(e) => println!("Release Error: {}", e),
-        _ => ()
+// Parses a color given as R,G,B
+fn parse_color(s: &str) -> Result<(u8, u8, u8), String> {
+    let rgb: Vec<u8> = match s.split(',').map(|c| c.trim().parse()).collect() {
+        Ok(rgb) => rgb,
+        Err(e) => return Err(format!("expected R,G,B: {}", e))
+    };
+    match rgb[..] {
+        [r, g, b] => Ok((r, g, b)),
+        _ => Err(String::from("expected R,G,B"))
     }
+}
 
-    if is_active {
-        match handle.attach_kernel_driver(1) {
-            Err(e) => println!("Error attaching kernel driver: {}", e),
+
+// Prints the answer to an idle-time query, either as seconds on a line each
+// or as JSON. Seconds until dimming are left out (or null) when already dimmed
+fn print_idle_time(answer: &str, json: bool) {
+    let mut idle = "0";
+    let mut dim_in = None;
+    for part in answer.split_whitespace() {
+        match part.split_once('=') {
+            Some(("idle", v)) => idle = v,
+            Some(("dim-in", v)) => dim_in = Some(v),
             _ => ()
         }
     }
-}
-
-
-// Determines the current brightness level of the keyboard backlight
-fn read_brightness_level(handle: &mut libusb::DeviceHandle) -> Result<u8, String> {
-    let is_active = take_control(handle);
 
-    // 0x88 is "get effect"
-    // 0x02 is "effect attribute brightness"
-    let mut data: [u8; 8] = [0x88, 0x02, 0x33, 0x00, 0x00, 0x00, 0x00, 0x00];
-    match handle.claim_interface(1) {
-        Err(e) => {
-            return Err(e.to_string());
-        },
-        _ => ()
+    if json {
+        println!("{{\"idle\": {}, \"dim_in\": {}}}", idle, dim_in.unwrap_or("null"));
+    } else {
+        println!("{}", idle);
+        if let Some(dim_in) = dim_in {
+            println!("{}", dim_in);
+        }
     }
+}
 
-    // Set up some request types
-    let request_type_in = libusb::request_type(libusb::Direction::In, libusb::RequestType::Class, libusb::Recipient::Interface);
-    let request_type_out = libusb::request_type(libusb::Direction::Out, libusb::RequestType::Class, libusb::Recipient::Interface);
 
-    // Write out the request to read the brightness
-    // request 0x09 is HID set_report
-    // value 0x0300 is HID feature
-    // index 0x0001 is whatever
-    match handle.write_control(request_type_out, 0x09, 0x0300, 0x0001, &data, Duration::from_secs(1)) {
-        Err(e) => {
-            return Err(e.to_string());
-        },
-        _ => ()
+// Prints the dimmed state from the status file and the idle time reported
+// over the control socket, whichever of them are available
+fn print_status(dimmed: Option<(bool, Duration)>, idle_time: Option<&str>) {
+    match dimmed {
+        Some((true, since)) => println!("dimmed for {}s", since.as_secs()),
+        Some((false, since)) => println!("on for {}s", since.as_secs()),
+        None => ()
     }
 
-    // Read the brightness
-    // request 0x01 is HID get_report
-    // value 0x0300 is HID feature
-    // index 0x0001 is whatever
-    match handle.read_control(request_type_in, 0x01, 0x0300, 0x0001, &mut data, Duration::from_secs(1)) {
-        Err(e) => {
-            return Err(e.to_string());
-        },
-        _ => ()
+    for part in idle_time.unwrap_or_default().split_whitespace() {
+        match part.split_once('=') {
+            Some(("idle", v)) => println!("idle for {}s", v),
+            Some(("dim-in", v)) => println!("dimming in {}s", v),
+            _ => ()
+        }
     }
+}
 
-    release_control(handle, is_active);
 
-    return Ok(data[4])
+// Spawns a sleep
+async fn create_timeout(duration: Duration) {
+    tokio::spawn(sleep(duration)).await.unwrap();
 }
 
 
-// Sets the keyboard backlight level
-fn set_backlight_level(handle: &mut libusb::DeviceHandle, level: u8) {
-    let is_active = take_control(handle);
+// Opens the ITE controller and works out what it can do, ready to be driven
+fn open_controller(args: &Cli) -> Result<(Device, DeviceSource, DeviceDescription, Capabilities), BlError> {
+    // Find the controller if we weren't told which it is
+    let product_id = match args.product_id {
+        Some(product_id) => product_id,
+        None => detect_product_id(args)?
+    };
 
-    // 0x08 is "set effect"
-    // 0x02 is "effect attribute brightness"
-    let data: [u8; 8] = [0x08, 0x02, 0x33, 0x00, level, 0x00, 0x00, 0x00];
-    match handle.claim_interface(1) {
-        Err(e) => {
-            println!("Claim Error: {}", e);
-            return;
-        },
-        _ => ()
+    // Open the device
+    let mut handle = match args.backend {
+        Backend::Hidraw => Device::Hidraw(open_hidraw_device(args.vendor_id, product_id, args.hidraw_device.as_deref())?),
+        _ => Device::Usb(open_usb_device(args.vendor_id, product_id)?)
+    };
+    let source = match args.backend {
+        Backend::Hidraw => DeviceSource::Hidraw { vendor_id: args.vendor_id, product_id, path: args.hidraw_device.clone() },
+        _ => DeviceSource::Usb { vendor_id: args.vendor_id, product_id }
+    };
+
+    // The templates have to fit in the reports
+    if args.report_length < DEFAULT_REPORT_LENGTH {
+        return Err(BlError::Config(format!("report length must be at least {}", DEFAULT_REPORT_LENGTH)));
+    }
+    if args.set_template.byte_count() > args.report_length || args.get_template.byte_count() > args.report_length {
+        return Err(BlError::Config(format!("report templates are longer than the report length of {}", args.report_length)));
     }
 
-    // Set up the request type
-    let request_type = libusb::request_type(libusb::Direction::Out, libusb::RequestType::Class, libusb::Recipient::Interface);
+    let mut desc = DeviceDescription {
+        report_length: args.report_length,
+        set_template: args.set_template.clone(),
+        get_template: args.get_template.clone(),
+        zones: args.zones.clone(),
+        verify_retries: args.verify_retries,
+        retry: RetryPolicy { retries: args.transfer_retries, delay: Duration::from_secs_f64(args.retry_delay) },
+        reset_after: args.reset_after,
+        hold_interface: false
+    };
 
-    // request 0x09 is HID set_report
-    // value 0x0300 is HID feature
-    // index 0x0001 is whatever
-    match handle.write_control(request_type, 0x09, 0x0300, 0x0001, &data, Duration::from_secs(1)) {
-        Err(e) => println!("Error: {}", e),
-        _ => ()
+    // Find out what the controller can do
+    let caps = read_capabilities(&mut handle, &desc);
+    match caps.firmware {
+        Some(f) => info!("Controller firmware version {}.{}.{}.{}", f[0], f[1], f[2], f[3]),
+        None => info!("Controller did not report a firmware version, assuming defaults")
+    }
+    info!("Capabilities: max level {}, color: {}, effects: {}", caps.max_level, caps.color, caps.effects);
+
+    // Only drive the auxiliary zones the controller answers for
+    let mut zones = std::mem::take(&mut desc.zones);
+    zones.retain(|zone| {
+        let found = has_channel(&mut handle, &desc, zone.channel);
+        if !found {
+            warn!("Controller does not answer for zone {} on channel 0x{:02x}, ignoring it", zone.name, zone.channel);
+        }
+        found
+    });
+    desc.zones = zones;
+    for zone in &desc.zones {
+        info!("Controlling zone {} on channel 0x{:02x}", zone.name, zone.channel);
+    }
+
+    // Work out which protocol revision we're talking
+    let protocol = probe_protocol(&mut handle, &desc, product_id, &caps);
+    match protocol {
+        Some(p) => info!("Using protocol {:?}", p),
+        None => warn!("Unknown protocol revision, assuming {:?}", Protocol::Rev3)
     }
 
-    release_control(handle, is_active);
+    Ok((handle, source, desc, caps))
 }
 
 
-// Sets the keyboard backlight color
-fn set_backlight_color(handle: &mut libusb::DeviceHandle, r: u8, g: u8, b: u8) {
-    let is_active = take_control(handle);
+// Opens the backlight to drive, with whichever backend we've been given
+fn open_backlight(args: &Cli) -> Result<Box<dyn BacklightBackend>, BlError> {
+    Ok(match args.backend {
+        Backend::Mock => Box::new(MockBacklight::new(MAX_LEVEL)),
+        Backend::Sysfs => Box::new(open_led(args.led.as_deref())?),
+        Backend::Usb | Backend::Hidraw => {
+            let (handle, source, mut desc, caps) = open_controller(args)?;
 
-    match handle.claim_interface(1) {
-        Err(e) => {
-            println!("Claim Error: {}", e);
-            return;
-        },
-        _ => ()
-    }
+            // The interface is only held once the controller has been probed,
+            // and only by the USB backend, which is the one that claims it
+            desc.hold_interface = args.hold_interface && args.backend == Backend::Usb;
+            Box::new(KeyboardBacklight::new(handle, desc, caps).reconnect_from(source))
+        }
+    })
+}
 
-    // Set up the request type
-    let request_type = libusb::request_type(libusb::Direction::Out, libusb::RequestType::Class, libusb::Recipient::Interface);
 
-    // request 0x09 is HID set_report
-    // value 0x0300 is HID feature
-    // index 0x0001 is whatever
-    let data: [u8; 8] = [0x12, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00];
-    match handle.write_control(request_type, 0x09, 0x0300, 0x0001, &data, Duration::from_secs(1)) {
-        Err(e) => println!("Error: {}", e),
-        _ => ()
-    }
+// Whether two sets of arguments pick the same backlight, so that it doesn't
+// have to be opened again
+fn same_device(a: &Cli, b: &Cli) -> bool {
+    a.backend == b.backend && a.vendor_id == b.vendor_id && a.product_id == b.product_id &&
+        a.known_product == b.known_product && a.hidraw_device == b.hidraw_device && a.led == b.led
+}
 
-    // Send the color eight times for the eight zones (we send to endpoint 2, which is the output
-    // endpoint
-    for _ in 0..8 {
-        let color_data: [u8; 64] = [0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b, 0, r, g, b];
-        match handle.write_bulk(2, &color_data, Duration::from_secs(1)) {
-            Err(e) => println!("Error: {}", e),
-            _ => ()
-        }
-    }
 
-    release_control(handle, is_active);
+// Opens the kernel's keyboard backlight LED, finding it if it isn't given
+fn open_led(path: Option<&Path>) -> Result<SysfsBacklight, BlError> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => sysfs::find()?
+    };
 
+    let led = SysfsBacklight::open(&path)?;
+    info!(device = %path.display(), "Using keyboard backlight LED {}", path.display());
+    Ok(led)
 }
 
-// Returns the current brightness level or a default
-fn get_updated_requested_level(handle: &mut libusb::DeviceHandle, level: u8) -> u8 {
-    // Read the current brightness level as the user may have
-    // changed it via the keyboard
-    return match read_brightness_level(handle) {
-        Ok(l) => {
-            l
-        }
-        Err(e) => {
-            println!("Failed to get current brightness: {}", e);
-            level
-        }
-    };
+
+// The product IDs of every controller we know about, including any extra ones
+// we've been given
+fn known_product_ids(args: &Cli) -> Vec<u16> {
+    KNOWN_CONTROLLERS.iter().map(|(id, _)| *id)
+        .chain(args.known_product.iter().copied())
+        .collect()
 }
 
 
-// Entry point
-#[tokio::main(worker_threads=2)]
-async fn main() {
-    // Parse the command line arguments
-    let args = Cli::parse();
-
-    // Get the path to our keyboard input device
-    let event_path = match get_keyboard_event() {
-        Ok(e) => {
-            println!("Found keyboard device at {}", e);
-            e
-        },
-        Err(e) => panic!("couldn't find input device: {}", e)
+// Looks for a known controller from the vendor, going through the same
+// interface as the backend will
+fn detect_product_id(args: &Cli) -> Result<u16, BlError> {
+    let product_ids = known_product_ids(args);
+
+    let detected = match args.backend {
+        Backend::Hidraw => detect_hidraw_controller(args.vendor_id, &product_ids),
+        _ => detect_usb_controller(args.vendor_id, &product_ids)
     };
+    match detected {
+        Ok(product_id) => {
+            info!("Detected controller with product ID 0x{:04x}", product_id);
+            Ok(product_id)
+        },
+        Err(BlError::DeviceNotFound(e)) => Err(BlError::DeviceNotFound(format!("{}, try giving --product-id", e))),
+        Err(e) => Err(e)
+    }
+}
 
+
+// Opens the controller's USB device
+fn open_usb_device(vendor_id: u16, product_id: u16) -> Result<rusb::DeviceHandle<rusb::Context>, BlError> {
     // Initialise libusb
-    let context = match libusb::Context::new() {
-        Ok(context) => context,
-        Err(e) => panic!("could not initialise libusb: {}", e)
-    };
+    let context = rusb::Context::new().map_err(|e| BlError::usb("could not initialise libusb", e))?;
 
-    // Open the USB device
-    let mut handle = match context.open_device_with_vid_pid(args.vendor_id, args.product_id) {
+    match context.open_device_with_vid_pid(vendor_id, product_id) {
         Some(handle) => {
-            println!("Found matching USB device for vendor 0x{:04x}, product 0x{:04x}", args.vendor_id, args.product_id);
-            handle
+            info!("Found matching USB device for vendor 0x{:04x}, product 0x{:04x}", vendor_id, product_id);
+            Ok(handle)
         },
-        None => panic!("couldn't find USB device")
+        None => Err(BlError::DeviceNotFound(format!("couldn't find USB device 0x{:04x}:0x{:04x}", vendor_id, product_id)))
+    }
+}
+
+
+// Opens the hidraw node of the interface we send reports to, finding it from
+// the USB IDs if it isn't given
+fn open_hidraw_device(vendor_id: u16, product_id: u16, path: Option<&Path>) -> Result<File, BlError> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => hidraw::find(vendor_id, product_id, 1)?
     };
 
-    // Read the current brightness level
-    let mut requested_level = get_updated_requested_level(&mut handle, 50);
-    println!("Initial backlight level is {}", requested_level);
+    let file = hidraw::open(&path)?;
+    info!(device = %path.display(), "Opened hidraw device {}", path.display());
+    Ok(file)
+}
 
-    // Create a thread that posts to a channel when it's able to read
-    let (s, mut r) = mpsc::unbounded_channel();
-    let thread_builder = thread::Builder::new().name("input-reader".to_string());
-    let thread_start_result = thread_builder.spawn(move || {
-        // Open input device
-        let mut file = File::open(Path::new(&event_path)).expect("Failed to open input device");
 
-        // Initialise a buffer large enough to read our input data
-        let mut buf: [u8; 24] = [0; 24];
+// Determines which devices under /dev/input are keyboards and returns their
+// paths, with the laptop's own keyboard first. A device that was given (which
+// may be a link, e.g. from by-id) is used on its own
+fn get_keyboard_events(input_device: Option<&Path>) -> Result<Vec<String>, BlError> {
+    if let Some(path) = input_device {
+        let resolved = fs::canonicalize(path).map_err(|e| BlError::io(path.display(), e))?;
+        return Ok(vec![resolved.to_string_lossy().into_owned()]);
+    }
+
+    match devices::find_keyboards()? {
+        keyboards if keyboards.is_empty() => Err(BlError::DeviceNotFound(String::from("couldn't find a keyboard input device"))),
+        keyboards => Ok(keyboards)
+    }
+}
 
-        // Debug
-        println!("Input thread running");
 
-        // Flag to keep track of Meta (Windows) key states
-        let mut meta_l_down = false;
-        let mut meta_r_down = false;
+// Determines which device under /dev/input is the main keyboard and returns
+// that path
+fn get_keyboard_event(input_device: Option<&Path>) -> Result<String, BlError> {
+    get_keyboard_events(input_device).map(|keyboards| keyboards[0].clone())
+}
 
-        // Read up to 24 bytes
-        loop {
-            let count = file.read(&mut buf).expect("Failed to read");
-            if count < 24 {
-                println!("Warning - too few bytes read");
+
+// Starts reading from any keyboards that aren't being read yet, and returns
+// how many were started
+fn attach_keyboards(input_device: Option<&Path>, lock_keys: &[u16], active: &Arc<Mutex<Vec<String>>>, s: &mpsc::UnboundedSender<Event>) -> Result<usize, BlError> {
+    let mut attached = 0;
+    for event_path in get_keyboard_events(input_device)? {
+        if active.lock().unwrap().contains(&event_path) {
+            continue;
+        }
+
+        info!(target: log::INPUT, device = %event_path, "Found keyboard device at {}", event_path);
+        active.lock().unwrap().push(event_path.clone());
+        match spawn_input_reader(event_path.clone(), lock_keys.to_vec(), active.clone(), s.clone()) {
+            Ok(_) => attached += 1,
+            Err(e) => {
+                warn!(target: log::INPUT, device = %event_path, "Failed to read keyboard {}: {}", event_path, e);
+                active.lock().unwrap().retain(|p| *p != event_path);
             }
+        }
+    }
+
+    Ok(attached)
+}
+
+
+// Creates a thread that posts to a channel when it's able to read a key event
+// from the given input device. When the device goes away (e.g. a Bluetooth
+// keyboard disconnecting) it's taken out of the active list and the thread
+// stops, so that it can be picked up again when it comes back
+fn spawn_input_reader(event_path: String, lock_keys: Vec<u16>, active: Arc<Mutex<Vec<String>>>, s: mpsc::UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    // Open the input device here rather than in the thread, so that it's open
+    // before any privileges are dropped
+    let mut reader = input::EventReader::new(File::open(Path::new(&event_path))?);
+    let thread_builder = thread::Builder::new().name("input-reader".to_string());
+    thread_builder.spawn(move || {
+
+        info!(target: log::INPUT, device = %event_path, "Reading keys from {}", event_path);
 
-            // Parse the data to see what keys were pressed
-            let in_type = (buf[17] as u16) << 8 | (buf[16] as u16);
-            let code = (buf[19] as u16) << 8 | (buf[18] as u16);
-            let value = (buf[23] as u32) << 24 | (buf[22] as u32) << 16 | (buf[21] as u32) << 8 | (buf[20] as u32);
+        // Keep track of which keys are held down. The last of the lock keys
+        // triggers the lock, while the rest have to be held down with it
+        let mut held: Vec<u16> = Vec::new();
+        let (lock_trigger, lock_modifiers) = match lock_keys.split_last() {
+            Some((trigger, modifiers)) => (Some(*trigger), modifiers.to_vec()),
+            None => (None, Vec::new())
+        };
+
+        loop {
+            // Read the next event to see what keys were pressed
+            let input::InputEvent { in_type, code, value } = match reader.next_event() {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!(target: log::INPUT, device = %event_path, "Stopped reading keys from {}: {}", event_path, e);
+                    active.lock().unwrap().retain(|p| *p != event_path);
+                    return;
+                }
+            };
 
             // Only handle events on a key-up / key-down / key-repeat
             if in_type == EV_KEY {
-                // Keep track if the Meta (Windows) key is down
-                if code == KEY_LEFT_META {
-                    meta_l_down = value > 0;
-                } else if code == KEY_RIGHT_META {
-                    meta_r_down = value > 0;
+                // Keep track of which keys are down (ignoring key repeats)
+                if value == 1 && !held.contains(&code) {
+                    held.push(code);
+                } else if value == 0 {
+                    held.retain(|k| *k != code);
                 }
 
-                // Check for a Meta+L combination key release
-                let result = match value == 0 && code == KEY_L && (meta_l_down || meta_r_down) {
-                    true => 1,
-                    false => 0
+                // Check for the lock combination (Meta+L by default) being released
+                let is_lock = lock_trigger == Some(code) && lock_modifiers.iter().all(|k| is_held(&held, *k));
+                let event = match value == 0 && is_lock {
+                    true => Event::LockKey,
+                    false => Event::Activity(ActivitySource::Keyboard)
                 };
 
                 // Send the event
-                match s.send(result) {
-                    Err(e) => println!("{}", e),
-                    Ok(_) => ()
+                if let Err(e) = s.send(event) {
+                    warn!(target: log::INPUT, "{}", e);
                 }
             }
         }
-    });
-    match thread_start_result {
-        Ok(_) => (),
-        Err(e) => panic!("Failed to start input thread: {}", e)
+    })
+}
+
+
+// Checks whether a key of the lock combination is held down. Either Meta key
+// does for the other, so that Meta+L works whichever side it's pressed on
+fn is_held(held: &[u16], key: u16) -> bool {
+    let meta = [KEY_LEFTMETA, KEY_RIGHTMETA];
+    match meta.contains(&key) {
+        true => held.iter().any(|k| meta.contains(k)),
+        false => held.contains(&key)
     }
+}
 
-    // Turn the backlight on
-    let mut level = requested_level;
-    if level == 0 {
-        println!("Initial level was 0, resetting to 50");
-        level = 50;
+
+// Returns the level the user has set, or a default if it can't be read
+async fn get_updated_requested_level(brightness: &StdSender<brightness::Command>, level: u8) -> u8 {
+    // Read the current brightness level as the user may have
+    // changed it via the keyboard
+    let (reply_s, reply_r) = oneshot::channel();
+    if brightness.send(brightness::Command::Read(reply_s)).is_err() {
+        error!(target: log::STATEMACHINE, "Brightness thread has stopped");
+        return level;
     }
-    set_backlight_level(&mut handle, level);
 
-    // If the color is given, set it on the device
-    if args.red > 0 || args.green > 0 || args.blue > 0 {
-        println!("Setting color to {}, {}, {}", args.red, args.green, args.blue);
-        set_backlight_color(&mut handle, args.red, args.green, args.blue);
+    match reply_r.await {
+        Ok(Ok(l)) => l,
+        Ok(Err(e)) => {
+            warn!(target: log::STATEMACHINE, "Failed to get current brightness: {}", e);
+            level
+        },
+        Err(e) => {
+            warn!(target: log::STATEMACHINE, "Failed to get current brightness: {}", e);
+            level
+        }
     }
+}
 
-    // Flag to indicate if we're currently dimming the backlight
-    let mut dimming = false;
 
-    // Flag to indicate if we currently think the backlight should be on (even
-    // if it's at a requested level of zero)
-    let mut is_active = true;
+// Asks for the backlight to move to a new level, fading unless fading has been
+// disabled for the source of the change. The reason says what caused it
+fn request_level(brightness: &StdSender<brightness::Command>, no_fade: &[FadeSource], source: FadeSource, level: u8, reason: &'static str) {
+    let command = brightness::Command::Set { level, fade: !no_fade.contains(&source), reason };
+    if let Err(e) = brightness.send(command) {
+        warn!(target: log::STATEMACHINE, "Failed to set brightness: {}", e);
+    }
+}
 
-    // How many future key events to ignore
-    let mut ignore_next = 0;
 
-    // Loop forever
-    loop {
-        // Default to "dimming" timeout
-        let mut timeout_time = 100;
-
-        // If we're not dimming...
-        if !dimming {
-            // ...and we're inactive, set a long timeout
-            if !is_active {
-                timeout_time = 3600000;
-            // ...and we're active, set the timeout to what the user requested
-            } else {
-                timeout_time = (args.timeout * 1000.0) as u64;
-            }
-        }
+// Gives the command line with anything it doesn't give filled in from the
+// config file and the fragments in conf.d, which are read over it
+fn all_args() -> Result<Vec<OsString>, BlError> {
+    let cli_args: Vec<OsString> = std::env::args_os().collect();
 
-        // Set up our tasks
-        let recv_task = r.recv();
-        let timeout_task = create_timeout(Duration::from_millis(timeout_time));
+    // The command line may not be complete without the config file, so this
+    // first pass is only to find the file and what was given
+    let given = Cli::command().ignore_errors(true).get_matches_from(&cli_args);
+    let path = given.get_one::<PathBuf>("config").cloned().or_else(config::default_path);
+    let paths: Vec<PathBuf> = path.into_iter().chain(config::fragments(Path::new(config::FRAGMENT_DIR))).collect();
+    if paths.is_empty() {
+        return Ok(cli_args);
+    }
 
-        // Wait for one of the tasks to complete
-        tokio::select! {
-            // Keypress
-            lock = recv_task => {
-                // Ignore events if we're asked to
-                if ignore_next > 0 {
-                    ignore_next = ignore_next - 1;
-                } else {
-                    // If the result back was a lockscreen (and dim-on-locking is enabled)
-                    if args.lock && lock.unwrap() == 1 {
-                        // Only trigger if active otherwise we could set the requested
-                        // level whilst dimming
-                        if is_active {
-                            // Ignore the next couple of events (so the Meta or L up doesn't trigger the backlight)
-                            ignore_next = 2;
+    let config_args = config::to_args(&paths, &Cli::command(), &given)
+        .map_err(|e| BlError::Config(format!("could not read config file {}", e)))?;
+    let mut all_args = cli_args;
+    all_args.splice(1..1, config_args);
+    Ok(all_args)
+}
 
-                            // Take us to dimming
-                            is_active = false;
-                            requested_level = get_updated_requested_level(&mut handle, level);
-                            level = requested_level;
 
-                            // Flag up that we're currently dimming
-                            if requested_level > 0 {
-                                dimming = true;
-                            }
-                        }
-                    } else {
-                        // Key was pressed, stop dimming, set active and change the
-                        // backlight level if it's not current what the user set it to
-                        is_active = true;
-                        dimming = false;
-                        if level != requested_level {
-                            level = requested_level;
-                            set_backlight_level(&mut handle, level);
-                        }
-                    }
-                }
-            },
+// Parses the command line and config file, exiting with the usage if they
+// don't make sense
+fn parse_args() -> Result<Cli, BlError> {
+    Ok(Cli::parse_from(all_args()?))
+}
 
-            // Timeout
-            _ = timeout_task => {
-                // No key has been pressed recently, so we're definitely now
-                // inactive (and possibly already dimming)
 
-                // If we're starting to dim and currently active (otherwise
-                // we'll trigger a dim when we're already dimmed which will
-                // set requested_level to zero!)
-                if is_active && !dimming {
-                    // We're on longer active
-                    is_active = false;
+// Parses the command line and config file again, e.g. after the config file
+// has been edited. Mistakes are returned rather than exiting
+fn reparse_args() -> Result<Cli, BlError> {
+    Cli::try_parse_from(all_args()?).map_err(|e| {
+        let message = e.to_string();
+        BlError::Config(message.lines().next().unwrap_or_default().trim_start_matches("error: ").to_string())
+    })
+}
 
-                    requested_level = get_updated_requested_level(&mut handle, level);
-                    level = requested_level;
 
-                    // Flag up that we're currently dimming
-                    if requested_level > 0 {
-                        dimming = true;
-                    }
-                }
+// Works out what to log from --log-filter, or failing that --verbose and
+// --quiet
+fn log_filter(args: &Cli) -> Targets {
+    if let Some(filter) = &args.log_filter {
+        return filter.clone();
+    }
 
-                // Update the backlight level
-                if dimming && level != 0 {
-                    // The brightness is non-linear so change the dimming speed
-                    // based on the current level
-                    if level >= 10 {
-                        level = level - 2;
-                    } else if level >= 1 {
-                        level = level - 1;
-                    }
+    let level = match (args.verbose, args.quiet) {
+        (0, 0) => LevelFilter::INFO,
+        (1, _) => LevelFilter::DEBUG,
+        (_, 0) => LevelFilter::TRACE,
+        (_, 1) => LevelFilter::WARN,
+        _ => LevelFilter::ERROR
+    };
+    Targets::new().with_default(level)
+}
 
-                    // Change the level if we've got something valid
-                    if level <= 50 {
-                        set_backlight_level(&mut handle, level);
-                    }
 
-                    // If we've reached level zero, we can stop dimming
-                    if level == 0 {
-                        dimming = false;
-                    }
-                }
+// Entry point. Failures are logged and turned into an exit code that says
+// what kind of failure it was
+#[tokio::main(worker_threads=2)]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // Logging isn't set up until the arguments have been parsed
+            if tracing::dispatcher::has_been_set() {
+                error!("Error: {}", e);
+            } else {
+                eprintln!("Error: {}", e);
+            }
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+
+// Runs whichever command was asked for, or the daemon
+async fn run() -> Result<(), BlError> {
+    // Parse the command line arguments and config file
+    let mut args = parse_args()?;
+
+    // Send our output to a log file if asked
+    if let Some(path) = &args.log_file {
+        let max_age = args.log_max_age.map(|hours| Duration::from_secs_f64(hours * 3600.0));
+        log::init_file(path.clone(), args.log_max_size, max_age, args.log_keep)
+            .map_err(|e| BlError::io(format!("could not open log file {}", path.display()), e))?;
+    }
+    log::init(log_filter(&args), args.log_format == LogFormat::Json);
+
+    // Talking to the daemon doesn't need the device, so do it first
+    let status_path = args.status_file.clone().unwrap_or_else(status::default_path);
+    let control_path = args.control_socket.clone().unwrap_or_else(control::default_path);
+    let control_paths = control::client_paths(args.control_socket.as_deref());
+    match &args.command {
+        Some(Commands::WaitIdle { idle_for, max_wait }) => {
+            let max_wait = max_wait.map(Duration::from_secs_f64);
+            if !status::wait_idle(&status_path, Duration::from_secs_f64(*idle_for), max_wait) {
+                warn!("Timed out waiting for the backlight to be dimmed");
+                std::process::exit(1);
+            }
+            return Ok(());
+        },
+        Some(Commands::SimulateActivity) => {
+            control::send_command(&control::find_path(&control_paths), "activity")?;
+            return Ok(());
+        },
+        Some(Commands::Monitor { device }) => {
+            let event_path = match device {
+                Some(device) => device.clone(),
+                None => get_keyboard_event(args.input_device.as_deref())?
+            };
+            monitor::run(&event_path).map_err(|e| BlError::io(format!("stopped monitoring {}", event_path), e))?;
+            return Ok(());
+        },
+        Some(Commands::LearnLock { device }) => {
+            let event_path = match device {
+                Some(device) => device.clone(),
+                None => get_keyboard_event(args.input_device.as_deref())?
+            };
+            let keys = monitor::learn_combo(&event_path)
+                .map_err(|e| BlError::io(format!("failed to learn lock combination from {}", event_path), e))?;
+            let names: Vec<String> = keys.iter().map(|k| monitor::key_name(*k)).collect();
+            println!("Use this combination with --lock-keys {}", names.join(","));
+            println!("or lock_keys = [\"{}\"] in the config file", names.join("\", \""));
+            return Ok(());
+        },
+        Some(Commands::Status) => {
+            // Either is enough to say something, but if neither is there the
+            // daemon probably isn't running
+            let dimmed = status::read(&status_path);
+            let idle_time = match control::send_command(&control::find_path(&control_paths), "idle-time") {
+                Err(e) if dimmed.is_none() => return Err(e),
+                idle_time => idle_time.ok()
+            };
+            print_status(dimmed, idle_time.as_deref());
+            return Ok(());
+        },
+        Some(Commands::ListDevices { json }) => {
+            list::print_usb_devices(args.vendor_id, &known_product_ids(&args), *json)?;
+            return Ok(());
+        },
+        Some(Commands::ListInputs { json }) => {
+            let keyboards = get_keyboard_events(args.input_device.as_deref()).unwrap_or_default();
+            let others = [
+                ("touch", devices::find_touch_devices().unwrap_or_default()),
+                ("touchpad", devices::find_touchpads().unwrap_or_default()),
+                ("mouse", devices::find_mice().unwrap_or_default()),
+                ("gamepad", devices::find_gamepads().unwrap_or_default())
+            ];
+            list::print_input_devices(&keyboards, &others, *json)?;
+            return Ok(());
+        },
+        Some(Commands::InstallService { user, enable, .. }) => {
+            let path = service::install("install-service", *user, *enable, args.dbus.is_some())?;
+            info!("Installed the service as {}", path.display());
+            return Ok(());
+        },
+        Some(Commands::InstallUdevRules { uaccess }) => {
+            let product_ids = match args.product_id {
+                Some(product_id) => vec![product_id],
+                None => known_product_ids(&args)
+            };
+            let path = udev::install(args.vendor_id, &product_ids, *uaccess)?;
+            info!("Installed the udev rules as {}", path.display());
+            return Ok(());
+        },
+        Some(Commands::IdleTime { json }) => {
+            let answer = control::send_command(&control::find_path(&control_paths), "idle-time")?;
+            print_idle_time(&answer, *json);
+            return Ok(());
+        },
+        _ => ()
+    }
+
+    // While the daemon is running it has the device, so the level is read and
+    // set through it: over the user's own daemon's control socket or a system
+    // daemon's, or failing those over D-Bus. Without one, we go to the device
+    // ourselves below
+    let mut daemon_status = None;
+    let mut dbus_daemon = None;
+    if let Some(Commands::Get | Commands::Set { .. } | Commands::Cycle { .. } | Commands::CalibrateAls) = &args.command {
+        for path in &control_paths {
+            if let Some(status) = control::send_json_command(path, &json!({ "cmd": "status" }))? {
+                daemon_status = Some((path.clone(), status));
+                break;
             }
         }
+        if daemon_status.is_none() {
+            dbus_daemon = dbus::find_service();
+        }
+    }
+    let daemon_level = match (&daemon_status, &dbus_daemon) {
+        (Some((_, status)), _) => Some(status["level"].as_u64().unwrap_or(0)),
+        (_, Some((_, level))) => Some(*level as u64),
+        _ => None
+    };
+    if let Some(daemon_level) = daemon_level {
+        let set_level = |level: u8| match (&daemon_status, &dbus_daemon) {
+            (Some((path, _)), _) => control::send_json_command(path, &json!({ "cmd": "set", "level": level })).map(|_| ()),
+            (_, Some((proxy, _))) => dbus::set_level(proxy, level),
+            _ => Ok(())
+        };
+        match &args.command {
+            Some(Commands::Get) => println!("{}", daemon_level),
+            Some(Commands::Set { level }) => set_level(*level)?,
+            Some(Commands::Cycle { levels }) => match cycle::next_level(levels) {
+                Some(level) => {
+                    info!("Setting backlight level to {}", level);
+                    set_level(level)?;
+                },
+                None => info!("No preset levels to cycle through")
+            },
+            // The daemon would change the level from under us
+            Some(Commands::CalibrateAls) => {
+                return Err(BlError::AlreadyRunning(String::from("the daemon is running, stop it while calibrating")));
+            },
+            _ => ()
+        }
+        return Ok(());
+    }
+
+    // Two daemons would fight over the keyboard, so make sure we're the only
+    // one before touching it. Other commands mustn't go to the device
+    // underneath a daemon we couldn't reach either
+    let _instance_lock = match args.command {
+        Some(Commands::Daemon) | None => {
+            let path = args.lock_file.clone().unwrap_or_else(instance::default_path);
+            Some(instance::lock(&path)?)
+        },
+        _ => {
+            for path in instance::client_paths(args.lock_file.as_deref()) {
+                instance::check(&path)?;
+            }
+            None
+        }
+    };
+
+    // Saving and restoring the state works on the controller's effect
+    // channels directly, rather than through the backlight
+    if let Some(Commands::DumpState { file } | Commands::LoadState { file }) = &args.command {
+        if !matches!(args.backend, Backend::Usb | Backend::Hidraw) {
+            return Err(BlError::Config(String::from("saving and restoring state needs the ITE controller")));
+        }
+
+        let (mut handle, _, desc, _) = open_controller(&args)?;
+        match &args.command {
+            Some(Commands::DumpState { .. }) => {
+                snapshot::dump(&mut handle, &desc, file)?;
+                info!("Saved controller state to {}", file.display());
+            },
+            _ => {
+                snapshot::load(&mut handle, &desc, file)?;
+                info!("Restored controller state from {}", file.display());
+            }
+        }
+        return Ok(());
+    }
+
+    // Open the backlight
+    let mut backlight = open_backlight(&args)?;
+    let mut caps = backlight.capabilities();
+
+    // Run any one-shot command instead of the daemon
+    match &args.command {
+        Some(Commands::Get) => {
+            println!("{}", backlight.get()?);
+            return Ok(());
+        },
+        Some(Commands::Set { level }) => {
+            backlight.set((*level).min(caps.max_level))?;
+            return Ok(());
+        },
+        Some(Commands::Cycle { levels }) => {
+            match cycle::next_level(levels) {
+                Some(level) => {
+                    let level = level.min(caps.max_level);
+                    info!("Setting backlight level to {}", level);
+                    backlight.set(level)?;
+                },
+                None => info!("No preset levels to cycle through")
+            }
+            return Ok(());
+        },
+        Some(Commands::Bench { count }) => {
+            bench::run(backlight.as_mut(), *count);
+            return Ok(());
+        },
+        Some(Commands::CalibrateAls) => {
+            let sensor = match &args.als_device {
+                Some(sensor) => sensor.clone(),
+                None => als::find_sensor()?
+            };
+            let curve = calibrate::run(backlight.as_mut(), &sensor)?;
+            if curve.is_empty() {
+                println!("Nothing was recorded, so the config file was left alone");
+                return Ok(());
+            }
+
+            let points: Vec<String> = curve.iter().map(|(lux, level)| format!("{}={}", lux, level)).collect();
+            let path = args.config.clone().unwrap_or_else(config::default_write_path);
+            config::set_option(&path, "als_curve", points.iter().collect::<toml_edit::Array>())
+                .map_err(|e| BlError::Config(format!("could not write config file {}: {}", path.display(), e)))?;
+            println!("Wrote the curve {} to {}", points.join(", "), path.display());
+            println!("Turn it on with --als-auto, or als_auto = true in the config file");
+            return Ok(());
+        },
+        Some(Commands::DumpState { .. }) | Some(Commands::LoadState { .. }) |
+        Some(Commands::Daemon) | Some(Commands::Status) | Some(Commands::WaitIdle { .. }) | Some(Commands::SimulateActivity) | Some(Commands::IdleTime { .. }) | Some(Commands::Monitor { .. }) | Some(Commands::ListDevices { .. }) | Some(Commands::ListInputs { .. }) | Some(Commands::LearnLock { .. }) | Some(Commands::InstallService { .. }) | Some(Commands::InstallUdevRules { .. }) | None => ()
+    }
+
+    // Read the current brightness level
+    let mut requested_level = match backlight.get() {
+        Ok(l) => l,
+        Err(e) => {
+            warn!("Failed to get current brightness: {}", e);
+            caps.max_level
+        }
+    };
+    info!(brightness = requested_level, "Initial backlight level is {}", requested_level);
+
+    // Create the channel that all of the activity sources post to
+    let (s, mut r) = mpsc::unbounded_channel();
+
+    // Start reading from the keyboards, so that typing on an external one
+    // keeps the backlight on too
+    if args.idle_sources.contains(&IdleSource::Keyboard) {
+        // The lock combination is only looked for if it's one of the ways
+        // of noticing locks
+        let lock_keys = match args.lock_source.contains(&LockSource::Keys) {
+            true => args.lock_keys.clone(),
+            false => Vec::new()
+        };
+        let active = Arc::new(Mutex::new(Vec::new()));
+        attach_keyboards(args.input_device.as_deref(), &lock_keys, &active, &s)?;
+
+        // Pick up keyboards that are plugged in or reconnect later. Inside
+        // --chroot there's no /dev/input to open them from
+        if args.chroot.is_none() {
+            let (input_device, s) = (args.input_device.clone(), s.clone());
+            let watcher = hotplug::spawn_watcher("/dev/input", "keyboard-watcher", move || {
+                if let Ok(count) = attach_keyboards(input_device.as_deref(), &lock_keys, &active, &s) {
+                    if count > 0 {
+                        info!("Attached {} new keyboard(s)", count);
+                    }
+                }
+            });
+            if let Err(e) = watcher {
+                warn!("Failed to watch for new keyboards: {}", e);
+            }
+        }
+    }
+
+    // Start listening to acpid
+    if args.idle_sources.contains(&IdleSource::Acpid) {
+        acpid::spawn_reader(args.acpid_socket.clone(), s.clone())
+            .map_err(|e| BlError::io("could not start the acpid thread", e))?;
+    }
+
+    // Start asking the Wayland compositor about activity
+    if args.idle_sources.contains(&IdleSource::Wayland) {
+        wayland::spawn_idle_watcher(s.clone())
+            .map_err(|e| BlError::io("could not start the Wayland thread", e))?;
+    }
+
+    // Start asking the X server about activity
+    if args.idle_sources.contains(&IdleSource::X11) {
+        x11::spawn_idle_poller(s.clone())
+            .map_err(|e| BlError::io("could not start the X11 thread", e))?;
+    }
+
+    // Start watching GNOME's idle monitor
+    if args.idle_sources.contains(&IdleSource::Mutter) {
+        mutter::spawn_idle_watcher(s.clone())
+            .map_err(|e| BlError::io("could not start the Mutter thread", e))?;
+    }
+
+    // Start reading from any touchscreens, tablets, gamepads, mice and
+    // touchpads
+    let other_sources = [
+        (IdleSource::Touch, ActivitySource::Touch, devices::find_touch_devices as fn() -> Result<Vec<String>, BlError>),
+        (IdleSource::Gamepad, ActivitySource::Gamepad, devices::find_gamepads),
+        (IdleSource::Mouse, ActivitySource::Mouse, devices::find_mice),
+        (IdleSource::Touchpad, ActivitySource::Touchpad, devices::find_touchpads)
+    ];
+    for (idle_source, source, find_devices) in other_sources {
+        if !args.idle_sources.contains(&idle_source) {
+            continue;
+        }
+
+        let found = find_devices()?;
+        if found.is_empty() {
+            warn!("No {:?} devices found", source);
+        }
+
+        for device in found {
+            info!(device = %device, "Found {:?} device at {}", source, device);
+            if let Err(e) = devices::spawn_reader(device.clone(), source, s.clone()) {
+                warn!(device = %device, "Failed to read {:?} device {}: {}", source, device, e);
+            }
+        }
+    }
+
+    // Start reading the lid switch
+    if args.lid {
+        let found = lid::find_lid_switches()?;
+        if found.is_empty() {
+            warn!("No lid switch found");
+        }
+
+        for device in found {
+            if let Err(e) = lid::spawn_reader(device.clone(), s.clone()) {
+                warn!(device = %device, "Failed to read lid switch {}: {}", device, e);
+            }
+        }
+    }
+
+    // Start watching for the display turning off
+    if let Some(source) = args.display_power {
+        let spawned = match source {
+            DisplayPowerSource::Dpms => x11::spawn_dpms_poller(s.clone()),
+            DisplayPowerSource::Wayland => wayland::spawn_power_watcher(s.clone()),
+            DisplayPowerSource::Drm => display::spawn_power_poller(s.clone())
+        };
+        spawned.map_err(|e| BlError::io("could not start watching for the display turning off", e))?;
+    }
+
+    // Turn the backlight on
+    let mut level = requested_level;
+    if level == 0 {
+        info!("Initial level was 0, resetting to {}", caps.max_level);
+        level = caps.max_level;
+    }
+    if let Err(e) = backlight.set(level) {
+        warn!("Failed to set backlight level: {}", e);
+    }
+
+    // If the color is given, set it on the device
+    if (args.red > 0 || args.green > 0 || args.blue > 0) && !caps.color {
+        warn!("Controller does not support setting the color, ignoring it");
+    } else if args.red > 0 || args.green > 0 || args.blue > 0 {
+        info!("Setting color to {}, {}, {}", args.red, args.green, args.blue);
+        if let Err(e) = backlight.set_color(args.red, args.green, args.blue) {
+            warn!("Failed to set backlight color: {}", e);
+        }
+    }
+
+    // Start watching for the user's phone
+    if let Some(address) = &args.phone {
+        let interval = Duration::from_secs_f64(args.phone_interval);
+        presence::spawn_bluetooth(address.clone(), interval, args.phone_min_rssi, s.clone())
+            .map_err(|e| BlError::io("could not start the Bluetooth presence thread", e))?;
+    }
+
+    // Start running the presence check
+    if let Some(command) = &args.presence_command {
+        let interval = Duration::from_secs_f64(args.presence_interval);
+        presence::spawn_command(command.clone(), interval, s.clone())
+            .map_err(|e| BlError::io("could not start the presence thread", e))?;
+    }
+
+    // Start watching for the bell
+    if args.visual_bell {
+        bell::spawn_x11(s.clone())
+            .map_err(|e| BlError::io("could not start the bell thread", e))?;
+    }
+
+    // Start matching the color of the screen
+    if args.screen_color && !caps.color {
+        warn!("Controller does not support setting the color, not matching the screen");
+    } else if args.screen_color {
+        let interval = Duration::from_secs_f64(args.screen_color_interval);
+        screen::spawn_x11(interval, s.clone())
+            .map_err(|e| BlError::io("could not start the screen color thread", e))?;
+    }
+
+    // Start watching the ambient light
+    if args.als_gate.is_some() || args.als_auto {
+        let sensor = match &args.als_device {
+            Some(sensor) => sensor.clone(),
+            None => als::find_sensor()?
+        };
+        info!("Using ambient light sensor at {}", sensor.display());
+
+        let interval = Duration::from_secs_f64(args.als_interval);
+        if let Some(threshold) = args.als_gate {
+            als::spawn_gate(sensor.clone(), threshold, interval, s.clone())
+                .map_err(|e| BlError::io("could not start the ambient light thread", e))?;
+        }
+        if args.als_auto {
+            let curve = match args.als_curve.is_empty() {
+                true => vec![(0.0, caps.max_level), (50.0, caps.max_level / 2), (300.0, 0)],
+                false => args.als_curve.iter().map(|&(lux, level)| (lux, level.min(caps.max_level))).collect()
+            };
+            als::spawn_auto(sensor, curve, interval, s.clone())
+                .map_err(|e| BlError::io("could not start the ambient light thread", e))?;
+        }
+    }
+
+    // Start following the display's brightness
+    if args.follow_display {
+        let backlight = match &args.display_backlight {
+            Some(backlight) => backlight.clone(),
+            None => display::find_backlight()?
+        };
+        display::spawn_follower(backlight, caps.max_level, s.clone())
+            .map_err(|e| BlError::io("could not start following the display backlight", e))?;
+    }
+
+    // Start watching for games
+    if args.game_mode {
+        process::spawn_game_watch(args.game_processes.clone(), Duration::from_secs(5), s.clone())
+            .map_err(|e| BlError::io("could not start the game watch thread", e))?;
+    }
+
+    // Start watching for processes that stop us dimming
+    if !args.inhibit_processes.is_empty() {
+        process::spawn_inhibit_watch(args.inhibit_processes.clone(), Duration::from_secs(5), s.clone())
+            .map_err(|e| BlError::io("could not start the process watch thread", e))?;
+    }
+
+    // Start watching for being on battery
+    if watches_power(&args) {
+        let monitor = match args.power_monitor {
+            PowerMonitor::Sysfs => power::spawn_monitor(args.low_power, s.clone()),
+            PowerMonitor::Upower => upower::spawn_monitor(args.low_power, s.clone())
+        };
+        monitor.map_err(|e| BlError::io("could not start the power monitor thread", e))?;
+    }
+
+    // Start following the session being locked
+    if args.lock && args.lock_source.contains(&LockSource::Logind) {
+        logind::spawn_lock_watcher(s.clone())
+            .map_err(|e| BlError::io("could not start the logind thread", e))?;
+    }
+    if args.lock && args.lock_source.contains(&LockSource::Screensaver) {
+        screensaver::spawn_watcher(s.clone())
+            .map_err(|e| BlError::io("could not start the screensaver thread", e))?;
+    }
+
+    // Give the controller back to its driver while the system sleeps, and
+    // open it again afterwards, as the handle we had won't work any more.
+    // Inside --chroot it can't be opened again, so it's kept instead
+    if matches!(args.backend, Backend::Usb | Backend::Hidraw) && args.chroot.is_none() {
+        logind::spawn_sleep_watcher(s.clone())
+            .map_err(|e| BlError::io("could not start the sleep thread", e))?;
+    }
+
+    // Start following the system's dark mode
+    if args.dark_mode_level.is_some() || args.dark_mode_timeout.is_some() {
+        portal::spawn_dark_mode(s.clone())
+            .map_err(|e| BlError::io("could not start the dark mode thread", e))?;
+    }
+
+    // Start watching the temperature
+    if let Some(limit) = args.thermal_limit {
+        thermal::spawn_cap(args.thermal_sensor.clone(), limit, args.thermal_level, Duration::from_secs(5), s.clone())
+            .map_err(|e| BlError::io("could not start the thermal thread", e))?;
+    }
+
+    // Start showing the charging state
+    if args.charge_indicator && !caps.color {
+        warn!("Controller does not support setting the color, not showing the charging state");
+    } else if args.charge_indicator {
+        charge::spawn(args.charge_color, s.clone())
+            .map_err(|e| BlError::io("could not start the charging indicator thread", e))?;
+    }
+
+    // Start listening for commands from other programs
+    let control_group = match &args.control_group {
+        Some(group) => Some(privileges::lookup_group(group)?),
+        None => None
+    };
+    match control::spawn_listener(control_path.clone(), control_group, s.clone()) {
+        Ok(_) => (),
+        Err(e) => warn!("Failed to listen for commands on {}: {}", control_path.display(), e)
+    }
+
+    // Start offering control over D-Bus
+    let mut dbus_signals = match args.dbus {
+        Some(bus) => Some(dbus::spawn_service(bus == Bus::System, s.clone())
+            .map_err(|e| BlError::io("could not start the D-Bus thread", e))?),
+        None => None
+    };
+
+    // Start following another instance
+    if let (Some(addr), Some(key)) = (args.follow, &args.follow_key) {
+        follow::spawn_follower(addr, key, args.follow_from.clone(), s.clone())
+            .map_err(|e| BlError::io("could not start the follower thread", e))?;
+    }
+
+    // Schedule the daily sunrise
+    if let Some(at) = args.sunrise {
+        let duration = Duration::from_secs_f64(args.sunrise_duration * 60.0);
+        let target = args.sunrise_level.unwrap_or(caps.max_level).min(caps.max_level);
+        sunrise::spawn(at, duration, target, s.clone())
+            .map_err(|e| BlError::io("could not start the sunrise thread", e))?;
+    }
+
+    // Start following the schedule
+    if !args.schedule.is_empty() {
+        schedule::spawn(args.schedule.clone(), s.clone())
+            .map_err(|e| BlError::io("could not start the schedule thread", e))?;
+    }
+
+    // Follow the sun to cap the level after dark
+    if let (Some(night_level), Some(location)) = (args.night_level, args.location) {
+        night::spawn(location, night_level.min(caps.max_level), caps.max_level, s.clone())
+            .map_err(|e| BlError::io("could not start the night thread", e))?;
+    }
+
+    // Set up sending our state to any followers
+    let mut leader = match (args.lead, &args.follow_key) {
+        (Some(addr), Some(key)) => match follow::Leader::new(addr, key) {
+            Ok(leader) => {
+                info!("Sending state to followers at {}", addr);
+                Some(leader)
+            },
+            Err(e) => return Err(BlError::io("could not create the leader socket", e))
+        },
+        _ => None
+    };
+
+    // The color currently set, which is passed on to followers
+    let mut color = match args.red > 0 || args.green > 0 || args.blue > 0 {
+        true => Some((args.red, args.green, args.blue)),
+        false => None
+    };
+
+    // The level to put back if anything panics, which is kept up to date
+    // with the level the user wants
+    let restore_level = Arc::new(AtomicU8::new(level));
+
+    // Hand the device over to the brightness thread, which makes all further
+    // changes to it
+    let brightness = brightness::spawn(backlight, caps.max_level, level, color.filter(|_| caps.color), restore_level.clone())
+        .map_err(|e| BlError::io("could not start the brightness thread", e))?;
+    brightness::install_panic_guard(brightness.clone(), restore_level.clone());
+
+    // Flag to indicate if we currently think the backlight should be on (even
+    // if it's at a requested level of zero)
+    let mut is_active = true;
+
+    // How many future key events to ignore
+    let mut ignore_next = 0;
+
+    // Flag to indicate if a presence check thinks someone is at the machine
+    let mut someone_present = false;
+
+    // Flag to indicate if the room is too bright for dimming to matter
+    let mut room_bright = false;
+
+    // Flag to indicate if a game is running, which keeps the lighting as it is
+    let mut game_running = false;
+
+    // Flag to indicate if one of the inhibiting processes is running
+    let mut process_inhibit = false;
+
+    // Flags to indicate if another program has asked us not to dim on the
+    // timeout or on the lock combination
+    let mut inhibit_idle = false;
+    let mut inhibit_lock = false;
+
+    // When dimming on the timeout is allowed again, if it's been held off
+    // for a while
+    let mut inhibit_until: Option<Instant> = None;
+
+    // When to dim for a lock that is waiting out its grace period
+    let mut lock_deadline: Option<Instant> = None;
+
+    // Flag to indicate if a sunrise is bringing the backlight up while dimmed
+    let mut sunrise_running = false;
+
+    // Whether the lid is closed, which keeps the backlight off whatever else
+    // happens
+    let mut lid_closed = false;
+
+    // Whether the display is off, which does the same
+    let mut display_off = false;
+
+    // Whether the status file last said we were dimmed
+    let mut published_dimmed = None;
+
+    // When to dim if there's no more activity, and what to go back to when
+    // leaving dark mode
+    let mut timeout = Duration::from_secs_f64(args.timeout);
+    let mut light_timeout = timeout;
+
+    // Whether the system is in dark mode, and the level the user had before
+    // switching to it, to go back to
+    let mut dark_mode = false;
+    let mut light_level: Option<u8> = None;
+
+    // Whether we're on battery, and the caps the temperature, the schedule and
+    // the night put on the level. The lowest of them and the battery's is what
+    // the brightness thread enforces
+    let mut on_battery = false;
+    let mut battery_level: Option<f64> = None;
+    let mut thermal_cap: Option<u8> = None;
+    let mut schedule_cap: Option<u8> = None;
+    let mut night_cap: Option<u8> = None;
+    let mut idle_deadline = Instant::now() + timeout;
+
+    // When the user was last active, for idle-time queries
+    let mut last_activity = Instant::now();
+
+    // When the backlight was turned off, and whether the screensaver has
+    // been started since
+    let mut off_since: Option<Instant> = None;
+    let mut screensaver_running = false;
+
+    // Stop cleanly when asked to, rather than leaving the backlight dimmed
+    let mut sigterm = signal(SignalKind::terminate()).map_err(|e| BlError::io("could not handle SIGTERM", e))?;
+    let mut sigint = signal(SignalKind::interrupt()).map_err(|e| BlError::io("could not handle SIGINT", e))?;
+
+    // SIGUSR1 and SIGUSR2 step the level up and down
+    let mut sigusr1 = signal(SignalKind::user_defined1()).map_err(|e| BlError::io("could not handle SIGUSR1", e))?;
+    let mut sigusr2 = signal(SignalKind::user_defined2()).map_err(|e| BlError::io("could not handle SIGUSR2", e))?;
+    let mut step = args.step.unwrap_or((caps.max_level / 10).max(1));
+
+    // SIGHUP reloads the config file
+    let mut sighup = signal(SignalKind::hangup()).map_err(|e| BlError::io("could not handle SIGHUP", e))?;
+
+    // Saving the config file or a fragment reloads it too. Inside --chroot it
+    // can't be read again anyway
+    if args.chroot.is_none() {
+        if let Some(path) = args.config.clone().or_else(config::default_path) {
+            let s = s.clone();
+            let watcher = hotplug::spawn_file_watcher(&path, "config-watcher", move || {
+                let _ = s.send(Event::Reload);
+            });
+            if let Err(e) = watcher {
+                warn!("Failed to watch the config file {}: {}", path.display(), e);
+            }
+        }
+
+        let fragment_dir = Path::new(config::FRAGMENT_DIR);
+        if fragment_dir.is_dir() {
+            let s = s.clone();
+            let is_fragment = |name: &OsStr| Path::new(name).extension().is_some_and(|ext| ext == "toml");
+            let watcher = hotplug::spawn_dir_watcher(fragment_dir, "fragment-watcher", is_fragment, move || {
+                let _ = s.send(Event::Reload);
+            });
+            if let Err(e) = watcher {
+                warn!("Failed to watch {}: {}", fragment_dir.display(), e);
+            }
+        }
+    }
+
+    // Everything is open, so let systemd know we've started, and have it
+    // restart us if we stop answering
+    if let Err(e) = notify::send("READY=1") {
+        warn!("Failed to notify systemd: {}", e);
+    }
+    if let Some(interval) = notify::watchdog_interval() {
+        notify::spawn_watchdog(interval, s.clone())
+            .map_err(|e| BlError::io("could not start the watchdog thread", e))?;
+    }
+
+    // Nothing else needs root, so stop being it, keeping the directory of
+    // the status file and control socket writable. Inside --chroot neither
+    // is there to write to, so the status file isn't published at all
+    if let Some(user) = &args.run_as {
+        let writable: Vec<&Path> = match args.chroot {
+            Some(_) => Vec::new(),
+            None => [status_path.parent(), control_path.parent()].into_iter().flatten().collect()
+        };
+        privileges::drop_to(user, args.chroot.as_deref(), &writable)?;
+    }
+    let publish_status = args.chroot.is_none();
+    if args.seccomp {
+        seccomp::install()?;
+    }
+
+    // What systemd was last told about the backlight
+    let mut notified_state = None;
+
+    // Loop until we're stopped
+    loop {
+        // Let anything waiting on us know if we've changed state. A failed
+        // write isn't retried so that it's only logged once
+        if publish_status && published_dimmed != Some(!is_active) {
+            if let Err(e) = status::write(&status_path, !is_active) {
+                warn!(target: log::STATEMACHINE, "Failed to write status to {}: {}", status_path.display(), e);
+            }
+            published_dimmed = Some(!is_active);
+        }
+
+        // Keep track of what to put back if something panics
+        restore_level.store(requested_level, Ordering::Relaxed);
+
+        // Let any followers know what we're doing
+        if let Some(leader) = &mut leader {
+            leader.send(follow::State { level: requested_level, dimmed: !is_active, color });
+        }
+
+        // And anything watching us on D-Bus
+        if let Some(dbus_signals) = &mut dbus_signals {
+            dbus_signals.send(level, !is_active);
+        }
+
+        // And systemd, for `systemctl status`
+        if notified_state != Some((level, is_active)) {
+            let status = match is_active {
+                true => format!("STATUS=Backlight at level {}", level),
+                false => format!("STATUS=Dimmed to level {}", level)
+            };
+            if let Err(e) = notify::send(&status) {
+                debug!("Failed to notify systemd: {}", e);
+            }
+            notified_state = Some((level, is_active));
+        }
+
+        // If we're inactive, set a long timeout, otherwise set the timeout to
+        // what the user requested
+        let mut timeout_time = match is_active {
+            true => idle_deadline.saturating_duration_since(Instant::now()).as_millis() as u64,
+            false => 3600000
+        };
+
+        // Keep track of how long the backlight has been off for the screensaver
+        if is_active || level > 0 {
+            off_since = None;
+            screensaver_running = false;
+        } else if off_since.is_none() {
+            off_since = Some(Instant::now());
+        }
+        let screensaver_at = match (args.screensaver, off_since, screensaver_running || lid_closed || display_off) {
+            (Some(minutes), Some(since), false) => Some(since + Duration::from_secs_f64(minutes * 60.0)),
+            _ => None
+        };
+
+        // Wake up in time to start the screensaver
+        if let Some(at) = screensaver_at {
+            let remaining = at.saturating_duration_since(Instant::now()).as_millis() as u64;
+            timeout_time = timeout_time.min(remaining);
+        }
+
+        // Wake up in time to dim for any pending lock
+        if let Some(deadline) = lock_deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now()).as_millis() as u64;
+            timeout_time = timeout_time.min(remaining);
+        }
+
+        // Set up our tasks
+        let recv_task = r.recv();
+        let timeout_task = create_timeout(Duration::from_millis(timeout_time));
+
+        // Wait for one of the tasks to complete
+        tokio::select! {
+            // Keypress or other activity
+            event = recv_task => {
+                // Mirror the state of the instance we're following
+                if let Some(Event::Follow(state)) = event {
+                    if state.color.is_some() && state.color != color && caps.color {
+                        color = state.color;
+                        if let Some((r, g, b)) = color {
+                            if let Err(e) = brightness.send(brightness::Command::Color { r, g, b }) {
+                                warn!(target: log::STATEMACHINE, "Failed to set color: {}", e);
+                            }
+                        }
+                    }
+
+                    requested_level = state.level;
+                    if state.dimmed && is_active {
+                        is_active = false;
+                        level = 0;
+                        request_level(&brightness, &args.no_fade, FadeSource::Idle, level, "follow");
+                    } else if !state.dimmed && !lid_closed && !display_off && (!is_active || level != requested_level) {
+                        is_active = true;
+                        level = requested_level;
+                        request_level(&brightness, &args.no_fade, FadeSource::Activity, level, "follow");
+                    }
+                    continue;
+                }
+
+                // The bell flashes the backlight without counting as activity
+                if let Some(Event::Bell) = event {
+                    if lid_closed || display_off {
+                        continue;
+                    }
+                    if let Err(e) = brightness.send(brightness::Command::Flash { count: args.flash_count }) {
+                        warn!(target: log::STATEMACHINE, "Failed to flash backlight: {}", e);
+                    }
+                    continue;
+                }
+
+                // The sunrise only brings the backlight up while we're dimmed,
+                // and stops as soon as the user is up and about
+                if let Some(Event::Sunrise(step)) = event {
+                    if !is_active && !lid_closed && !display_off && step == 0 {
+                        info!(target: log::STATEMACHINE, "Starting sunrise");
+                        sunrise_running = true;
+                        if let (Some((r, g, b)), true) = (args.sunrise_color, caps.color) {
+                            if let Err(e) = brightness.send(brightness::Command::Color { r, g, b }) {
+                                warn!(target: log::STATEMACHINE, "Failed to set color: {}", e);
+                            }
+                        }
+                    }
+                    if !is_active && !lid_closed && !display_off && sunrise_running {
+                        level = step;
+                        if let Err(e) = brightness.send(brightness::Command::Set { level, fade: false, reason: "sunrise" }) {
+                            warn!(target: log::STATEMACHINE, "Failed to set backlight level: {}", e);
+                        }
+                    }
+                    continue;
+                }
+
+                // The screen color only changes the color, leaving the
+                // brightness to the idle dimming
+                if let Some(Event::ScreenColor(r, g, b)) = event {
+                    color = Some((r, g, b));
+                    if !sunrise_running {
+                        if let Err(e) = brightness.send(brightness::Command::Color { r, g, b }) {
+                            warn!(target: log::STATEMACHINE, "Failed to set color: {}", e);
+                        }
+                    }
+                    continue;
+                }
+
+                // The charging indicator is shown in place of the normal
+                // color, but the brightness is still left to the dimming
+                if let Some(Event::ChargeColor(charge_color)) = event {
+                    if !sunrise_running {
+                        if let Some((r, g, b)) = charge_color.or(color) {
+                            if let Err(e) = brightness.send(brightness::Command::Color { r, g, b }) {
+                                warn!(target: log::STATEMACHINE, "Failed to set color: {}", e);
+                            }
+                        }
+                    }
+                    continue;
+                }
+
+                // Answer idle time queries from the control socket
+                if let Some(Event::IdleQuery(reply)) = event {
+                    let dim_at = match (is_active, lock_deadline) {
+                        (false, _) => None,
+                        (true, Some(deadline)) => Some(deadline.min(idle_deadline)),
+                        (true, None) => Some(idle_deadline)
+                    };
+                    let dim_in = dim_at.map(|d| d.saturating_duration_since(Instant::now()));
+                    let _ = reply.send((last_activity.elapsed(), dim_in));
+                    continue;
+                }
+
+                // Presence only affects whether we dim on the timeout
+                if let Some(Event::Presence(present)) = event {
+                    someone_present = present;
+                    continue;
+                }
+
+                // As does the ambient light
+                if let Some(Event::AmbientBright(bright)) = event {
+                    room_bright = bright;
+                    continue;
+                }
+
+                // With --als-auto, the ambient light sets the level the user
+                // wants, but doesn't count as activity
+                if let Some(Event::AmbientLevel(ambient_level)) = event {
+                    requested_level = ambient_level;
+                    if is_active && level != requested_level {
+                        level = requested_level;
+                        request_level(&brightness, &args.no_fade, FadeSource::Activity, level, "als");
+                    }
+                    continue;
+                }
+
+                // As does the display's brightness
+                if let Some(Event::DisplayLevel(display_level)) = event {
+                    requested_level = display_level;
+                    if is_active && level != requested_level {
+                        level = requested_level;
+                        request_level(&brightness, &args.no_fade, FadeSource::Activity, level, "display");
+                    }
+                    continue;
+                }
+
+                // And whether a game is running, which also stops the lock
+                // combination from dimming
+                if let Some(Event::GameMode(running)) = event {
+                    game_running = running;
+                    continue;
+                }
+
+                // The brightness thread enforces the cap, but when it's lifted
+                // the level the user wants has to be put back
+                if let Some(Event::ThermalCap(cap)) = event {
+                    thermal_cap = cap;
+                    let power_cap = battery_cap(&args, on_battery, battery_level);
+                    if let Err(e) = brightness.send(brightness::Command::Cap(lowest_cap(&[thermal_cap, power_cap, schedule_cap, night_cap]))) {
+                        warn!(target: log::STATEMACHINE, "Failed to cap backlight level: {}", e);
+                    }
+                    if cap.is_none() && is_active {
+                        request_level(&brightness, &args.no_fade, FadeSource::Activity, level, "thermal");
+                    }
+                    continue;
+                }
+
+                // A low battery caps the level while on battery, and a
+                // critical one flashes a warning each time it drops further
+                if let Some(Event::BatteryLevel(new_battery_level)) = event {
+                    debug!(target: log::STATEMACHINE, "Battery at {:.0}%", new_battery_level);
+                    let old_cap = battery_cap(&args, on_battery, battery_level);
+                    let dropped = battery_level.is_none_or(|old| new_battery_level < old);
+                    battery_level = Some(new_battery_level);
+
+                    let new_cap = battery_cap(&args, on_battery, battery_level);
+                    if new_cap != old_cap {
+                        if new_cap.is_some_and(|cap| old_cap.is_none_or(|old| cap < old)) {
+                            info!(target: log::STATEMACHINE, "Battery is low, capping the backlight");
+                        }
+                        if let Err(e) = brightness.send(brightness::Command::Cap(lowest_cap(&[thermal_cap, new_cap, schedule_cap, night_cap]))) {
+                            warn!(target: log::STATEMACHINE, "Failed to cap backlight level: {}", e);
+                        }
+                        if is_active {
+                            request_level(&brightness, &args.no_fade, FadeSource::Activity, level, "battery");
+                        }
+                    }
+
+                    let critical = args.critical_battery.is_some_and(|critical| new_battery_level < critical);
+                    if on_battery && critical && dropped && !lid_closed && !display_off {
+                        warn!(target: log::STATEMACHINE, "Battery is critical at {:.0}%", new_battery_level);
+                        if let Err(e) = brightness.send(brightness::Command::Flash { count: args.critical_battery_flashes }) {
+                            warn!(target: log::STATEMACHINE, "Failed to flash backlight: {}", e);
+                        }
+                    }
+                    continue;
+                }
+
+                // The schedule caps the level like the temperature does, and
+                // sets the level the user wants when a period starts
+                if let Some(Event::Schedule { cap, level: schedule_level }) = event {
+                    schedule_cap = cap;
+                    let power_cap = battery_cap(&args, on_battery, battery_level);
+                    if let Err(e) = brightness.send(brightness::Command::Cap(lowest_cap(&[thermal_cap, power_cap, schedule_cap, night_cap]))) {
+                        warn!(target: log::STATEMACHINE, "Failed to cap backlight level: {}", e);
+                    }
+                    if let Some(schedule_level) = schedule_level {
+                        requested_level = schedule_level.min(caps.max_level);
+                    }
+                    if is_active {
+                        level = requested_level;
+                        request_level(&brightness, &args.no_fade, FadeSource::Activity, level, "schedule");
+                    }
+                    continue;
+                }
+
+                // The night cap rises in the morning a step at a time, and the
+                // level the user wants is put back at each step
+                if let Some(Event::NightCap(cap)) = event {
+                    let raised = match (night_cap, cap) {
+                        (Some(old), Some(new)) => new > old,
+                        (_, new) => new.is_none()
+                    };
+                    night_cap = cap;
+                    let power_cap = battery_cap(&args, on_battery, battery_level);
+                    if let Err(e) = brightness.send(brightness::Command::Cap(lowest_cap(&[thermal_cap, power_cap, schedule_cap, night_cap]))) {
+                        warn!(target: log::STATEMACHINE, "Failed to cap backlight level: {}", e);
+                    }
+                    if raised && is_active {
+                        request_level(&brightness, &args.no_fade, FadeSource::Activity, level, "night");
+                    }
+                    continue;
+                }
+
+                // The battery has its own timeout and cap, and the level to
+                // dim to the next time we're idle
+                if let Some(Event::OnBattery(battery)) = event {
+                    info!(target: log::STATEMACHINE, "{}", if battery { "On battery" } else { "On mains power" });
+                    on_battery = battery;
+                    timeout = current_timeout(&args, dark_mode, on_battery, light_timeout);
+                    idle_deadline = idle_deadline.min(Instant::now() + timeout);
+
+                    let power_cap = battery_cap(&args, on_battery, battery_level);
+                    if let Err(e) = brightness.send(brightness::Command::Cap(lowest_cap(&[thermal_cap, power_cap, schedule_cap, night_cap]))) {
+                        warn!(target: log::STATEMACHINE, "Failed to cap backlight level: {}", e);
+                    }
+                    if !battery && is_active {
+                        request_level(&brightness, &args.no_fade, FadeSource::Activity, level, "power");
+                    }
+                    continue;
+                }
+
+                // Dark mode has its own level and timeout
+                if let Some(Event::DarkMode(dark)) = event {
+                    dark_mode = dark;
+                    timeout = current_timeout(&args, dark_mode, on_battery, light_timeout);
+                    idle_deadline = idle_deadline.min(Instant::now() + timeout);
+
+                    let new_level = match (dark, args.dark_mode_level) {
+                        (true, Some(dark_level)) if light_level.is_none() => {
+                            // The user may have changed the level on the
+                            // keyboard, but only while the backlight is on
+                            light_level = match is_active {
+                                true => Some(get_updated_requested_level(&brightness, requested_level).await),
+                                false => Some(requested_level)
+                            };
+                            Some(dark_level.min(caps.max_level))
+                        },
+                        (false, _) => light_level.take(),
+                        _ => None
+                    };
+                    if let Some(new_level) = new_level {
+                        requested_level = new_level;
+                        if is_active {
+                            level = requested_level;
+                            request_level(&brightness, &args.no_fade, FadeSource::Activity, level, "dark-mode");
+                        }
+                    }
+                    continue;
+                }
+
+                // Inhibiting processes only stop us dimming on the timeout
+                if let Some(Event::ProcessInhibit(running)) = event {
+                    process_inhibit = running;
+                    continue;
+                }
+
+                // Other programs can hold off either kind of dimming
+                if let Some(Event::Inhibit { idle, lock }) = event {
+                    inhibit_idle = idle;
+                    inhibit_lock = lock;
+                    continue;
+                }
+
+                // Or just dimming on the timeout, for a while
+                if let Some(Event::InhibitFor(duration)) = event {
+                    match duration.is_zero() {
+                        true => info!(target: log::STATEMACHINE, "Dimming on the timeout again"),
+                        false => info!(target: log::STATEMACHINE, "Not dimming for {:.0}s", duration.as_secs_f64())
+                    }
+                    inhibit_until = Some(Instant::now() + duration);
+                    continue;
+                }
+
+                // Sleeping and waking only affect the device, which the
+                // brightness thread looks after
+                if let Some(Event::Sleep(done)) = event {
+                    info!(target: log::STATEMACHINE, "Giving the backlight back for the system to sleep");
+                    if let Err(e) = brightness.send(brightness::Command::Suspend(done)) {
+                        warn!(target: log::STATEMACHINE, "Failed to give the backlight back: {}", e);
+                    }
+                    continue;
+                }
+                if let Some(Event::Wake) = event {
+                    if let Err(e) = brightness.send(brightness::Command::Resume) {
+                        warn!(target: log::STATEMACHINE, "Failed to reopen the backlight: {}", e);
+                    }
+                    continue;
+                }
+
+                // Answered by the brightness thread, so that the watchdog
+                // hears back only if it isn't stuck either
+                if let Some(Event::Watchdog(reply)) = event {
+                    if let Err(e) = brightness.send(brightness::Command::Read(reply)) {
+                        warn!(target: log::STATEMACHINE, "Failed to check the backlight: {}", e);
+                    }
+                    continue;
+                }
+
+                // Answer state queries from D-Bus and the control socket. The
+                // user may have changed the level on the keyboard, but only
+                // while it's on
+                if let Some(Event::StateQuery(reply)) = event {
+                    let level = match is_active {
+                        true => get_updated_requested_level(&brightness, level).await,
+                        false => level
+                    };
+                    let _ = reply.send(dbus::State { level, dimmed: !is_active, timeout });
+                    continue;
+                }
+
+                // The config file has changed. Options the main loop reads take
+                // effect straight away, and the backlight is opened again if
+                // another one has been picked, but anything that runs on its own
+                // keeps the options it was started with
+                if let Some(Event::Reload) = event {
+                    // The config file is outside --chroot, so there's nothing to
+                    // read it from
+                    if args.chroot.is_some() {
+                        warn!("The configuration can't be reloaded inside --chroot, restart the daemon instead");
+                        continue;
+                    }
+                    let new_args = match reparse_args() {
+                        Ok(new_args) => new_args,
+                        Err(e) => {
+                            warn!("Failed to reload the configuration, keeping the old one: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if !same_device(&args, &new_args) {
+                        match open_backlight(&new_args) {
+                            Ok(backlight) => {
+                                caps = backlight.capabilities();
+                                requested_level = requested_level.min(caps.max_level);
+                                level = level.min(caps.max_level);
+                                if let Err(e) = brightness.send(brightness::Command::Replace(backlight)) {
+                                    warn!("Failed to switch backlight: {}", e);
+                                }
+                            },
+                            Err(e) => warn!("Failed to open the newly configured backlight, keeping the old one: {}", e)
+                        }
+                    }
+
+                    light_timeout = Duration::from_secs_f64(new_args.timeout);
+                    timeout = current_timeout(&new_args, dark_mode, on_battery, light_timeout);
+                    if on_battery {
+                        let power_cap = battery_cap(&new_args, on_battery, battery_level);
+                        if let Err(e) = brightness.send(brightness::Command::Cap(lowest_cap(&[thermal_cap, power_cap, schedule_cap, night_cap]))) {
+                            warn!("Failed to cap backlight level: {}", e);
+                        }
+                    }
+                    idle_deadline = idle_deadline.min(Instant::now() + timeout);
+                    step = new_args.step.unwrap_or((caps.max_level / 10).max(1));
+                    for option in needs_restart(&args, &new_args) {
+                        warn!("{} changed, but won't take effect until the daemon is restarted", option);
+                    }
+                    args = new_args;
+                    info!("Reloaded the configuration");
+                    continue;
+                }
+
+                // A new timeout is also kept for when dark mode ends
+                if let Some(Event::SetTimeout(new_timeout)) = event {
+                    info!(target: log::STATEMACHINE, "Timeout changed to {:.1}s", new_timeout.as_secs_f64());
+                    timeout = new_timeout;
+                    light_timeout = new_timeout;
+                    idle_deadline = idle_deadline.min(Instant::now() + timeout);
+                    continue;
+                }
+
+                // A step up or down from the level the user wants is then
+                // handled like a level set by another program
+                let event = match event {
+                    Some(Event::Step { up, source }) => {
+                        let current = match is_active {
+                            true => get_updated_requested_level(&brightness, requested_level).await,
+                            false => requested_level
+                        };
+                        Some(Event::SetLevel(match up {
+                            true => current.saturating_add(step),
+                            false => current.saturating_sub(step)
+                        }, source))
+                    },
+                    event => event
+                };
+
+                // A level set by another program becomes the level the user
+                // wants. If we're dimmed it also counts as activity, bringing
+                // the backlight back on at that level below
+                if let Some(Event::SetLevel(new_level, source)) = event {
+                    requested_level = new_level.min(caps.max_level);
+                    if is_active {
+                        level = requested_level;
+                        idle_deadline = Instant::now() + timeout;
+                        request_level(&brightness, &args.no_fade, source, level, "set");
+                    }
+                }
+
+                // Closing the lid turns the backlight off straight away, as
+                // fading it inside a closed laptop only wastes power, and
+                // nothing brings it back until the lid is opened, which then
+                // counts as activity. The display turning off does the same,
+                // as the user has gone or asked for the screen to go dark
+                let turned_off = match event {
+                    Some(Event::Lid(closed)) if closed != lid_closed => {
+                        info!(target: log::STATEMACHINE, "Lid {}", if closed { "closed" } else { "opened" });
+                        lid_closed = closed;
+                        closed.then_some("lid")
+                    },
+                    Some(Event::DisplayPower(on)) if on == display_off => {
+                        info!(target: log::STATEMACHINE, "Display turned {}", if on { "on" } else { "off" });
+                        display_off = !on;
+                        (!on).then_some("display-off")
+                    },
+                    Some(Event::Lid(_)) | Some(Event::DisplayPower(_)) => continue,
+                    _ => None
+                };
+                if let Some(reason) = turned_off {
+                    if is_active {
+                        is_active = false;
+                        requested_level = get_updated_requested_level(&brightness, level).await;
+                    }
+                    lock_deadline = None;
+                    level = 0;
+                    if let Err(e) = brightness.send(brightness::Command::Set { level, fade: false, reason }) {
+                        warn!(target: log::STATEMACHINE, "Failed to set backlight level: {}", e);
+                    }
+                    continue;
+                }
+                if lid_closed || display_off {
+                    continue;
+                }
+
+                // Activity from a source with no weight is ignored altogether
+                if let Some(Event::Activity(source)) = event {
+                    if activity_weight(&args.activity_weights, source) == 0.0 {
+                        continue;
+                    }
+                }
+
+                // Work out whether this event should dim the backlight now
+                let dim_now = match event {
+                    Some(Event::LockKey) | Some(Event::Locked(true)) => args.lock && !game_running && !inhibit_lock,
+                    Some(Event::DimNow) => true,
+                    _ => false
+                };
+
+                // Ignore events if we're asked to
+                if ignore_next > 0 {
+                    ignore_next -= 1;
+                } else {
+                    // If the result back was a lockscreen (and dim-on-locking is
+                    // enabled) or another source asked us to dim
+                    if dim_now {
+                        // Only trigger if active otherwise we could set the requested
+                        // level whilst dimming
+                        if is_active {
+                            // Ignore the next couple of events (so the Meta or L up doesn't trigger the backlight)
+                            if let Some(Event::LockKey) = event {
+                                ignore_next = 2;
+                            }
+
+                            // Give the user a moment to change their mind
+                            if let Some(Event::LockKey | Event::Locked(true)) = event {
+                                if args.lock_grace > 0.0 {
+                                    lock_deadline = Some(Instant::now() + Duration::from_secs_f64(args.lock_grace));
+                                    continue;
+                                }
+                            }
+
+                            // Take us to dimming. When locking, we may be asked
+                            // to leave the backlight on low rather than off
+                            is_active = false;
+                            lock_deadline = None;
+                            requested_level = get_updated_requested_level(&brightness, level).await;
+                            let reason;
+                            (level, reason) = match event {
+                                Some(Event::LockKey) | Some(Event::Locked(true)) => (args.lock_level.min(requested_level), "lock"),
+                                _ => (0, "dim-now")
+                            };
+                            request_level(&brightness, &args.no_fade, FadeSource::Idle, level, reason);
+                        }
+                    } else if !is_active {
+                        // Key was pressed, set active and bring the backlight
+                        // back to what the user set it to
+                        is_active = true;
+                        idle_deadline = Instant::now() + timeout;
+                        level = requested_level;
+                        let reason = match event {
+                            Some(Event::Locked(false)) => "unlock",
+                            Some(Event::Lid(false)) => "lid",
+                            Some(Event::DisplayPower(true)) => "display-on",
+                            Some(Event::SetLevel(..)) => "set",
+                            _ => "activity"
+                        };
+                        let source = match event {
+                            Some(Event::SetLevel(_, source)) => source,
+                            _ => FadeSource::Activity
+                        };
+                        request_level(&brightness, &args.no_fade, source, level, reason);
+
+                        // Put the color back if the sunrise changed it
+                        if sunrise_running {
+                            sunrise_running = false;
+                            if let (Some((r, g, b)), Some(_), true) = (color, args.sunrise_color, caps.color) {
+                                if let Err(e) = brightness.send(brightness::Command::Color { r, g, b }) {
+                                    warn!(target: log::STATEMACHINE, "Failed to set color: {}", e);
+                                }
+                            }
+                        }
+                    } else if lock_deadline.is_some() {
+                        // Key was pressed during the lock grace period, which
+                        // means the user unlocked again
+                        debug!(target: log::STATEMACHINE, "Unlocked during grace period, not dimming");
+                        lock_deadline = None;
+                    }
+
+                    // Push back the timeout by however much this source's
+                    // activity is worth, but never past a full timeout from now
+                    if let Some(Event::Activity(source)) = event {
+                        last_activity = Instant::now();
+                        let weight = activity_weight(&args.activity_weights, source);
+                        let extended = idle_deadline + timeout.mul_f64(weight);
+                        idle_deadline = idle_deadline.max(extended.min(Instant::now() + timeout));
+                    }
+                }
+            },
+
+            // Asked to stop, by systemd or Ctrl-C
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+
+            // The config file has changed
+            _ = sighup.recv() => {
+                let _ = s.send(Event::Reload);
+            },
+
+            // Stepping the level, e.g. from window manager key bindings
+            _ = sigusr1.recv() => {
+                let _ = s.send(Event::Step { up: true, source: FadeSource::Signal });
+            },
+            _ = sigusr2.recv() => {
+                let _ = s.send(Event::Step { up: false, source: FadeSource::Signal });
+            },
+
+            // Timeout
+            _ = timeout_task => {
+                // The backlight has been off long enough for the screensaver
+                if let Some(at) = screensaver_at {
+                    if Instant::now() >= at {
+                        info!(target: log::STATEMACHINE, "Starting screensaver");
+                        screensaver_running = true;
+                        if let Err(e) = brightness.send(brightness::Command::Screensaver { color: caps.color }) {
+                            warn!(target: log::STATEMACHINE, "Failed to start screensaver: {}", e);
+                        }
+                        continue;
+                    }
+                }
+
+                // A lock has waited out its grace period, so dim as if we'd
+                // done it straight away
+                if let Some(deadline) = lock_deadline {
+                    if Instant::now() >= deadline {
+                        lock_deadline = None;
+                        if is_active {
+                            is_active = false;
+                            requested_level = get_updated_requested_level(&brightness, level).await;
+                            level = args.lock_level.min(requested_level);
+                            request_level(&brightness, &args.no_fade, FadeSource::Idle, level, "lock");
+                        }
+                        continue;
+                    }
+                }
+
+                // No key has been pressed recently, so if we're currently
+                // active it's time to dim (otherwise we'd trigger a dim when
+                // we're already dimmed which would set requested_level to zero!).
+                // When following, the leader decides when to dim, and we
+                // don't dim on a timeout while someone is at the machine or
+                // the room is bright
+                if is_active && Instant::now() >= idle_deadline {
+                    let inhibited = inhibit_idle || inhibit_until.is_some_and(|until| Instant::now() < until);
+                    if args.follow.is_none() && !someone_present && !room_bright && !game_running && !process_inhibit && !inhibited {
+                        is_active = false;
+                        requested_level = get_updated_requested_level(&brightness, level).await;
+                        level = match on_battery {
+                            true => args.battery_idle_level.unwrap_or(args.idle_level),
+                            false => args.idle_level
+                        }.min(requested_level);
+                        match args.idle_effect {
+                            IdleEffect::Off => request_level(&brightness, &args.no_fade, FadeSource::Idle, level, "idle"),
+                            IdleEffect::Breathe => {
+                                let breathe = brightness::Command::Breathe { level: args.breathe_level, hardware: caps.effects };
+                                if let Err(e) = brightness.send(breathe) {
+                                    warn!(target: log::STATEMACHINE, "Failed to start breathing: {}", e);
+                                }
+                            }
+                        }
+                    } else {
+                        // Check again after another timeout
+                        idle_deadline = Instant::now() + timeout;
+                    }
+                }
+            }
+        }
+    }
+
+    // Put the backlight back to the level the user wants, which they may have
+    // changed on the keyboard if it's on, and give the device back
+    let level = match is_active {
+        true => get_updated_requested_level(&brightness, level).await,
+        false => requested_level
+    };
+    info!(target: log::STATEMACHINE, brightness = level, "Stopping, restoring backlight level {}", level);
+    let _ = notify::send("STOPPING=1");
+    let (done_s, done_r) = oneshot::channel();
+    if brightness.send(brightness::Command::Shutdown { level, done: done_s }).is_err() {
+        error!(target: log::STATEMACHINE, "Brightness thread has stopped");
+        return Ok(());
+    }
+    if done_r.await.is_err() {
+        error!(target: log::STATEMACHINE, "Brightness thread stopped before restoring the backlight");
     }
+    Ok(())
 }
@@ -0,0 +1,133 @@
+use std::fs::File;
+
+use crate::input;
+
+// Names of the event types, indexed by type
+const TYPE_NAMES: [&str; 0x13] = [
+    "EV_SYN", "EV_KEY", "EV_REL", "EV_ABS", "EV_MSC", "EV_SW", "", "", "", "", "", "", "", "", "", "", "", "EV_LED", "EV_SND"
+];
+
+// Names of the keys on a typical keyboard, indexed by code, as they appear in
+// linux/input-event-codes.h
+const KEY_NAMES: [&str; 128] = [
+    "KEY_RESERVED", "KEY_ESC", "KEY_1", "KEY_2", "KEY_3", "KEY_4", "KEY_5", "KEY_6",
+    "KEY_7", "KEY_8", "KEY_9", "KEY_0", "KEY_MINUS", "KEY_EQUAL", "KEY_BACKSPACE", "KEY_TAB",
+    "KEY_Q", "KEY_W", "KEY_E", "KEY_R", "KEY_T", "KEY_Y", "KEY_U", "KEY_I",
+    "KEY_O", "KEY_P", "KEY_LEFTBRACE", "KEY_RIGHTBRACE", "KEY_ENTER", "KEY_LEFTCTRL", "KEY_A", "KEY_S",
+    "KEY_D", "KEY_F", "KEY_G", "KEY_H", "KEY_J", "KEY_K", "KEY_L", "KEY_SEMICOLON",
+    "KEY_APOSTROPHE", "KEY_GRAVE", "KEY_LEFTSHIFT", "KEY_BACKSLASH", "KEY_Z", "KEY_X", "KEY_C", "KEY_V",
+    "KEY_B", "KEY_N", "KEY_M", "KEY_COMMA", "KEY_DOT", "KEY_SLASH", "KEY_RIGHTSHIFT", "KEY_KPASTERISK",
+    "KEY_LEFTALT", "KEY_SPACE", "KEY_CAPSLOCK", "KEY_F1", "KEY_F2", "KEY_F3", "KEY_F4", "KEY_F5",
+    "KEY_F6", "KEY_F7", "KEY_F8", "KEY_F9", "KEY_F10", "KEY_NUMLOCK", "KEY_SCROLLLOCK", "KEY_KP7",
+    "KEY_KP8", "KEY_KP9", "KEY_KPMINUS", "KEY_KP4", "KEY_KP5", "KEY_KP6", "KEY_KPPLUS", "KEY_KP1",
+    "KEY_KP2", "KEY_KP3", "KEY_KP0", "KEY_KPDOT", "", "KEY_ZENKAKUHANKAKU", "KEY_102ND", "KEY_F11",
+    "KEY_F12", "KEY_RO", "KEY_KATAKANA", "KEY_HIRAGANA", "KEY_HENKAN", "KEY_KATAKANAHIRAGANA", "KEY_MUHENKAN", "KEY_KPJPCOMMA",
+    "KEY_KPENTER", "KEY_RIGHTCTRL", "KEY_KPSLASH", "KEY_SYSRQ", "KEY_RIGHTALT", "KEY_LINEFEED", "KEY_HOME", "KEY_UP",
+    "KEY_PAGEUP", "KEY_LEFT", "KEY_RIGHT", "KEY_END", "KEY_DOWN", "KEY_PAGEDOWN", "KEY_INSERT", "KEY_DELETE",
+    "KEY_MACRO", "KEY_MUTE", "KEY_VOLUMEDOWN", "KEY_VOLUMEUP", "KEY_POWER", "KEY_KPEQUAL", "KEY_KPPLUSMINUS", "KEY_PAUSE",
+    "KEY_SCALE", "KEY_KPCOMMA", "KEY_HANGEUL", "KEY_HANJA", "KEY_YEN", "KEY_LEFTMETA", "KEY_RIGHTMETA", "KEY_COMPOSE"
+];
+
+
+// Gives a readable name for an event type
+fn type_name(in_type: u16) -> String {
+    match TYPE_NAMES.get(in_type as usize) {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => format!("0x{:02x}", in_type)
+    }
+}
+
+
+// Gives the name of a key, or its code if it doesn't have one
+pub fn key_name(code: u16) -> String {
+    code_name(0x01, code)
+}
+
+
+// Parses a key given either by name (e.g. KEY_LEFTMETA) or by code
+pub fn parse_key(s: &str) -> Result<u16, String> {
+    if let Ok(code) = s.parse::<u16>() {
+        return Ok(code);
+    }
+
+    let name = s.to_uppercase();
+    let name = match name.starts_with("KEY_") {
+        true => name,
+        false => format!("KEY_{}", name)
+    };
+    match KEY_NAMES.iter().position(|k| *k == name) {
+        Some(code) => Ok(code as u16),
+        None => Err(format!("unknown key {}", s))
+    }
+}
+
+
+// Gives a readable name for an event code. Only key codes have names here,
+// anything else is shown as a number
+fn code_name(in_type: u16, code: u16) -> String {
+    match (in_type, KEY_NAMES.get(code as usize)) {
+        (0x01, Some(name)) if !name.is_empty() => name.to_string(),
+        _ => format!("{}", code)
+    }
+}
+
+
+// Reads a single key event from an input device, skipping everything else
+fn read_key(reader: &mut input::EventReader) -> std::io::Result<(u16, i32)> {
+    loop {
+        let event = reader.next_event()?;
+        if event.in_type == 0x01 {
+            return Ok((event.code, event.value));
+        }
+    }
+}
+
+
+// Waits for a key combination to be pressed and released, giving the keys in
+// the order they were pressed
+pub fn learn_combo(event_path: &str) -> std::io::Result<Vec<u16>> {
+    let mut reader = input::EventReader::new(File::open(event_path)?);
+    println!("Press the lock combination on {}, then let go", event_path);
+
+    // Releases before anything is pressed (such as the Enter that started us)
+    // are ignored
+    let mut keys: Vec<u16> = Vec::new();
+    let mut held = 0;
+    loop {
+        match read_key(&mut reader)? {
+            (code, 1) => {
+                if !keys.contains(&code) {
+                    keys.push(code);
+                }
+                held += 1;
+            },
+            (_, 0) if held > 0 => {
+                held -= 1;
+                if held == 0 {
+                    return Ok(keys);
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
+
+// Prints every event read from an input device until it goes away, so that
+// keycodes can be found for configuring key combinations
+pub fn run(event_path: &str) -> std::io::Result<()> {
+    let mut reader = input::EventReader::new(File::open(event_path)?);
+    println!("Monitoring {}, press Ctrl+C to stop", event_path);
+
+    loop {
+        let input::InputEvent { in_type, code, value } = reader.next_event()?;
+
+        // Sync events just separate groups of events, so leave them out
+        if in_type == 0x00 {
+            continue;
+        }
+
+        println!("{}  type {} ({})  code {} ({})  value {}", event_path,
+            in_type, type_name(in_type), code, code_name(in_type, code), value);
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use zbus::blocking::{Connection, Proxy};
+
+use tracing::{debug, info, warn};
+use crate::{ActivitySource, Event};
+
+// Where Mutter's idle monitor for all of the session's input lives
+const MONITOR_NAME: &str = "org.gnome.Mutter.IdleMonitor";
+const MONITOR_PATH: &str = "/org/gnome/Mutter/IdleMonitor/Core";
+const MONITOR_INTERFACE: &str = "org.gnome.Mutter.IdleMonitor";
+
+// How long without input before Mutter says the session is idle, in
+// milliseconds. Until it does, there's been input in the last second
+const IDLE_AFTER_MS: u64 = 1000;
+
+
+// Posts activity to the main loop once a second for as long as the session
+// isn't idle, stopping once the watch that says so has gone
+fn tick(idle: Weak<AtomicBool>, s: &UnboundedSender<Event>) {
+    loop {
+        thread::sleep(Duration::from_millis(IDLE_AFTER_MS));
+        let idle = match idle.upgrade() {
+            Some(idle) => idle.load(Ordering::Relaxed),
+            None => return
+        };
+        if !idle && s.send(Event::Activity(ActivitySource::Mutter)).is_err() {
+            return;
+        }
+    }
+}
+
+
+// Asks Mutter to say when the session goes idle and when the user comes back.
+// Mutter counts all of the session's input, and holds the idle time back while
+// anything inhibits idling through GNOME (e.g. a video playing)
+fn watch_idle(s: &UnboundedSender<Event>) -> Result<(), zbus::Error> {
+    let conn = Connection::session()?;
+    let monitor = Proxy::new(&conn, MONITOR_NAME, MONITOR_PATH, MONITOR_INTERFACE)?;
+
+    // Subscribe before adding the watches so that none of them are missed
+    let signals = monitor.receive_signal("WatchFired")?;
+    let idle_watch: u32 = monitor.call("AddIdleWatch", &(IDLE_AFTER_MS,))?;
+
+    // User active watches only fire once, so one is added each time the
+    // session goes idle
+    let idle_time: u64 = monitor.call("GetIdletime", &())?;
+    let mut active_watch = None;
+    if idle_time >= IDLE_AFTER_MS {
+        active_watch = Some(monitor.call::<_, _, u32>("AddUserActiveWatch", &())?);
+    }
+    info!("Watching GNOME's idle monitor for activity");
+
+    let idle = Arc::new(AtomicBool::new(active_watch.is_some()));
+    let (weak_idle, ticker_s) = (Arc::downgrade(&idle), s.clone());
+    thread::Builder::new().name("mutter-idle-tick".to_string())
+        .spawn(move || tick(weak_idle, &ticker_s))
+        .map_err(|e| zbus::Error::InputOutput(Arc::new(e)))?;
+
+    for message in signals {
+        let id: u32 = message.body().deserialize()?;
+        if id == idle_watch {
+            debug!("Mutter says the session is idle");
+            idle.store(true, Ordering::Relaxed);
+            if active_watch.is_none() {
+                active_watch = Some(monitor.call("AddUserActiveWatch", &())?);
+            }
+        } else if Some(id) == active_watch {
+            debug!("Mutter says the session is active");
+            idle.store(false, Ordering::Relaxed);
+            active_watch = None;
+            if s.send(Event::Activity(ActivitySource::Mutter)).is_err() {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+// Creates a thread that posts activity to the main loop whenever GNOME's idle
+// monitor sees input
+pub fn spawn_idle_watcher(s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("mutter-idle".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = watch_idle(&s) {
+            warn!("Stopped watching GNOME's idle monitor: {}", e);
+        }
+    })
+}
@@ -0,0 +1,327 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use chrono::{DateTime, Local, NaiveDate, TimeDelta, Utc};
+use tokio::sync::mpsc::UnboundedSender;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::OwnedObjectPath;
+
+use tracing::{debug, info, warn};
+use crate::{power, Event};
+
+// Where geoclue lives on the system bus
+const GEOCLUE_NAME: &str = "org.freedesktop.GeoClue2";
+const MANAGER_PATH: &str = "/org/freedesktop/GeoClue2/Manager";
+const MANAGER_INTERFACE: &str = "org.freedesktop.GeoClue2.Manager";
+const CLIENT_INTERFACE: &str = "org.freedesktop.GeoClue2.Client";
+const LOCATION_INTERFACE: &str = "org.freedesktop.GeoClue2.Location";
+
+// Geoclue's accuracy level for the nearest city, which is plenty for working
+// out when the sun sets
+const CITY_ACCURACY: u32 = 4;
+
+// How far we have to move, in meters, before geoclue tells us again. Sunset
+// moves by about a minute for every 20km
+const DISTANCE_THRESHOLD: u32 = 10000;
+
+// How long to wait before asking geoclue again after it failed
+const GEOCLUE_RETRY: Duration = Duration::from_secs(600);
+
+// How high the sun is, in degrees, when it's full day and when it's night.
+// In between is twilight, which the cap is ramped across as redshift does
+const DAY_ELEVATION: f64 = 3.0;
+const NIGHT_ELEVATION: f64 = -6.0;
+
+// How high the middle of the sun is at sunrise and sunset, allowing for its
+// size and the atmosphere bending its light
+const HORIZON_ELEVATION: f64 = -0.833;
+
+// How often to check where the sun is
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+
+// Where we are, for working out where the sun is. Geoclue can be given
+// somewhere to go by until it answers, for when it never has before
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Location {
+    Fixed { latitude: f64, longitude: f64 },
+    Geoclue { fallback: Option<(f64, f64)> }
+}
+
+
+// Parses a location given as LATITUDE,LONGITUDE in degrees, or `geoclue` to
+// look it up, optionally followed by :LATITUDE,LONGITUDE to go by until it
+// does
+pub fn parse_location(s: &str) -> Result<Location, String> {
+    if s == "geoclue" {
+        return Ok(Location::Geoclue { fallback: None });
+    }
+    if let Some(fallback) = s.strip_prefix("geoclue:") {
+        return match parse_location(fallback)? {
+            Location::Fixed { latitude, longitude } => Ok(Location::Geoclue { fallback: Some((latitude, longitude)) }),
+            Location::Geoclue { .. } => Err(String::from("expected LATITUDE,LONGITUDE after geoclue:"))
+        };
+    }
+
+    let (latitude, longitude) = s.split_once(',').ok_or_else(|| String::from("expected LATITUDE,LONGITUDE, geoclue or geoclue:LATITUDE,LONGITUDE"))?;
+    let latitude: f64 = latitude.trim().parse().map_err(|e| format!("invalid latitude: {}", e))?;
+    let longitude: f64 = longitude.trim().parse().map_err(|e| format!("invalid longitude: {}", e))?;
+    if !(-90.0..=90.0).contains(&latitude) {
+        return Err(String::from("the latitude must be from -90 to 90"));
+    }
+    if !(-180.0..=180.0).contains(&longitude) {
+        return Err(String::from("the longitude must be from -180 to 180"));
+    }
+    Ok(Location::Fixed { latitude, longitude })
+}
+
+
+// Works out how high the sun is above the horizon, in degrees, at the given
+// time and place. This uses NOAA's approximations, which are good to well
+// within a minute of the sunrise and sunset for the next few centuries
+fn sun_elevation(time: DateTime<Utc>, latitude: f64, longitude: f64) -> f64 {
+    // Julian centuries since noon on the 1st of January 2000
+    let t = (time.timestamp() as f64 / 86400.0 - 10957.5) / 36525.0;
+
+    let mean_longitude = (280.46646 + t * (36000.76983 + t * 0.0003032)).rem_euclid(360.0).to_radians();
+    let mean_anomaly = (357.52911 + t * (35999.05029 - t * 0.0001537)).to_radians();
+    let eccentricity = 0.016708634 - t * (0.000042037 + t * 0.0000001267);
+    let centre = mean_anomaly.sin() * (1.914602 - t * (0.004817 + t * 0.000014))
+        + (2.0 * mean_anomaly).sin() * (0.019993 - t * 0.000101)
+        + (3.0 * mean_anomaly).sin() * 0.000289;
+    let omega = (125.04 - 1934.136 * t).to_radians();
+    let apparent_longitude = mean_longitude + (centre - 0.00569 - 0.00478 * omega.sin()).to_radians();
+    let obliquity = (23.0 + (26.0 + (21.448 - t * (46.815 + t * (0.00059 - t * 0.001813))) / 60.0) / 60.0
+        + 0.00256 * omega.cos()).to_radians();
+    let declination = (obliquity.sin() * apparent_longitude.sin()).asin();
+
+    // How far the sun is ahead of or behind the clock, in minutes
+    let y = (obliquity / 2.0).tan().powi(2);
+    let equation_of_time = 4.0 * (y * (2.0 * mean_longitude).sin()
+        - 2.0 * eccentricity * mean_anomaly.sin()
+        + 4.0 * eccentricity * y * mean_anomaly.sin() * (2.0 * mean_longitude).cos()
+        - 0.5 * y * y * (4.0 * mean_longitude).sin()
+        - 1.25 * eccentricity * eccentricity * (2.0 * mean_anomaly).sin()).to_degrees();
+
+    let solar_minutes = time.timestamp().rem_euclid(86400) as f64 / 60.0 + equation_of_time + 4.0 * longitude;
+    let hour_angle = (solar_minutes / 4.0 - 180.0).to_radians();
+    let latitude = latitude.to_radians();
+    let zenith = (latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos())
+        .clamp(-1.0, 1.0)
+        .acos();
+    90.0 - zenith.to_degrees()
+}
+
+
+// Works out how far into the day we are, from 0 at night to 1 once the sun
+// is up, going smoothly between them through twilight
+fn daylight(elevation: f64) -> f64 {
+    ((elevation - NIGHT_ELEVATION) / (DAY_ELEVATION - NIGHT_ELEVATION)).clamp(0.0, 1.0)
+}
+
+
+// Works out when the sun rises and sets on the given day, to the minute.
+// Either is None if it doesn't happen that day, as in polar summer or winter
+fn sun_times(day: NaiveDate, latitude: f64, longitude: f64) -> (Option<DateTime<Local>>, Option<DateTime<Local>>) {
+    let midnight = match day.and_hms_opt(0, 0, 0).and_then(|t| t.and_local_timezone(Local).earliest()) {
+        Some(midnight) => midnight,
+        None => return (None, None)
+    };
+
+    let is_up = |time: DateTime<Local>| sun_elevation(time.with_timezone(&Utc), latitude, longitude) > HORIZON_ELEVATION;
+    let (mut sunrise, mut sunset) = (None, None);
+    let mut was_up = is_up(midnight);
+    for minute in 1..=24 * 60 {
+        let time = midnight + TimeDelta::minutes(minute);
+        let up = is_up(time);
+        match (was_up, up) {
+            (false, true) => sunrise = sunrise.or(Some(time)),
+            (true, false) => sunset = sunset.or(Some(time)),
+            _ => ()
+        }
+        was_up = up;
+    }
+
+    (sunrise, sunset)
+}
+
+
+// Works out where the last location geoclue gave is kept between runs
+fn cache_path() -> PathBuf {
+    let dir = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".cache"),
+            None => PathBuf::from("/var/cache")
+        }
+    };
+    dir.join("bl-control").join("location")
+}
+
+
+// Reads the last location geoclue gave, if there is one
+fn cached_location() -> Option<(f64, f64)> {
+    let contents = fs::read_to_string(cache_path()).ok()?;
+    match parse_location(contents.trim()) {
+        Ok(Location::Fixed { latitude, longitude }) => Some((latitude, longitude)),
+        _ => None
+    }
+}
+
+
+// Keeps a location from geoclue for next time. Failing to just means going
+// without it if geoclue doesn't answer then
+fn cache_location(latitude: f64, longitude: f64) {
+    let path = cache_path();
+    let saved = match path.parent() {
+        Some(parent) => fs::create_dir_all(parent).and_then(|_| fs::write(&path, format!("{},{}\n", latitude, longitude))),
+        None => Ok(())
+    };
+    if let Err(e) = saved {
+        warn!("Failed to save our location to {}: {}", path.display(), e);
+    }
+}
+
+
+// Asks geoclue roughly where we are, and keeps sending where we are whenever
+// we move far enough to matter. Only returns without an error once nothing is
+// listening any more
+fn follow_geoclue(fixes: &mpsc::Sender<(f64, f64)>) -> Result<(), zbus::Error> {
+    let conn = Connection::system()?;
+    let manager = Proxy::new(&conn, GEOCLUE_NAME, MANAGER_PATH, MANAGER_INTERFACE)?;
+    let client_path: OwnedObjectPath = manager.call("GetClient", &())?;
+    let client = Proxy::new(&conn, GEOCLUE_NAME, client_path, CLIENT_INTERFACE)?;
+    client.set_property("DesktopId", "bl-control")?;
+    client.set_property("RequestedAccuracyLevel", CITY_ACCURACY)?;
+    client.set_property("DistanceThreshold", DISTANCE_THRESHOLD)?;
+
+    // Subscribe before starting so that the first location isn't missed
+    let updates = client.receive_signal("LocationUpdated")?;
+    client.call_method("Start", &())?;
+    for message in updates {
+        let (_, location_path): (OwnedObjectPath, OwnedObjectPath) = message.body().deserialize()?;
+        let location = Proxy::new(&conn, GEOCLUE_NAME, location_path, LOCATION_INTERFACE)?;
+        let latitude: f64 = location.get_property("Latitude")?;
+        let longitude: f64 = location.get_property("Longitude")?;
+        info!("Geoclue puts us at {:.2},{:.2}", latitude, longitude);
+        cache_location(latitude, longitude);
+        if fixes.send((latitude, longitude)).is_err() {
+            client.call_method("Stop", &())?;
+            return Ok(());
+        }
+    }
+
+    Err(zbus::Error::Failure(String::from("geoclue stopped giving locations")))
+}
+
+
+// Creates a thread that sends where geoclue puts us, asking it again a while
+// after it fails
+fn spawn_geoclue(fixes: mpsc::Sender<(f64, f64)>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("geoclue".to_string());
+    thread_builder.spawn(move || {
+        loop {
+            match follow_geoclue(&fixes) {
+                Ok(()) => return,
+                Err(e) => warn!("Failed to find our location through geoclue, trying again in {} minutes: {}", GEOCLUE_RETRY.as_secs() / 60, e)
+            }
+            thread::sleep(GEOCLUE_RETRY);
+        }
+    })
+}
+
+
+// Formats a sunrise or sunset time for the log
+fn format_time(time: Option<DateTime<Local>>) -> String {
+    match time {
+        Some(time) => time.format("%H:%M").to_string(),
+        None => String::from("none")
+    }
+}
+
+
+// Creates a thread that caps the backlight level after dark, between the
+// night level and the maximum as the sun sets and rises, and lifts the cap
+// during the day
+pub fn spawn(location: Location, night_level: u8, max_level: u8, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    // Until geoclue answers, go by where it put us last time, or failing that
+    // the fallback
+    let (fixes, new_fixes) = mpsc::channel();
+    let mut location = match location {
+        Location::Fixed { latitude, longitude } => Some((latitude, longitude)),
+        Location::Geoclue { fallback } => {
+            spawn_geoclue(fixes.clone())?;
+            let cached = cached_location();
+            if let Some((latitude, longitude)) = cached {
+                info!("Going by the last location from geoclue, {:.2},{:.2}, until it answers", latitude, longitude);
+            }
+            cached.or(fallback)
+        }
+    };
+
+    let thread_builder = thread::Builder::new().name("night".to_string());
+    thread_builder.spawn(move || {
+        // Kept so that waiting for geoclue times out rather than failing
+        // straight away when it isn't being asked
+        let _fixes = fixes;
+
+        // Only the first cap needs sending, as there's none to begin with
+        let mut last_day = None;
+        let mut last_cap = None;
+        loop {
+            // With nothing else to go by, wait for geoclue
+            if location.is_none() {
+                location = new_fixes.recv().ok();
+            }
+            let (latitude, longitude) = match location {
+                Some(location) => location,
+                None => return
+            };
+
+            let now = Local::now();
+            if last_day != Some(now.date_naive()) {
+                let (sunrise, sunset) = sun_times(now.date_naive(), latitude, longitude);
+                info!("Sunrise today is at {}, sunset at {}", format_time(sunrise), format_time(sunset));
+                last_day = Some(now.date_naive());
+            }
+
+            let elevation = sun_elevation(now.with_timezone(&Utc), latitude, longitude);
+            let daylight = daylight(elevation);
+            let cap = (daylight < 1.0).then(|| night_level + ((max_level - night_level) as f64 * daylight).round() as u8);
+            if cap != last_cap {
+                debug!("The sun is at {:.1} degrees, capping the backlight at {:?}", elevation, cap);
+                if let Err(e) = s.send(Event::NightCap(cap)) {
+                    warn!("{}", e);
+                    return;
+                }
+                last_cap = cap;
+            }
+
+            // Geoclue moving us means working it all out again straight away
+            if let Ok(fix) = new_fixes.recv_timeout(power::poll_interval(POLL_INTERVAL)) {
+                if Some(fix) != location {
+                    location = Some(fix);
+                    last_day = None;
+                }
+            }
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_locations() {
+        assert_eq!(parse_location("51.5,-0.13"), Ok(Location::Fixed { latitude: 51.5, longitude: -0.13 }));
+        assert_eq!(parse_location("geoclue"), Ok(Location::Geoclue { fallback: None }));
+        assert_eq!(parse_location("geoclue:51.5, -0.13"), Ok(Location::Geoclue { fallback: Some((51.5, -0.13)) }));
+        assert!(parse_location("geoclue:geoclue").is_err());
+        assert!(parse_location("91,0").is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+use crate::Event;
+
+
+// The socket to systemd, connected the first time it's used so that it keeps
+// working if we chroot afterwards
+static SOCKET: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+
+
+// Connects to the socket systemd gave us in NOTIFY_SOCKET, if it gave us one.
+// Names starting with @ are in the abstract namespace
+fn connect() -> io::Result<Option<UnixDatagram>> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(None)
+    };
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&path)?
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    socket.connect_addr(&addr)?;
+    Ok(Some(socket))
+}
+
+
+// Tells systemd about our state, e.g. "READY=1", if it's asked us to
+pub fn send(state: &str) -> io::Result<()> {
+    let socket = match SOCKET.get() {
+        Some(socket) => socket,
+        None => {
+            let socket = connect()?;
+            SOCKET.get_or_init(|| socket)
+        }
+    };
+    if let Some(socket) = socket {
+        socket.send(state.as_bytes())?;
+    }
+    Ok(())
+}
+
+
+// How often systemd wants to hear from us, if it's watching us at all
+pub fn watchdog_interval() -> Option<Duration> {
+    // The watchdog may be meant for another process in the service
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(process::id()) {
+            return None;
+        }
+    }
+
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    match usec {
+        0 => None,
+        usec => Some(Duration::from_micros(usec))
+    }
+}
+
+
+// Creates a thread that pings systemd's watchdog twice an interval, but only
+// once the main loop and the brightness thread have both answered, so that a
+// hung USB transfer gets the service restarted rather than left frozen
+pub fn spawn_watchdog(interval: Duration, s: UnboundedSender<Event>) -> io::Result<()> {
+    let thread_builder = thread::Builder::new().name("watchdog".to_string());
+    thread_builder.spawn(move || {
+        loop {
+            thread::sleep(interval / 2);
+
+            let (reply_s, reply_r) = oneshot::channel();
+            if s.send(Event::Watchdog(reply_s)).is_err() {
+                break;
+            }
+
+            // An error reading the level still means nothing is stuck, but
+            // no answer at all means the backlight has gone away
+            if reply_r.blocking_recv().is_err() {
+                debug!("No answer for the watchdog");
+                continue;
+            }
+            if let Err(e) = send("WATCHDOG=1") {
+                warn!("Failed to ping the systemd watchdog: {}", e);
+            }
+        }
+    })?;
+    Ok(())
+}
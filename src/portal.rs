@@ -0,0 +1,63 @@
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::OwnedValue;
+
+use tracing::{debug, info, warn};
+use crate::Event;
+
+// The setting that holds the system's light or dark preference
+const APPEARANCE_NAMESPACE: &str = "org.freedesktop.appearance";
+const COLOR_SCHEME_KEY: &str = "color-scheme";
+
+// The color-scheme value that means dark (0 is no preference, 2 is light)
+const PREFER_DARK: u32 = 1;
+
+
+// Sends the color scheme to the main loop, if it's changed
+fn send_scheme(value: OwnedValue, was_dark: &mut Option<bool>, s: &UnboundedSender<Event>) {
+    let dark = u32::try_from(value).map(|v| v == PREFER_DARK).unwrap_or(false);
+    if Some(dark) != *was_dark {
+        debug!("System switched to {} mode", if dark { "dark" } else { "light" });
+        if let Err(e) = s.send(Event::DarkMode(dark)) {
+            warn!("{}", e);
+        }
+        *was_dark = Some(dark);
+    }
+}
+
+
+// Reads the color scheme from the settings portal and then follows changes
+fn watch_color_scheme(s: &UnboundedSender<Event>) -> Result<(), zbus::Error> {
+    let conn = Connection::session()?;
+    let proxy = Proxy::new(&conn, "org.freedesktop.portal.Desktop", "/org/freedesktop/portal/desktop",
+        "org.freedesktop.portal.Settings")?;
+
+    // Subscribe before reading so that a change in between isn't missed
+    let changes = proxy.receive_signal("SettingChanged")?;
+    let mut was_dark = None;
+    let value: OwnedValue = proxy.call("ReadOne", &(APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY))?;
+    send_scheme(value, &mut was_dark, s);
+    info!("Watching the settings portal for dark mode");
+
+    for message in changes {
+        let (namespace, key, value): (String, String, OwnedValue) = message.body().deserialize()?;
+        if namespace == APPEARANCE_NAMESPACE && key == COLOR_SCHEME_KEY {
+            send_scheme(value, &mut was_dark, s);
+        }
+    }
+
+    Ok(())
+}
+
+
+// Creates a thread that posts to the main loop whenever the system switches
+// between light and dark mode
+pub fn spawn_dark_mode(s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("dark-mode".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = watch_color_scheme(&s) {
+            warn!("Stopped watching for dark mode: {}", e);
+        }
+    })
+}
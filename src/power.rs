@@ -0,0 +1,158 @@
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, warn};
+
+use crate::Event;
+
+// Where the kernel lists batteries and chargers
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+// How often to check whether we're on battery
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// How much longer to wait between polls and fade steps while saving power
+pub const LOW_POWER_FACTOR: u32 = 4;
+
+// Whether we're on battery with low power mode turned on. This is shared
+// rather than sent to the main loop as it's the polling threads that need it
+static LOW_POWER: AtomicBool = AtomicBool::new(false);
+
+
+// Whether we should be doing as little as possible to save the battery
+pub fn low_power() -> bool {
+    LOW_POWER.load(Ordering::Relaxed)
+}
+
+
+// Turns low power mode on or off, for whichever thread is following the power
+// supply
+pub fn set_low_power(on: bool) {
+    LOW_POWER.store(on, Ordering::Relaxed);
+}
+
+
+// Works out how long to wait between polls, which is longer while saving
+// power so that the various polling threads wake up less often
+pub fn poll_interval(interval: Duration) -> Duration {
+    match low_power() {
+        true => interval * LOW_POWER_FACTOR,
+        false => interval
+    }
+}
+
+
+// Sleeps between polls, for longer while saving power
+pub fn poll_sleep(interval: Duration) {
+    thread::sleep(poll_interval(interval));
+}
+
+
+// What the main loop has been told about the power supply, so that it's only
+// told about changes whichever monitor is following it. Like the main loop,
+// it starts out on mains power
+pub struct Supply {
+    save_power: bool,
+    on_battery: bool,
+    level: Option<f64>
+}
+
+impl Supply {
+    pub fn new(save_power: bool) -> Supply {
+        Supply { save_power, on_battery: false, level: None }
+    }
+
+    // Tells the main loop that we've gone on to battery or back on to mains
+    // power, if that's changed, and turns low power mode on to match if we're
+    // saving power. Gives false once the main loop has gone
+    pub fn send_on_battery(&mut self, battery: bool, s: &UnboundedSender<Event>) -> bool {
+        if battery == self.on_battery {
+            return true;
+        }
+
+        debug!("{}", if battery { "On battery" } else { "On mains power" });
+        if self.save_power {
+            set_low_power(battery);
+        }
+        self.on_battery = battery;
+        s.send(Event::OnBattery(battery)).is_ok()
+    }
+
+    // Tells the main loop how full the battery is, if that's changed
+    pub fn send_level(&mut self, level: Option<f64>, s: &UnboundedSender<Event>) -> bool {
+        if level == self.level {
+            return true;
+        }
+
+        self.level = level;
+        match level {
+            Some(level) => s.send(Event::BatteryLevel(level)).is_ok(),
+            None => true
+        }
+    }
+}
+
+
+// Checks whether any mains supply is online. Machines without one are never
+// on battery as far as we're concerned
+fn on_battery() -> Result<bool, String> {
+    let entries = fs::read_dir(POWER_SUPPLY_PATH).map_err(|e| e.to_string())?;
+    let mut has_mains = false;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_mains = fs::read_to_string(path.join("type")).map(|t| t.trim() == "Mains").unwrap_or(false);
+        if !is_mains {
+            continue;
+        }
+
+        has_mains = true;
+        if fs::read_to_string(path.join("online")).map(|o| o.trim() == "1").unwrap_or(false) {
+            return Ok(false);
+        }
+    }
+
+    Ok(has_mains)
+}
+
+
+// Reads how full the first battery the kernel knows about is, as a percentage
+fn battery_level() -> Option<f64> {
+    let entries = fs::read_dir(POWER_SUPPLY_PATH).ok()?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_battery = fs::read_to_string(path.join("type")).map(|t| t.trim() == "Battery").unwrap_or(false);
+        if is_battery {
+            return fs::read_to_string(path.join("capacity")).ok()?.trim().parse().ok();
+        }
+    }
+
+    None
+}
+
+
+// Creates a thread that posts to the main loop whenever we go on to battery or
+// back on to mains power, for the battery's profile, and whenever the battery's
+// level changes. It also turns low power mode on while we're on battery if
+// we're saving power
+pub fn spawn_monitor(save_power: bool, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("power-monitor".to_string());
+    thread_builder.spawn(move || {
+        let mut supply = Supply::new(save_power);
+        loop {
+            match on_battery() {
+                Ok(battery) => if !supply.send_on_battery(battery, &s) {
+                    return;
+                },
+                Err(e) => warn!("Failed to check power supply: {}", e)
+            }
+
+            if !supply.send_level(battery_level(), &s) {
+                return;
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    })
+}
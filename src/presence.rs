@@ -0,0 +1,102 @@
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::{debug, warn};
+use crate::{power, ActivitySource, Event};
+
+
+// Asks BlueZ about a paired device, returning whether it's connected and its
+// signal strength if known
+fn query_bluetooth(address: &str) -> Result<(bool, Option<i16>), String> {
+    let output = match Command::new("bluetoothctl").arg("info").arg(address).output() {
+        Ok(output) => output,
+        Err(e) => return Err(e.to_string())
+    };
+    if !output.status.success() {
+        return Err(format!("bluetoothctl exited with {}", output.status));
+    }
+
+    // The output is a list of "Key: value" lines
+    let mut connected = false;
+    let mut rssi = None;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        match line.trim().split_once(':') {
+            Some(("Connected", v)) => connected = v.trim() == "yes",
+            // e.g. "RSSI: -58" or "RSSI: 0xffffffc6 (-58)"
+            Some(("RSSI", v)) => rssi = v.trim().trim_end_matches(')').rsplit(['(', ' ']).next().and_then(|r| r.parse().ok()),
+            _ => ()
+        }
+    }
+
+    Ok((connected, rssi))
+}
+
+
+// Creates a thread that polls BlueZ for a paired phone. When the phone goes
+// away (disconnects, or its signal drops below the minimum) the backlight is
+// dimmed, and when it comes back it is restored
+pub fn spawn_bluetooth(address: String, interval: Duration, min_rssi: Option<i16>, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("bluetooth-presence".to_string());
+    thread_builder.spawn(move || {
+        // Assume the phone is present until we hear otherwise
+        let mut was_present = true;
+        loop {
+            let present = match query_bluetooth(&address) {
+                Ok((connected, rssi)) => match (min_rssi, rssi) {
+                    (Some(min), Some(rssi)) => connected && rssi >= min,
+                    _ => connected
+                },
+                Err(e) => {
+                    warn!("Failed to query Bluetooth device {}: {}", address, e);
+                    was_present
+                }
+            };
+
+            if present != was_present {
+                debug!("Phone {} is {}", address, if present { "back" } else { "gone" });
+                let event = match present {
+                    true => Event::Activity(ActivitySource::Phone),
+                    false => Event::DimNow
+                };
+                if let Err(e) = s.send(event) {
+                    warn!("{}", e);
+                }
+                was_present = present;
+            }
+
+            power::poll_sleep(interval);
+        }
+    })
+}
+
+
+// Creates a thread that runs a presence check command every so often. The
+// command exits successfully if someone is sitting at the machine, and could
+// be anything from a quick webcam face check to asking a presence daemon
+pub fn spawn_command(command: String, interval: Duration, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("command-presence".to_string());
+    thread_builder.spawn(move || {
+        let mut was_present = None;
+        loop {
+            let present = match Command::new("sh").arg("-c").arg(&command).status() {
+                Ok(status) => status.success(),
+                Err(e) => {
+                    warn!("Failed to run presence command: {}", e);
+                    false
+                }
+            };
+
+            if Some(present) != was_present {
+                debug!("Presence command reports {}", if present { "someone present" } else { "nobody present" });
+                if let Err(e) = s.send(Event::Presence(present)) {
+                    warn!("{}", e);
+                }
+                was_present = Some(present);
+            }
+
+            power::poll_sleep(interval);
+        }
+    })
+}
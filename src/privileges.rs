@@ -0,0 +1,103 @@
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::chown;
+use std::path::Path;
+use std::ptr;
+use tracing::{debug, info};
+
+use bl_control::error::BlError;
+
+
+// Turns the result of a libc call into an error saying what we were doing
+fn check(result: libc::c_int, context: impl std::fmt::Display) -> Result<(), BlError> {
+    match result {
+        0 => Ok(()),
+        _ => Err(BlError::io(context, io::Error::last_os_error()))
+    }
+}
+
+
+// Looks up a user's IDs by name
+fn lookup(user: &CStr) -> Result<(libc::uid_t, libc::gid_t), BlError> {
+    let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut found = ptr::null_mut();
+    let result = unsafe {
+        libc::getpwnam_r(user.as_ptr(), passwd.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut found)
+    };
+    if result != 0 {
+        return Err(BlError::io(format!("could not look up user {}", user.to_string_lossy()), io::Error::from_raw_os_error(result)));
+    }
+    if found.is_null() {
+        return Err(BlError::Config(format!("there is no user named {}", user.to_string_lossy())));
+    }
+
+    let passwd = unsafe { passwd.assume_init() };
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+
+// Looks up a group's ID by name
+pub fn lookup_group(group: &str) -> Result<libc::gid_t, BlError> {
+    let name = CString::new(group).map_err(|_| BlError::Config(format!("there is no group named {}", group)))?;
+    let mut entry = MaybeUninit::<libc::group>::uninit();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut found = ptr::null_mut();
+    let result = unsafe {
+        libc::getgrnam_r(name.as_ptr(), entry.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut found)
+    };
+    if result != 0 {
+        return Err(BlError::io(format!("could not look up group {}", group), io::Error::from_raw_os_error(result)));
+    }
+    if found.is_null() {
+        return Err(BlError::Config(format!("there is no group named {}", group)));
+    }
+
+    let entry = unsafe { entry.assume_init() };
+    Ok(entry.gr_gid)
+}
+
+
+// Gives up root once everything that needs it has been opened, becoming the
+// given user (with their groups) and optionally shutting ourselves into a
+// directory. The directories we still write to are handed over to the user
+pub fn drop_to(user: &str, root: Option<&Path>, writable: &[&Path]) -> Result<(), BlError> {
+    if unsafe { libc::geteuid() } != 0 {
+        debug!("Not running as root, so there are no privileges to drop");
+        return Ok(());
+    }
+
+    let name = CString::new(user).map_err(|_| BlError::Config(format!("there is no user named {}", user)))?;
+    let (uid, gid) = lookup(&name)?;
+
+    for dir in writable {
+        fs::create_dir_all(dir).map_err(|e| BlError::io(format!("could not create {}", dir.display()), e))?;
+        chown(dir, Some(uid), Some(gid)).map_err(|e| BlError::io(format!("could not give {} to {}", dir.display(), user), e))?;
+    }
+
+    // The groups have to go first, as only root can change them, and before
+    // the chroot, as they're looked up in /etc/group
+    check(unsafe { libc::initgroups(name.as_ptr(), gid) }, format!("could not take on the groups of {}", user))?;
+
+    if let Some(root) = root {
+        let path = CString::new(root.as_os_str().as_bytes())
+            .map_err(|_| BlError::Config(format!("can't chroot to {}", root.display())))?;
+        check(unsafe { libc::chroot(path.as_ptr()) }, format!("could not chroot to {}", root.display()))?;
+        env::set_current_dir("/").map_err(|e| BlError::io(format!("could not change to {}", root.display()), e))?;
+    }
+
+    check(unsafe { libc::setgid(gid) }, format!("could not switch to the group of {}", user))?;
+    check(unsafe { libc::setuid(uid) }, format!("could not switch to user {}", user))?;
+
+    // Make sure there's no way back
+    if unsafe { libc::setuid(0) } == 0 {
+        return Err(BlError::Config(format!("could still become root after switching to {}", user)));
+    }
+
+    info!("Dropped privileges to user {}", user);
+    Ok(())
+}
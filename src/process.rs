@@ -0,0 +1,101 @@
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::{debug, warn};
+use crate::{power, Event};
+
+// Processes that mean a game is being played
+const GAME_PROCESSES: [&str; 2] = ["gamescope", "gamescope-wl"];
+
+// Arguments that Steam is given when it runs in Big Picture mode
+const BIG_PICTURE_ARGS: [&str; 2] = ["-gamepadui", "-bigpicture"];
+
+
+// Calls the given function with the name and arguments of each running process
+fn for_each_process(mut f: impl FnMut(&str, &[String]) -> bool) -> bool {
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to list processes: {}", e);
+            return false;
+        }
+    };
+
+    for entry in entries.flatten() {
+        // Processes are the directories named after their PID
+        if !entry.file_name().to_string_lossy().chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+
+        // Processes can go away while we're looking at them
+        let name = match fs::read_to_string(entry.path().join("comm")) {
+            Ok(name) => name.trim().to_string(),
+            Err(_) => continue
+        };
+        let args: Vec<String> = match fs::read(entry.path().join("cmdline")) {
+            Ok(cmdline) => cmdline.split(|b| *b == 0).map(|a| String::from_utf8_lossy(a).into_owned()).collect(),
+            Err(_) => Vec::new()
+        };
+
+        if f(&name, &args) {
+            return true;
+        }
+    }
+
+    false
+}
+
+
+// Checks whether a game is running: gamescope, Steam in Big Picture mode or
+// one of the given processes
+fn game_running(games: &[String]) -> bool {
+    for_each_process(|name, args| {
+        GAME_PROCESSES.contains(&name)
+            || games.iter().any(|g| g == name)
+            || (name == "steam" && args.iter().any(|a| BIG_PICTURE_ARGS.contains(&a.as_str())))
+    })
+}
+
+
+// Checks whether any of the given processes are running
+fn any_running(names: &[String]) -> bool {
+    for_each_process(|name, _| names.iter().any(|n| n == name))
+}
+
+
+// Creates a thread that runs a check every so often and posts to the main
+// loop whenever its answer changes
+fn spawn_watch(thread_name: &str, description: &'static str, interval: Duration, check: impl Fn() -> bool + Send + 'static,
+        to_event: fn(bool) -> Event, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name(thread_name.to_string());
+    thread_builder.spawn(move || {
+        let mut was_running = false;
+        loop {
+            let running = check();
+            if running != was_running {
+                debug!("{} {}", description, if running { "started" } else { "ended" });
+                if let Err(e) = s.send(to_event(running)) {
+                    warn!("{}", e);
+                }
+                was_running = running;
+            }
+
+            power::poll_sleep(interval);
+        }
+    })
+}
+
+
+// Creates a thread that posts to the main loop when a game starts or stops
+pub fn spawn_game_watch(games: Vec<String>, interval: Duration, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    spawn_watch("game-watch", "Game mode", interval, move || game_running(&games), Event::GameMode, s)
+}
+
+
+// Creates a thread that posts to the main loop when any of the given
+// processes start running, or when the last of them stops
+pub fn spawn_inhibit_watch(names: Vec<String>, interval: Duration, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    spawn_watch("inhibit-watch", "Inhibiting process", interval, move || any_running(&names), Event::ProcessInhibit, s)
+}
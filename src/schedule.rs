@@ -0,0 +1,149 @@
+use std::thread;
+use std::time::Duration;
+use chrono::{Local, NaiveTime};
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::{debug, warn};
+use crate::Event;
+use crate::sunrise::parse_time;
+
+// How often to check the time. The wall clock is read afresh each time rather
+// than sleeping until the next change, so that the clock being changed (or
+// going back and forth for daylight saving) is picked up
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+
+// A time of day during which the backlight is capped or set to a level
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Period {
+    pub from: NaiveTime,
+    pub to: NaiveTime,
+    // The level the backlight is capped at during the period
+    pub max_level: Option<u8>,
+    // The level the backlight is set to when the period starts
+    pub level: Option<u8>
+}
+
+impl Period {
+    // Whether the period covers the given time of day. Periods that end
+    // earlier in the day than they start carry on past midnight, and those
+    // that end when they start last all day
+    fn covers(&self, now: NaiveTime) -> bool {
+        match self.from < self.to {
+            true => self.from <= now && now < self.to,
+            false => now >= self.from || now < self.to
+        }
+    }
+}
+
+
+// Parses a period given as comma-separated KEY=VALUE pairs, e.g.
+// from=22:00,to=07:00,max_level=15
+pub fn parse_period(s: &str) -> Result<Period, String> {
+    let (mut from, mut to, mut max_level, mut level) = (None, None, None, None);
+    for pair in s.split(',') {
+        let (key, value) = match pair.split_once('=') {
+            Some((key, value)) => (key.trim().replace('-', "_"), value.trim()),
+            None => return Err(String::from("expected KEY=VALUE pairs, e.g. from=22:00,to=07:00,max_level=15"))
+        };
+        match key.as_str() {
+            "from" => from = Some(parse_time(value)?),
+            "to" => to = Some(parse_time(value)?),
+            "max_level" => max_level = Some(value.parse().map_err(|e| format!("invalid max_level: {}", e))?),
+            "level" => level = Some(value.parse().map_err(|e| format!("invalid level: {}", e))?),
+            key => return Err(format!("unknown key `{}`, expected from, to, max_level or level", key))
+        }
+    }
+
+    match (from, to) {
+        (Some(from), Some(to)) if max_level.is_some() || level.is_some() => Ok(Period { from, to, max_level, level }),
+        (Some(_), Some(_)) => Err(String::from("expected a max_level or level for the period")),
+        _ => Err(String::from("expected both a from and a to time"))
+    }
+}
+
+
+// Creates a thread that posts to the main loop whenever the periods in force
+// change, with the lowest cap of those in force and the level of any that
+// have just started
+pub fn spawn(periods: Vec<Period>, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("schedule".to_string());
+    thread_builder.spawn(move || {
+        let mut was_active: Option<Vec<bool>> = None;
+        loop {
+            let now = Local::now().time();
+            let active: Vec<bool> = periods.iter().map(|p| p.covers(now)).collect();
+            if was_active.as_ref() != Some(&active) {
+                let cap = periods.iter().zip(&active)
+                    .filter_map(|(p, &on)| p.max_level.filter(|_| on))
+                    .min();
+
+                // Only periods that have started since the last check set the
+                // level, so that the user can change it during them
+                let level = periods.iter().zip(&active).enumerate()
+                    .filter(|&(i, (_, &on))| on && !was_active.as_ref().is_some_and(|was| was[i]))
+                    .filter_map(|(_, (p, _))| p.level)
+                    .next_back();
+
+                debug!("Schedule caps the backlight at {:?} and sets it to {:?}", cap, level);
+                if let Err(e) = s.send(Event::Schedule { cap, level }) {
+                    warn!("{}", e);
+                    return;
+                }
+                was_active = Some(active);
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    fn period(from: &str, to: &str) -> Period {
+        Period { from: time(from), to: time(to), max_level: Some(10), level: None }
+    }
+
+    #[test]
+    fn covers_periods_within_a_day() {
+        let period = period("09:00", "17:00");
+        assert!(period.covers(time("09:00")));
+        assert!(period.covers(time("12:00")));
+        assert!(!period.covers(time("17:00")));
+        assert!(!period.covers(time("08:59")));
+    }
+
+    #[test]
+    fn covers_periods_past_midnight() {
+        let period = period("22:00", "07:00");
+        assert!(period.covers(time("22:00")));
+        assert!(period.covers(time("23:59")));
+        assert!(period.covers(time("00:00")));
+        assert!(period.covers(time("06:59")));
+        assert!(!period.covers(time("07:00")));
+        assert!(!period.covers(time("12:00")));
+    }
+
+    #[test]
+    fn covers_all_day_periods() {
+        let period = period("08:00", "08:00");
+        assert!(period.covers(time("08:00")));
+        assert!(period.covers(time("07:59")));
+        assert!(period.covers(time("20:00")));
+    }
+
+    #[test]
+    fn parses_periods() {
+        let period = parse_period("from=22:00,to=07:00,max-level=15").unwrap();
+        assert_eq!(period, Period { from: time("22:00"), to: time("07:00"), max_level: Some(15), level: None });
+        assert!(parse_period("from=22:00,to=07:00").is_err());
+        assert!(parse_period("from=22:00,max_level=15").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{ConnectionExt as _, ImageFormat};
+
+use tracing::{info, warn};
+use crate::{power, Event};
+
+// How many points across and down the screen are sampled
+const GRID_WIDTH: u16 = 16;
+const GRID_HEIGHT: u16 = 9;
+
+// How far a channel has to move before the keyboard is updated, so that small
+// changes on screen don't keep rewriting the color
+const MIN_CHANGE: i16 = 8;
+
+
+// Samples a grid of pixels across the screen and averages them. Each sample
+// is a single pixel so that very little has to be copied from the X server
+fn sample_screen(conn: &impl Connection, screen_num: usize) -> Result<(u8, u8, u8), String> {
+    let screen = &conn.setup().roots[screen_num];
+    let (width, height) = (screen.width_in_pixels, screen.height_in_pixels);
+
+    // Send all of the requests before waiting on any of the replies
+    let mut cookies = Vec::new();
+    for row in 0..GRID_HEIGHT {
+        for col in 0..GRID_WIDTH {
+            let x = (width / GRID_WIDTH) * col + width / GRID_WIDTH / 2;
+            let y = (height / GRID_HEIGHT) * row + height / GRID_HEIGHT / 2;
+            let cookie = conn.get_image(ImageFormat::Z_PIXMAP, screen.root, x as i16, y as i16, 1, 1, !0)
+                .map_err(|e| e.to_string())?;
+            cookies.push(cookie);
+        }
+    }
+
+    let (mut r, mut g, mut b) = (0u32, 0u32, 0u32);
+    let count = cookies.len() as u32;
+    for cookie in cookies {
+        let reply = cookie.reply().map_err(|e| e.to_string())?;
+
+        // True color screens give pixels as BGRX
+        if reply.data.len() < 3 {
+            return Err(format!("unsupported pixel format at depth {}", reply.depth));
+        }
+        b += reply.data[0] as u32;
+        g += reply.data[1] as u32;
+        r += reply.data[2] as u32;
+    }
+
+    Ok(((r / count) as u8, (g / count) as u8, (b / count) as u8))
+}
+
+
+// Periodically samples the screen and posts its color whenever it changes
+fn watch_screen(interval: Duration, s: &UnboundedSender<Event>) -> Result<(), String> {
+    let (conn, screen_num) = x11rb::connect(None).map_err(|e| e.to_string())?;
+    info!("Matching the backlight color to the screen");
+
+    let mut last: Option<(u8, u8, u8)> = None;
+    loop {
+        // Copying from the screen is the most expensive thing we do, so stop
+        // altogether while saving power
+        if power::low_power() {
+            power::poll_sleep(interval);
+            continue;
+        }
+
+        let (r, g, b) = sample_screen(&conn, screen_num)?;
+        let changed = match last {
+            Some((lr, lg, lb)) => [(r, lr), (g, lg), (b, lb)].iter()
+                .any(|(c, l)| (*c as i16 - *l as i16).abs() >= MIN_CHANGE),
+            None => true
+        };
+
+        if changed {
+            if let Err(e) = s.send(Event::ScreenColor(r, g, b)) {
+                warn!("{}", e);
+            }
+            last = Some((r, g, b));
+        }
+
+        thread::sleep(interval);
+    }
+}
+
+
+// Creates a thread that posts the screen's average color to the main loop
+pub fn spawn_x11(interval: Duration, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("x11-screen".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = watch_screen(interval, &s) {
+            warn!("Stopped matching the screen color: {}", e);
+        }
+    })
+}
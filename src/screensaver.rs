@@ -0,0 +1,52 @@
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+use zbus::MatchRule;
+use zbus::blocking::{Connection, MessageIterator};
+use zbus::message::Type;
+
+use tracing::{debug, info, warn};
+use crate::Event;
+
+
+// Follows the desktop's screensaver or locker coming on and going off. KDE
+// and xscreensaver's bridge use org.freedesktop.ScreenSaver, while GNOME,
+// MATE and Cinnamon have their own, but they all send ActiveChanged
+fn watch_screensaver(s: &UnboundedSender<Event>) -> Result<(), zbus::Error> {
+    let conn = Connection::session()?;
+    let rule = MatchRule::builder().msg_type(Type::Signal).member("ActiveChanged")?.build();
+    let messages = MessageIterator::for_match_rule(rule, &conn, None)?;
+    info!("Watching the desktop's screensaver for locks");
+
+    let mut was_active = None;
+    for message in messages {
+        let message = message?;
+        let from_screensaver = message.header().interface().is_some_and(|i| i.as_str().ends_with(".ScreenSaver"));
+        if !from_screensaver {
+            continue;
+        }
+
+        // Both the freedesktop and GNOME interfaces can send the same change
+        let active: bool = message.body().deserialize()?;
+        if Some(active) != was_active {
+            debug!("Screensaver {}", if active { "started" } else { "stopped" });
+            if let Err(e) = s.send(Event::Locked(active)) {
+                warn!("{}", e);
+            }
+            was_active = Some(active);
+        }
+    }
+
+    Ok(())
+}
+
+
+// Creates a thread that posts to the main loop whenever the desktop's
+// screensaver comes on or goes off
+pub fn spawn_watcher(s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("screensaver".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = watch_screensaver(&s) {
+            warn!("Stopped watching the screensaver: {}", e);
+        }
+    })
+}
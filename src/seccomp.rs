@@ -0,0 +1,118 @@
+use std::io;
+use std::mem::offset_of;
+use tracing::info;
+
+use bl_control::error::BlError;
+
+// The architecture the filter is built for, from linux/audit.h. Calls made
+// as another architecture (e.g. 32-bit ones on x86_64) have other numbers, so
+// they're never let through
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc00000b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+// The system calls the daemon needs once it's running: reading the input
+// devices and talking to the controller, the event loop and its threads,
+// sockets for the control socket, D-Bus, the journal and following, and files
+// for the status file, hotplugged devices and sysfs
+const ALLOWED: &[libc::c_long] = &[
+    libc::SYS_read, libc::SYS_readv, libc::SYS_pread64, libc::SYS_write, libc::SYS_writev, libc::SYS_pwrite64,
+    libc::SYS_openat, libc::SYS_close, libc::SYS_lseek, libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx,
+    libc::SYS_fcntl, libc::SYS_dup, libc::SYS_dup3, libc::SYS_pipe2, libc::SYS_getdents64, libc::SYS_readlinkat,
+    libc::SYS_faccessat, libc::SYS_faccessat2, libc::SYS_renameat, libc::SYS_renameat2, libc::SYS_mkdirat,
+    libc::SYS_unlinkat, libc::SYS_getcwd, libc::SYS_fsync, libc::SYS_memfd_create, libc::SYS_ioctl,
+    libc::SYS_ppoll, libc::SYS_epoll_create1, libc::SYS_epoll_ctl, libc::SYS_epoll_pwait, libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2, libc::SYS_timerfd_create, libc::SYS_timerfd_settime, libc::SYS_timerfd_gettime,
+    libc::SYS_inotify_init1, libc::SYS_inotify_add_watch, libc::SYS_inotify_rm_watch,
+    libc::SYS_socket, libc::SYS_socketpair, libc::SYS_connect, libc::SYS_bind, libc::SYS_listen, libc::SYS_accept4,
+    libc::SYS_sendto, libc::SYS_sendmsg, libc::SYS_sendmmsg, libc::SYS_recvfrom, libc::SYS_recvmsg,
+    libc::SYS_getsockopt, libc::SYS_setsockopt, libc::SYS_getsockname, libc::SYS_getpeername, libc::SYS_shutdown,
+    libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mremap, libc::SYS_mprotect, libc::SYS_madvise, libc::SYS_brk,
+    libc::SYS_futex, libc::SYS_clone, libc::SYS_clone3, libc::SYS_set_robust_list, libc::SYS_rseq,
+    libc::SYS_sched_yield, libc::SYS_sched_getaffinity, libc::SYS_prctl, libc::SYS_exit, libc::SYS_exit_group,
+    libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+    libc::SYS_tgkill, libc::SYS_getpid, libc::SYS_gettid, libc::SYS_getuid, libc::SYS_geteuid, libc::SYS_getgid,
+    libc::SYS_getegid, libc::SYS_uname, libc::SYS_getrandom, libc::SYS_clock_gettime, libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep, libc::SYS_nanosleep, libc::SYS_gettimeofday,
+    // Older calls that only x86_64 has, which libraries may still use there
+    #[cfg(target_arch = "x86_64")] libc::SYS_open,
+    #[cfg(target_arch = "x86_64")] libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")] libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")] libc::SYS_access,
+    #[cfg(target_arch = "x86_64")] libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")] libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")] libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")] libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")] libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")] libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")] libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")] libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")] libc::SYS_epoll_create
+];
+
+
+// A BPF instruction
+fn statement(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+// A BPF jump, taken if the accumulator equals k
+fn jump_if(k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16, jt, jf, k }
+}
+
+
+// Builds the filter, which lets the allowed calls through and fails anything
+// else with EPERM. Failing rather than killing the daemon means a call we
+// didn't foresee can't leave the backlight dimmed
+fn filter(arch: u32, allowed: &[libc::c_long]) -> Vec<libc::sock_filter> {
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let mut program = vec![
+        statement(load, offset_of!(libc::seccomp_data, arch) as u32),
+        jump_if(arch, 1, 0),
+        statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(load, offset_of!(libc::seccomp_data, nr) as u32)
+    ];
+
+    // Each allowed call jumps over the rest to the final return
+    for (i, nr) in allowed.iter().enumerate() {
+        program.push(jump_if(*nr as u32, (allowed.len() - i) as u8, 0));
+    }
+    program.push(statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA)));
+    program.push(statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+    program
+}
+
+
+// Restricts every thread of the daemon to the system calls it needs from now
+// on, so that someone taking it over can't do much with it. Anything that
+// needs more, like switching user, has to be done first. Programs we run
+// would be held to the same calls, so nothing can be run afterwards
+pub fn install() -> Result<(), BlError> {
+    let arch = AUDIT_ARCH.ok_or_else(|| BlError::Config(String::from("seccomp filtering isn't supported on this architecture")))?;
+    let program = filter(arch, ALLOWED);
+    let prog = libc::sock_fprog { len: program.len() as u16, filter: program.as_ptr() as *mut libc::sock_filter };
+
+    // Without this only root could install the filter. It also stops
+    // anything from gaining privileges
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(BlError::io("could not stop gaining privileges", io::Error::last_os_error()));
+    }
+
+    // The threads that are already running are covered too
+    let result = unsafe {
+        libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, libc::SECCOMP_FILTER_FLAG_TSYNC, &prog)
+    };
+    match result {
+        0 => (),
+        r if r < 0 => return Err(BlError::io("could not install the seccomp filter", io::Error::last_os_error())),
+        // A thread ID, for a thread that couldn't be given the filter
+        r => return Err(BlError::Config(format!("could not install the seccomp filter on thread {}", r)))
+    }
+
+    info!("Restricted the daemon to {} system calls", ALLOWED.len());
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+use bl_control::error::BlError;
+
+// The name of the unit, for systemctl
+const UNIT_NAME: &str = "bl-control.service";
+
+
+// The arguments we were run with, up to the subcommand, which are what the
+// daemon should be run with. Options after the subcommand are its own
+fn daemon_args(subcommand: &str) -> Vec<OsString> {
+    env::args_os().skip(1).take_while(|arg| arg != subcommand).collect()
+}
+
+
+// Quotes an argument for a unit file's command line. Specifiers and variables
+// are escaped so that systemd passes the argument through as it is
+fn quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty() && !escaped.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\') {
+        return escaped;
+    }
+
+    let mut quoted = String::from("\"");
+    for c in escaped.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+
+// Builds the unit file. A system service waits for udev to have found the
+// devices that were there at boot, as otherwise the keyboard may not be there
+// yet when it starts
+fn unit(command: &str, user: bool, dbus: bool) -> String {
+    let mut after = Vec::new();
+    if !user {
+        after.extend(["systemd-udevd.service", "systemd-udev-trigger.service"]);
+    }
+    if dbus {
+        after.push("dbus.service");
+    }
+
+    let mut unit = String::from("[Unit]\nDescription=Keyboard backlight control\n");
+    if !after.is_empty() {
+        unit.push_str(&format!("After={}\n", after.join(" ")));
+    }
+    unit.push_str(&format!(
+        "\n[Service]\nType=notify\nExecStart={}\nExecReload=/bin/kill -HUP $MAINPID\nWatchdogSec=30\nRestart=on-failure\n\n[Install]\nWantedBy={}\n",
+        command,
+        if user { "default.target" } else { "multi-user.target" }
+    ));
+    unit
+}
+
+
+// Where the unit goes, for either the system or the user's own manager
+fn unit_path(user: bool) -> Result<PathBuf, BlError> {
+    if !user {
+        return Ok(PathBuf::from("/etc/systemd/system").join(UNIT_NAME));
+    }
+
+    let config_dir = match (env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME")) {
+        (Some(dir), _) => PathBuf::from(dir),
+        (None, Some(home)) => PathBuf::from(home).join(".config"),
+        (None, None) => return Err(BlError::Config(String::from("could not find the user's config directory")))
+    };
+    Ok(config_dir.join("systemd").join("user").join(UNIT_NAME))
+}
+
+
+// Runs systemctl for either manager, failing if it does
+fn systemctl(user: bool, args: &[&str]) -> Result<(), BlError> {
+    let mut command = Command::new("systemctl");
+    if user {
+        command.arg("--user");
+    }
+    let status = command.args(args).status()
+        .map_err(|e| BlError::io("could not run systemctl", e))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(BlError::io(format!("systemctl {} failed", args.join(" ")), io::Error::other(status.to_string())))
+    }
+}
+
+
+// Writes a unit that runs the daemon with the options we were given, and
+// optionally enables and starts it, giving where it was written
+pub fn install(subcommand: &str, user: bool, enable: bool, dbus: bool) -> Result<PathBuf, BlError> {
+    let exe = env::current_exe().map_err(|e| BlError::io("could not find our own binary", e))?;
+    let mut command = Vec::new();
+    for arg in std::iter::once(exe.into_os_string()).chain(daemon_args(subcommand)) {
+        let arg = arg.into_string()
+            .map_err(|arg| BlError::Config(format!("can't put {} in a unit file", arg.to_string_lossy())))?;
+        command.push(quote(&arg));
+    }
+
+    let path = unit_path(user)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| BlError::io(format!("could not create {}", parent.display()), e))?;
+    }
+    fs::write(&path, unit(&command.join(" "), user, dbus))
+        .map_err(|e| BlError::io(format!("could not write {}", path.display()), e))?;
+
+    systemctl(user, &["daemon-reload"])?;
+    if enable {
+        systemctl(user, &["enable", "--now", UNIT_NAME])?;
+    }
+    Ok(path)
+}
@@ -0,0 +1,45 @@
+use std::fs;
+use std::path::Path;
+
+use bl_control::{Device, DeviceDescription, KEYBOARD_CHANNEL, parse_zone, read_channel_level, write_channel_level};
+use bl_control::error::BlError;
+
+
+// Saves the level of the keyboard and each zone to a file, one per line in the
+// same NAME=CHANNEL:LEVEL form as --zone. The controller can't report its
+// color or effect, so only the levels can be saved
+pub fn dump(handle: &mut Device, desc: &DeviceDescription, path: &Path) -> Result<(), BlError> {
+    let mut channels = vec![(String::from("keyboard"), KEYBOARD_CHANNEL)];
+    for zone in &desc.zones {
+        channels.push((zone.name.clone(), zone.channel));
+    }
+
+    let mut contents = String::new();
+    for (name, channel) in channels {
+        let level = read_channel_level(handle, desc, channel)?;
+        contents.push_str(&format!("{}=0x{:02x}:{}\n", name, channel, level));
+    }
+
+    fs::write(path, contents).map_err(|e| BlError::io(format!("could not write {}", path.display()), e))
+}
+
+
+// Restores the levels saved by dump(). Every line is checked before anything
+// is written so that a bad file doesn't leave the controller half restored
+pub fn load(handle: &mut Device, desc: &DeviceDescription, path: &Path) -> Result<(), BlError> {
+    let contents = fs::read_to_string(path).map_err(|e| BlError::io(format!("could not read {}", path.display()), e))?;
+
+    let mut channels = Vec::new();
+    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+        let zone = parse_zone(line.trim()).map_err(|e| BlError::Config(format!("{}: {}", path.display(), e)))?;
+        match zone.level {
+            Some(level) => channels.push((zone.channel, level)),
+            None => return Err(BlError::Config(format!("{}: no level saved for {}", path.display(), zone.name)))
+        }
+    }
+
+    for (channel, level) in channels {
+        write_channel_level(handle, desc, channel, level)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+// How often wait-idle checks the status file
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+
+// Works out where the daemon publishes its status by default
+pub fn default_path() -> PathBuf {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from("/run")
+    };
+    dir.join("bl-control").join("status")
+}
+
+
+// Publishes whether the backlight is dimmed, along with when that started, as
+// a line like "dimmed=1 since=1700000000". The file is replaced rather than
+// rewritten so that readers never see half of it
+pub fn write(path: &Path, dimmed: bool) -> std::io::Result<()> {
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, format!("dimmed={} since={}\n", dimmed as u8, since))?;
+    fs::rename(&temp, path)
+}
+
+
+// Reads the status published by write(), giving whether the backlight is
+// dimmed and for how long it has been that way
+pub fn read(path: &Path) -> Option<(bool, Duration)> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut dimmed = None;
+    let mut since = None;
+    for part in contents.split_whitespace() {
+        match part.split_once('=')? {
+            ("dimmed", v) => dimmed = Some(v == "1"),
+            ("since", v) => since = v.parse::<u64>().ok(),
+            _ => ()
+        }
+    }
+
+    let since = UNIX_EPOCH + Duration::from_secs(since?);
+    Some((dimmed?, since.elapsed().unwrap_or(Duration::ZERO)))
+}
+
+
+// Blocks until the daemon has been dimmed for at least the given time,
+// returning false if that doesn't happen before the deadline
+pub fn wait_idle(path: &Path, idle_for: Duration, max_wait: Option<Duration>) -> bool {
+    let start = Instant::now();
+    let mut warned = false;
+    loop {
+        match read(path) {
+            Some((true, dimmed_for)) if dimmed_for >= idle_for => return true,
+            Some(_) => (),
+            None if !warned => {
+                info!("No status at {}, waiting for the daemon", path.display());
+                warned = true;
+            },
+            None => ()
+        }
+
+        if let Some(max_wait) = max_wait {
+            if start.elapsed() >= max_wait {
+                return false;
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
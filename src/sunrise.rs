@@ -0,0 +1,45 @@
+use std::thread;
+use std::time::Duration;
+use chrono::{Local, NaiveTime};
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::warn;
+use crate::Event;
+
+
+// Parses a wall-clock time given as HH:MM
+pub fn parse_time(s: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(s, "%H:%M").map_err(|e| format!("expected HH:MM: {}", e))
+}
+
+
+// Works out how long it is until the given time of day next comes around
+fn time_until(at: NaiveTime) -> Duration {
+    let now = Local::now().naive_local();
+    let mut next = now.date().and_time(at);
+    if next <= now {
+        next += chrono::Duration::days(1);
+    }
+    (next - now).to_std().unwrap_or(Duration::ZERO)
+}
+
+
+// Creates a thread that brings the backlight up from off to the target level
+// over the given duration, starting at the given time every day
+pub fn spawn(at: NaiveTime, duration: Duration, target: u8, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("sunrise".to_string());
+    thread_builder.spawn(move || {
+        // Spread the levels evenly over the duration
+        let step_time = duration / target.max(1) as u32;
+        loop {
+            thread::sleep(time_until(at));
+
+            for level in 0..=target {
+                if let Err(e) = s.send(Event::Sunrise(level)) {
+                    warn!("{}", e);
+                }
+                thread::sleep(step_time);
+            }
+        }
+    })
+}
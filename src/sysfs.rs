@@ -0,0 +1,66 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{BacklightBackend, Capabilities};
+use crate::error::BlError;
+
+// Where the kernel's LED class devices live
+const LEDS_DIR: &str = "/sys/class/leds";
+
+
+// Finds the first keyboard backlight LED the kernel knows about
+pub fn find() -> Result<PathBuf, BlError> {
+    let entries = fs::read_dir(LEDS_DIR).map_err(|e| BlError::io(format!("could not list {}", LEDS_DIR), e))?;
+    let mut found: Vec<PathBuf> = entries.flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().contains("kbd_backlight"))
+        .map(|entry| entry.path())
+        .collect();
+
+    // Sort so that the same one is picked every time
+    found.sort();
+    found.into_iter().next().ok_or_else(|| BlError::DeviceNotFound(format!("no keyboard backlight in {}", LEDS_DIR)))
+}
+
+
+// Reads a number from one of the LED's attributes
+fn read_value(path: &Path) -> Result<u32, BlError> {
+    let contents = fs::read_to_string(path).map_err(|e| BlError::io(format!("could not read {}", path.display()), e))?;
+    contents.trim().parse::<u32>()
+        .map_err(|e| BlError::io(format!("could not read {}", path.display()), io::Error::new(io::ErrorKind::InvalidData, e)))
+}
+
+
+// A keyboard backlight exposed by the kernel through the LED class, as many
+// laptops without the ITE controller do
+pub struct SysfsBacklight {
+    path: PathBuf,
+    max_level: u8
+}
+
+impl SysfsBacklight {
+    // Opens the LED in the given directory under /sys/class/leds
+    pub fn open(path: &Path) -> Result<SysfsBacklight, BlError> {
+        let max_brightness = read_value(&path.join("max_brightness"))?;
+        Ok(SysfsBacklight {
+            path: path.to_path_buf(),
+            max_level: max_brightness.min(u8::MAX as u32) as u8
+        })
+    }
+}
+
+impl BacklightBackend for SysfsBacklight {
+    fn get(&mut self) -> Result<u8, BlError> {
+        read_value(&self.path.join("brightness")).map(|level| level.min(self.max_level as u32) as u8)
+    }
+
+    fn set(&mut self, level: u8) -> Result<(), BlError> {
+        let path = self.path.join("brightness");
+        fs::write(&path, level.min(self.max_level).to_string())
+            .map_err(|e| BlError::io(format!("could not write {}", path.display()), e))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { max_level: self.max_level, color: false, ..Capabilities::default() }
+    }
+}
@@ -0,0 +1,122 @@
+// The length of a feature report unless the device says otherwise
+pub const DEFAULT_REPORT_LENGTH: usize = 8;
+
+
+// A single byte of a report template
+#[derive(Clone, Copy, PartialEq)]
+enum TemplateByte {
+    // A fixed value
+    Literal(u8),
+    // The brightness level
+    Level,
+    // The effect channel being addressed
+    Channel
+}
+
+
+// A feature report with placeholders for the values that change between
+// requests, e.g. "[0x08, {channel}, 0x33, 0x00, {level}, 0x00, 0x00, 0x00]".
+// Templates shorter than the report length are padded with zeroes
+#[derive(Clone)]
+pub struct ReportTemplate {
+    bytes: Vec<TemplateByte>
+}
+
+impl ReportTemplate {
+    // Parses a template from a comma-separated list of bytes (decimal or hex)
+    // and placeholders, optionally surrounded by square brackets
+    pub fn parse(s: &str) -> Result<ReportTemplate, String> {
+        let s = s.trim();
+        let s = s.strip_prefix('[').unwrap_or(s);
+        let s = s.strip_suffix(']').unwrap_or(s);
+
+        let mut bytes = Vec::new();
+        for part in s.split(',') {
+            let byte = match part.trim() {
+                "{level}" => TemplateByte::Level,
+                "{channel}" => TemplateByte::Channel,
+                p => TemplateByte::Literal(clap_num::maybe_hex::<u8>(p)?)
+            };
+            bytes.push(byte);
+        }
+
+        if !bytes.contains(&TemplateByte::Level) {
+            return Err(String::from("template has no {level} placeholder"));
+        }
+
+        Ok(ReportTemplate { bytes })
+    }
+
+    // The number of bytes given in the template
+    pub fn byte_count(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // Builds a report of the given length from the template
+    pub fn fill(&self, channel: u8, level: u8, length: usize) -> Vec<u8> {
+        let mut data = vec![0; length];
+        for (i, byte) in self.bytes.iter().enumerate().take(length) {
+            data[i] = match byte {
+                TemplateByte::Literal(b) => *b,
+                TemplateByte::Level => level,
+                TemplateByte::Channel => channel
+            };
+        }
+        data
+    }
+
+    // Returns the position of the level in the report. When a template is used
+    // for a query, this is where the level is found in the reply
+    pub fn level_index(&self) -> usize {
+        // parse() guarantees there is a level placeholder
+        self.bytes.iter().position(|b| *b == TemplateByte::Level).unwrap()
+    }
+}
+
+
+// Pads the start of a report out to the given length with zeroes
+pub fn pad_report(bytes: &[u8], length: usize) -> Vec<u8> {
+    let mut data = vec![0; length];
+    let count = bytes.len().min(length);
+    data[..count].copy_from_slice(&bytes[..count]);
+    data
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_templates() {
+        let template = ReportTemplate::parse("[0x08, {channel}, 0x33, 0x00, {level}, 0x00]").unwrap();
+        assert_eq!(template.byte_count(), 6);
+        assert_eq!(template.level_index(), 4);
+        assert_eq!(template.fill(2, 30, 8), vec![0x08, 2, 0x33, 0, 30, 0, 0, 0]);
+    }
+
+    #[test]
+    fn parses_templates_without_brackets() {
+        let template = ReportTemplate::parse("1, 2, {level}").unwrap();
+        assert_eq!(template.fill(0, 9, 3), vec![1, 2, 9]);
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        assert!(ReportTemplate::parse("[0x08, {channel}]").is_err());
+        assert!(ReportTemplate::parse("[0x08, {level}, 0x100]").is_err());
+        assert!(ReportTemplate::parse("[0x08, {level}, {other}]").is_err());
+    }
+
+    #[test]
+    fn truncates_long_templates() {
+        let template = ReportTemplate::parse("{level}, 1, 2, 3").unwrap();
+        assert_eq!(template.fill(0, 5, 2), vec![5, 1]);
+    }
+
+    #[test]
+    fn pads_reports() {
+        assert_eq!(pad_report(&[0x80], 4), vec![0x80, 0, 0, 0]);
+        assert_eq!(pad_report(&[1, 2, 3], 2), vec![1, 2]);
+    }
+}
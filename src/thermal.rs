@@ -0,0 +1,84 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+use tracing::{debug, warn};
+use crate::{power, Event};
+
+// Where the kernel lists hardware monitoring devices
+const HWMON_PATH: &str = "/sys/class/hwmon";
+
+// How far the temperature has to fall below the limit before the cap is
+// lifted, so that it doesn't flap around the limit
+const HYSTERESIS: f64 = 5.0;
+
+
+// Reads a temperature in degrees Celsius from a hwmon tempN_input file, which
+// gives it in millidegrees
+fn read_temperature(path: &Path) -> Option<f64> {
+    let millidegrees: f64 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    Some(millidegrees / 1000.0)
+}
+
+
+// Finds the hottest of all of the temperatures the kernel knows about
+fn hottest() -> Result<f64, String> {
+    let devices = fs::read_dir(HWMON_PATH).map_err(|e| e.to_string())?;
+    let mut hottest = None;
+    for device in devices.flatten() {
+        let files = match fs::read_dir(device.path()) {
+            Ok(files) => files,
+            Err(_) => continue
+        };
+        for file in files.flatten() {
+            let name = file.file_name().to_string_lossy().into_owned();
+            if !(name.starts_with("temp") && name.ends_with("_input")) {
+                continue;
+            }
+            if let Some(t) = read_temperature(&file.path()) {
+                hottest = Some(hottest.map_or(t, |h: f64| h.max(t)));
+            }
+        }
+    }
+
+    hottest.ok_or_else(|| String::from("no temperature sensors found"))
+}
+
+
+// Creates a thread that checks the temperature every so often and caps the
+// backlight level while it's over the limit. The sensor is a tempN_input file,
+// otherwise the hottest sensor is used
+pub fn spawn_cap(sensor: Option<PathBuf>, limit: f64, cap: u8, interval: Duration, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("thermal-cap".to_string());
+    thread_builder.spawn(move || {
+        let mut capped = false;
+        loop {
+            let temperature = match &sensor {
+                Some(sensor) => read_temperature(sensor).ok_or_else(|| format!("could not read {}", sensor.display())),
+                None => hottest()
+            };
+
+            match temperature {
+                Ok(t) => {
+                    let should_cap = match capped {
+                        true => t > limit - HYSTERESIS,
+                        false => t >= limit
+                    };
+                    if should_cap != capped {
+                        debug!("Temperature is {:.1}C, {}", t, if should_cap { "capping backlight level" } else { "lifting backlight cap" });
+                        let event = Event::ThermalCap(if should_cap { Some(cap) } else { None });
+                        if let Err(e) = s.send(event) {
+                            warn!("{}", e);
+                        }
+                        capped = should_cap;
+                    }
+                },
+                Err(e) => warn!("Failed to read temperature: {}", e)
+            }
+
+            power::poll_sleep(interval);
+        }
+    })
+}
@@ -0,0 +1,77 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+use bl_control::error::BlError;
+
+// Where the rules go. They must come before 73-seat-late.rules, which turns
+// the uaccess tag into access for the logged-in user
+const RULES_PATH: &str = "/etc/udev/rules.d/70-bl-control.rules";
+
+
+// Who is given access to the devices, either a group or whoever is logged in
+// at the seat
+fn access(group: &str, uaccess: bool) -> String {
+    match uaccess {
+        true => String::from("TAG+=\"uaccess\""),
+        false => format!("GROUP=\"{}\", MODE=\"0660\"", group)
+    }
+}
+
+
+// Builds the rules for the controller, through both libusb and hidraw, for
+// the keyboards' event devices, and for the kernel's keyboard backlight LED
+fn rules(vendor_id: u16, product_ids: &[u16], uaccess: bool) -> String {
+    let mut rules = String::from("# Lets bl-control run without being root. Written by bl-control install-udev-rules\n");
+
+    rules.push_str("\n# The controller\n");
+    for product_id in product_ids {
+        for (subsystem, attrs) in [("usb", "ATTR"), ("hidraw", "ATTRS")] {
+            rules.push_str(&format!(
+                "SUBSYSTEM==\"{}\", {}{{idVendor}}==\"{:04x}\", {}{{idProduct}}==\"{:04x}\", {}\n",
+                subsystem, attrs, vendor_id, attrs, product_id, access("plugdev", uaccess)
+            ));
+        }
+    }
+
+    rules.push_str("\n# The keyboards, to see key presses\n");
+    rules.push_str(&format!(
+        "SUBSYSTEM==\"input\", KERNEL==\"event*\", ENV{{ID_INPUT_KEYBOARD}}==\"1\", {}\n",
+        access("input", uaccess)
+    ));
+
+    // sysfs attributes can't be tagged, so the LED can only be given to a
+    // group
+    if !uaccess {
+        rules.push_str("\n# The kernel's keyboard backlight, for the sysfs backend\n");
+        rules.push_str(
+            "SUBSYSTEM==\"leds\", KERNEL==\"*kbd_backlight*\", RUN+=\"/bin/chgrp plugdev /sys%p/brightness\", RUN+=\"/bin/chmod g+w /sys%p/brightness\"\n"
+        );
+    }
+    rules
+}
+
+
+// Runs udevadm, failing if it does
+fn udevadm(args: &[&str]) -> Result<(), BlError> {
+    let status = Command::new("udevadm").args(args).status()
+        .map_err(|e| BlError::io("could not run udevadm", e))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(BlError::io(format!("udevadm {} failed", args.join(" ")), io::Error::other(status.to_string())))
+    }
+}
+
+
+// Writes the rules and has udev apply them to the devices that are already
+// there, giving where they were written
+pub fn install(vendor_id: u16, product_ids: &[u16], uaccess: bool) -> Result<PathBuf, BlError> {
+    let path = PathBuf::from(RULES_PATH);
+    fs::write(&path, rules(vendor_id, product_ids, uaccess))
+        .map_err(|e| BlError::io(format!("could not write {}", path.display()), e))?;
+
+    udevadm(&["control", "--reload"])?;
+    udevadm(&["trigger", "--action=change", "--subsystem-match=usb", "--subsystem-match=hidraw", "--subsystem-match=input", "--subsystem-match=leds"])?;
+    Ok(path)
+}
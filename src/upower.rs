@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+use zbus::MatchRule;
+use zbus::blocking::{Connection, MessageIterator, Proxy};
+use zbus::message::Type;
+use zbus::zvariant::OwnedValue;
+
+use tracing::{info, warn};
+use crate::Event;
+use crate::power;
+
+// Where upower lives on the system bus. The display device is the battery
+// that desktops show, combining all of the laptop's batteries
+const UPOWER_NAME: &str = "org.freedesktop.UPower";
+const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+const UPOWER_INTERFACE: &str = "org.freedesktop.UPower";
+const DISPLAY_DEVICE_PATH: &str = "/org/freedesktop/UPower/devices/DisplayDevice";
+const DEVICE_INTERFACE: &str = "org.freedesktop.UPower.Device";
+
+
+// Follows upower's idea of whether we're on battery and how full the battery
+// is as it changes, rather than polling sysfs
+fn watch_power(save_power: bool, s: &UnboundedSender<Event>) -> Result<(), zbus::Error> {
+    let conn = Connection::system()?;
+    let upower = Proxy::new(&conn, UPOWER_NAME, UPOWER_PATH, UPOWER_INTERFACE)?;
+    let display_device = Proxy::new(&conn, UPOWER_NAME, DISPLAY_DEVICE_PATH, DEVICE_INTERFACE)?;
+
+    // Subscribe before reading the properties so that no change is missed
+    let rule = MatchRule::builder().msg_type(Type::Signal).sender(UPOWER_NAME)?
+        .interface("org.freedesktop.DBus.Properties")?.member("PropertiesChanged")?
+        .path_namespace(UPOWER_PATH)?.build();
+    let messages = MessageIterator::for_match_rule(rule, &conn, None)?;
+
+    let mut supply = power::Supply::new(save_power);
+    if !supply.send_on_battery(upower.get_property("OnBattery")?, s) {
+        return Ok(());
+    }
+    let has_battery: bool = display_device.get_property("IsPresent")?;
+    if has_battery && !supply.send_level(Some(display_device.get_property("Percentage")?), s) {
+        return Ok(());
+    }
+    info!("Watching upower for the power supply");
+
+    for message in messages {
+        let message = message?;
+        let (interface, changed, _): (String, HashMap<String, OwnedValue>, Vec<String>) = message.body().deserialize()?;
+        let path = message.header().path().map(|p| p.to_string());
+        match (path.as_deref(), interface.as_str()) {
+            (Some(UPOWER_PATH), UPOWER_INTERFACE) => {
+                if let Some(Ok(battery)) = changed.get("OnBattery").map(bool::try_from) {
+                    if !supply.send_on_battery(battery, s) {
+                        return Ok(());
+                    }
+                }
+            },
+            (Some(DISPLAY_DEVICE_PATH), DEVICE_INTERFACE) => {
+                if let Some(Ok(level)) = changed.get("Percentage").map(f64::try_from) {
+                    if !supply.send_level(Some(level), s) {
+                        return Ok(());
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+
+    Ok(())
+}
+
+
+// Creates a thread that posts to the main loop whenever upower says we went on
+// to battery or back on to mains power, or the battery's level changed
+pub fn spawn_monitor(save_power: bool, s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("upower".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = watch_power(save_power, &s) {
+            warn!("Stopped watching upower: {}", e);
+        }
+    })
+}
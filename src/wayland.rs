@@ -0,0 +1,185 @@
+use std::os::fd::AsRawFd;
+use std::thread;
+use tokio::sync::mpsc::UnboundedSender;
+use wayland_client::{Connection, Dispatch, EventQueue, QueueHandle};
+use wayland_client::globals::{registry_queue_init, GlobalListContents};
+use wayland_client::WEnum;
+use wayland_client::protocol::{wl_output::WlOutput, wl_registry::WlRegistry, wl_seat::WlSeat};
+use wayland_protocols::ext::idle_notify::v1::client::ext_idle_notification_v1::{self, ExtIdleNotificationV1};
+use wayland_protocols::ext::idle_notify::v1::client::ext_idle_notifier_v1::ExtIdleNotifierV1;
+use wayland_protocols_wlr::output_power_management::v1::client::zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1;
+use wayland_protocols_wlr::output_power_management::v1::client::zwlr_output_power_v1::{self, ZwlrOutputPowerV1};
+
+use tracing::{debug, info, warn};
+use crate::{display, ActivitySource, Event};
+
+// How long without input before the compositor says the session is idle, in
+// milliseconds. Until it does, there's been input in the last second
+const IDLE_AFTER_MS: u32 = 1000;
+
+
+// Whether the compositor last said the session was idle
+struct State {
+    idle: bool
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for State {
+    fn event(_: &mut Self, _: &WlRegistry, _: <WlRegistry as wayland_client::Proxy>::Event, _: &GlobalListContents, _: &Connection, _: &QueueHandle<Self>) {}
+}
+
+impl Dispatch<WlSeat, ()> for State {
+    fn event(_: &mut Self, _: &WlSeat, _: <WlSeat as wayland_client::Proxy>::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {}
+}
+
+impl Dispatch<ExtIdleNotifierV1, ()> for State {
+    fn event(_: &mut Self, _: &ExtIdleNotifierV1, _: <ExtIdleNotifierV1 as wayland_client::Proxy>::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {}
+}
+
+impl Dispatch<ExtIdleNotificationV1, ()> for State {
+    fn event(state: &mut Self, _: &ExtIdleNotificationV1, event: ext_idle_notification_v1::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {
+        match event {
+            ext_idle_notification_v1::Event::Idled => state.idle = true,
+            ext_idle_notification_v1::Event::Resumed => state.idle = false,
+            _ => ()
+        }
+    }
+}
+
+
+// Waits up to the idle time for the compositor to say something, dispatching
+// whatever it does
+fn wait_for_events(conn: &Connection, queue: &mut EventQueue<State>, state: &mut State) -> Result<(), String> {
+    queue.flush().map_err(|e| e.to_string())?;
+    if let Some(guard) = conn.prepare_read() {
+        let mut fd = libc::pollfd { fd: guard.connection_fd().as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let ready = unsafe { libc::poll(&mut fd, 1, IDLE_AFTER_MS as libc::c_int) };
+        if ready > 0 {
+            guard.read().map_err(|e| e.to_string())?;
+        }
+    }
+    queue.dispatch_pending(state).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+
+// Asks the compositor to tell us when the session goes idle and comes back,
+// then posts activity for as long as it isn't idle. This sees everything the
+// compositor counts as input, including the mouse, without reading devices
+fn watch_idle(s: &UnboundedSender<Event>) -> Result<(), String> {
+    let conn = Connection::connect_to_env().map_err(|e| e.to_string())?;
+    let (globals, mut queue) = registry_queue_init::<State>(&conn).map_err(|e| e.to_string())?;
+    let qh = queue.handle();
+
+    let seat: WlSeat = globals.bind(&qh, 1..=1, ()).map_err(|e| format!("no seat: {}", e))?;
+    let notifier: ExtIdleNotifierV1 = globals.bind(&qh, 1..=1, ())
+        .map_err(|e| format!("the compositor doesn't support ext-idle-notify-v1: {}", e))?;
+    let _notification = notifier.get_idle_notification(IDLE_AFTER_MS, &seat, &qh, ());
+    info!("Watching the Wayland compositor for activity");
+
+    let mut state = State { idle: false };
+    loop {
+        let was_idle = state.idle;
+        wait_for_events(&conn, &mut queue, &mut state)?;
+        if state.idle != was_idle {
+            debug!("Compositor says the session is {}", if state.idle { "idle" } else { "active" });
+        }
+
+        if !state.idle && s.send(Event::Activity(ActivitySource::Wayland)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+
+// Creates a thread that posts activity to the main loop whenever the Wayland
+// compositor sees input
+pub fn spawn_idle_watcher(s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("wayland-idle".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = watch_idle(&s) {
+            warn!("Stopped watching the Wayland compositor: {}", e);
+        }
+    })
+}
+
+
+// Whether each output was last said to be on, or None once the compositor
+// has said it can't tell us (e.g. because the output was unplugged)
+struct PowerState {
+    on: Vec<Option<bool>>
+}
+
+impl PowerState {
+    // The display counts as off once every output that's left is off
+    fn display_on(&self) -> bool {
+        let outputs: Vec<bool> = self.on.iter().flatten().copied().collect();
+        outputs.is_empty() || outputs.iter().any(|&on| on)
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for PowerState {
+    fn event(_: &mut Self, _: &WlRegistry, _: <WlRegistry as wayland_client::Proxy>::Event, _: &GlobalListContents, _: &Connection, _: &QueueHandle<Self>) {}
+}
+
+impl Dispatch<WlOutput, ()> for PowerState {
+    fn event(_: &mut Self, _: &WlOutput, _: <WlOutput as wayland_client::Proxy>::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {}
+}
+
+impl Dispatch<ZwlrOutputPowerManagerV1, ()> for PowerState {
+    fn event(_: &mut Self, _: &ZwlrOutputPowerManagerV1, _: <ZwlrOutputPowerManagerV1 as wayland_client::Proxy>::Event, _: &(), _: &Connection, _: &QueueHandle<Self>) {}
+}
+
+impl Dispatch<ZwlrOutputPowerV1, usize> for PowerState {
+    fn event(state: &mut Self, _: &ZwlrOutputPowerV1, event: zwlr_output_power_v1::Event, index: &usize, _: &Connection, _: &QueueHandle<Self>) {
+        match event {
+            zwlr_output_power_v1::Event::Mode { mode: WEnum::Value(mode) } => state.on[*index] = Some(mode == zwlr_output_power_v1::Mode::On),
+            zwlr_output_power_v1::Event::Failed => state.on[*index] = None,
+            _ => ()
+        }
+    }
+}
+
+
+// Asks the compositor to tell us whenever its outputs are turned off or back
+// on through wlr-output-power-management, e.g. by swayidle, posting to the
+// main loop whenever they all go off or one comes back. Outputs plugged in
+// after we start aren't followed
+fn watch_power(s: &UnboundedSender<Event>) -> Result<(), String> {
+    let conn = Connection::connect_to_env().map_err(|e| e.to_string())?;
+    let (globals, mut queue) = registry_queue_init::<PowerState>(&conn).map_err(|e| e.to_string())?;
+    let qh = queue.handle();
+
+    let manager: ZwlrOutputPowerManagerV1 = globals.bind(&qh, 1..=1, ())
+        .map_err(|e| format!("the compositor doesn't support wlr-output-power-management-unstable-v1: {}", e))?;
+    let outputs: Vec<ZwlrOutputPowerV1> = globals.contents().clone_list().into_iter()
+        .filter(|global| global.interface == "wl_output")
+        .enumerate()
+        .map(|(index, global)| {
+            let output: WlOutput = globals.registry().bind(global.name, 1, &qh, ());
+            manager.get_output_power(&output, &qh, index)
+        })
+        .collect();
+    info!("Watching the Wayland compositor for {} output(s) turning off", outputs.len());
+
+    // Until the compositor says otherwise, each output is taken to be on
+    let mut state = PowerState { on: vec![Some(true); outputs.len()] };
+    let mut was_on = true;
+    loop {
+        queue.blocking_dispatch(&mut state).map_err(|e| e.to_string())?;
+        if !display::send_power(state.display_on(), &mut was_on, "Compositor", s) {
+            return Ok(());
+        }
+    }
+}
+
+
+// Creates a thread that posts to the main loop whenever the Wayland
+// compositor turns the display off or back on
+pub fn spawn_power_watcher(s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("wayland-power".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = watch_power(&s) {
+            warn!("Stopped watching the Wayland compositor for the display turning off: {}", e);
+        }
+    })
+}
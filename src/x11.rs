@@ -0,0 +1,83 @@
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use x11rb::connection::Connection;
+use x11rb::protocol::dpms::{ConnectionExt as _, DPMSMode};
+use x11rb::protocol::screensaver::ConnectionExt as _;
+
+use tracing::{info, warn};
+use crate::{display, ActivitySource, Event};
+
+// How often the X server is asked how long it's been since the last input
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+
+// Asks the X server how long the session has been idle once a second through
+// the MIT-SCREEN-SAVER extension, posting activity whenever there's been input
+// since the last time. This sees everything the X server does, including the
+// mouse, without needing access to /dev/input
+fn poll_idle(s: &UnboundedSender<Event>) -> Result<(), String> {
+    let (conn, screen_num) = x11rb::connect(None).map_err(|e| e.to_string())?;
+    let root = conn.setup().roots[screen_num].root;
+
+    let version = conn.screensaver_query_version(1, 1).map_err(|e| e.to_string())?
+        .reply().map_err(|_| String::from("X server does not support MIT-SCREEN-SAVER"))?;
+    info!("Polling the X server for activity (MIT-SCREEN-SAVER {}.{})", version.server_major_version, version.server_minor_version);
+
+    loop {
+        let reply = conn.screensaver_query_info(root).map_err(|e| e.to_string())?
+            .reply().map_err(|e| e.to_string())?;
+        if u128::from(reply.ms_since_user_input) < POLL_INTERVAL.as_millis()
+            && s.send(Event::Activity(ActivitySource::X11)).is_err() {
+            return Ok(());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+
+// Creates a thread that posts activity to the main loop whenever the X server
+// sees input
+pub fn spawn_idle_poller(s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("x11-idle".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = poll_idle(&s) {
+            warn!("Stopped polling the X server for activity: {}", e);
+        }
+    })
+}
+
+
+// Asks the X server once a second whether DPMS has turned the display off,
+// posting to the main loop whenever it turns off or back on. Standby and
+// suspend count as off, as the display is blank in both
+fn poll_dpms(s: &UnboundedSender<Event>) -> Result<(), String> {
+    let (conn, _) = x11rb::connect(None).map_err(|e| e.to_string())?;
+
+    let version = conn.dpms_get_version(1, 1).map_err(|e| e.to_string())?
+        .reply().map_err(|_| String::from("X server does not support DPMS"))?;
+    info!("Polling the X server for the display turning off (DPMS {}.{})", version.server_major_version, version.server_minor_version);
+
+    let mut was_on = true;
+    loop {
+        let reply = conn.dpms_info().map_err(|e| e.to_string())?
+            .reply().map_err(|e| e.to_string())?;
+        let on = !reply.state || reply.power_level == DPMSMode::ON;
+        if !display::send_power(on, &mut was_on, "DPMS", s) {
+            return Ok(());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+
+// Creates a thread that posts to the main loop whenever the X server turns
+// the display off or back on
+pub fn spawn_dpms_poller(s: UnboundedSender<Event>) -> std::io::Result<thread::JoinHandle<()>> {
+    let thread_builder = thread::Builder::new().name("x11-dpms".to_string());
+    thread_builder.spawn(move || {
+        if let Err(e) = poll_dpms(&s) {
+            warn!("Stopped polling the X server for DPMS: {}", e);
+        }
+    })
+}